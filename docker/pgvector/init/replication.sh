@@ -0,0 +1,5 @@
+#!/usr/bin/env bash
+# Allows the replicas to stream the WAL of the primary, runs once on initialization.
+set -eu -o pipefail
+
+echo "host replication all all trust" >> "$PGDATA/pg_hba.conf"
@@ -12,15 +12,32 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod filter_compliance;
+pub mod mixed;
+pub mod open_loop;
+pub mod plan;
 pub mod query_throughput;
+pub mod saturation;
+pub mod sweep;
+pub mod validation;
 
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Error;
+use anyhow::{bail, Context, Error};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::{databases::DatabaseName, distribution::QueryPayload};
+use crate::{
+    databases::DatabaseName,
+    distribution::{DocumentPayload, QueryPayload},
+    math::Distance,
+    resources::{load_bincode, load_vectors, ResolvedPaths},
+};
 
 #[derive(Clone, Copy)]
 pub struct QueryParameters {
@@ -30,6 +47,10 @@ pub struct QueryParameters {
     pub number_of_tasks: usize,
     pub queries_per_task: usize,
     pub use_filters: bool,
+    /// The fraction of filtered queries whose hits are checked against the filter.
+    pub filter_check_rate: f64,
+    pub quantization: QuantizationSearch,
+    pub consistency: Consistency,
     pub cpus: f32,
     pub mem_limit: f32,
 }
@@ -43,6 +64,9 @@ impl Display for QueryParameters {
             number_of_tasks,
             queries_per_task,
             use_filters,
+            filter_check_rate: _,
+            quantization,
+            consistency,
             cpus,
             mem_limit,
         } = self;
@@ -50,33 +74,413 @@ impl Display for QueryParameters {
         let use_filters = if *use_filters { "F" } else { "f" };
         write!(
             f,
-            "{cpus:.2}:{mem_limit:.2}-{k}:{ef}{fetch_payload}{use_filters}-{number_of_tasks}:{queries_per_task}"
+            "{cpus:.2}:{mem_limit:.2}-{k}:{ef}{fetch_payload}{use_filters}-{number_of_tasks}:{queries_per_task}{quantization}{consistency}"
         )
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct IngestionParameters {
     pub m: usize,
     pub ef_construct: usize,
+    pub index_type: IndexType,
+    pub element_type: ElementType,
+    pub quantization: Quantization,
+}
+
+impl IngestionParameters {
+    /// The name of the collection/index/document type build with this parameters.
+    pub fn index_name(&self) -> String {
+        let Self {
+            m,
+            ef_construct,
+            index_type,
+            element_type,
+            quantization,
+        } = self;
+        let mut name = format!("content_m{m}_ef{ef_construct}");
+        if *index_type == IndexType::Flat {
+            name.push_str("_flat");
+        }
+        if *element_type == ElementType::Byte {
+            name.push_str("_byte");
+        }
+        match quantization {
+            Quantization::None => {}
+            Quantization::Scalar {
+                quantile,
+                always_ram,
+            } => {
+                name.push_str("_scalar");
+                if let Some(quantile) = quantile {
+                    name.push_str(&format!("_q{quantile}").replace('.', "_"));
+                }
+                if *always_ram {
+                    name.push_str("_ram");
+                }
+            }
+            Quantization::Product {
+                compression,
+                always_ram,
+            } => {
+                name.push_str(&format!("_product_x{}", compression.ratio()));
+                if *always_ram {
+                    name.push_str("_ram");
+                }
+            }
+            Quantization::Binary { always_ram } => {
+                name.push_str("_binary");
+                if *always_ram {
+                    name.push_str("_ram");
+                }
+            }
+        }
+        name
+    }
 }
 
 impl Display for IngestionParameters {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let Self { m, ef_construct } = self;
-        write!(f, "{m}:{ef_construct}")
+        let Self {
+            m,
+            ef_construct,
+            index_type,
+            element_type,
+            quantization,
+        } = self;
+        let index_type = match index_type {
+            IndexType::Hnsw => "",
+            IndexType::Flat => "-flat",
+        };
+        let element_type = match element_type {
+            ElementType::Float => "",
+            ElementType::Byte => "-byte",
+        };
+        write!(
+            f,
+            "{m}:{ef_construct}{index_type}{element_type}{quantization}"
+        )
+    }
+}
+
+/// How the vectors are indexed, `flat` is currently only supported by elasticsearch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexType {
+    #[default]
+    Hnsw,
+    /// Brute force search, `m` and `ef_construct` are ignored.
+    Flat,
+}
+
+/// The type of the stored vector elements, `byte` is currently only supported by elasticsearch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ElementType {
+    #[default]
+    Float,
+    /// The elements are scaled and rounded to an int8, see [`byte_vector`].
+    Byte,
+}
+
+/// Converts the vector elements to bytes, fails if they aren't within `[-1;1]`.
+pub fn byte_vector(vector: &[f32]) -> Result<Vec<i8>, Error> {
+    vector
+        .iter()
+        .map(|element| {
+            if !(-1. ..=1.).contains(element) {
+                bail!(
+                    "vector element {element} is outside of [-1;1] and can't be scaled to a byte"
+                );
+            }
+            Ok((element * BYTE_SCALE).round() as i8)
+        })
+        .collect()
+}
+
+/// The factor vector elements are scaled by in [`byte_vector`].
+pub const BYTE_SCALE: f32 = 127.;
+
+/// How the vectors are quantized, currently only supported by qdrant and (only `scalar`)
+/// by elasticsearch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Quantization {
+    #[default]
+    None,
+    /// Quantizes each element to an int8.
+    Scalar {
+        /// The quantile of the values used to determine the quantization bounds.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quantile: Option<f32>,
+        /// Keeps the quantized vectors in RAM even if the vectors are on disk.
+        #[serde(default)]
+        always_ram: bool,
+    },
+    Product {
+        compression: CompressionRatio,
+        #[serde(default)]
+        always_ram: bool,
+    },
+    /// Quantizes each element to a single bit.
+    Binary {
+        #[serde(default)]
+        always_ram: bool,
+    },
+}
+
+impl Display for Quantization {
+    /// Empty if not quantized, the suffix of the ingestion bench id otherwise.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let always_ram = |always_ram: bool| if always_ram { "R" } else { "r" };
+        match self {
+            Quantization::None => Ok(()),
+            Quantization::Scalar {
+                quantile,
+                always_ram: ram,
+            } => {
+                write!(f, "-s")?;
+                if let Some(quantile) = quantile {
+                    write!(f, "{quantile}")?;
+                }
+                write!(f, "{}", always_ram(*ram))
+            }
+            Quantization::Product {
+                compression,
+                always_ram: ram,
+            } => write!(f, "-p{}{}", compression.ratio(), always_ram(*ram)),
+            Quantization::Binary { always_ram: ram } => write!(f, "-b{}", always_ram(*ram)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionRatio {
+    X4,
+    X8,
+    X16,
+    X32,
+    X64,
+}
+
+impl CompressionRatio {
+    pub fn ratio(self) -> usize {
+        match self {
+            CompressionRatio::X4 => 4,
+            CompressionRatio::X8 => 8,
+            CompressionRatio::X16 => 16,
+            CompressionRatio::X32 => 32,
+            CompressionRatio::X64 => 64,
+        }
+    }
+}
+
+/// How quantized vectors are used at query time, currently only supported by qdrant.
+///
+/// The default leaves it to the provider.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuantizationSearch {
+    /// Searches the original vectors only.
+    #[serde(default)]
+    pub ignore: bool,
+    /// Re-scores the hits with the original vectors.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rescore: Option<bool>,
+    /// How many more candidates are fetched from the quantized vectors for re-scoring.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oversampling: Option<f64>,
+}
+
+impl Display for QuantizationSearch {
+    /// Empty for the provider default, the suffix of the query bench id otherwise.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::default() {
+            return Ok(());
+        }
+        let Self {
+            ignore,
+            rescore,
+            oversampling,
+        } = self;
+        write!(f, "-q")?;
+        if *ignore {
+            write!(f, "I")?;
+        }
+        match rescore {
+            Some(true) => write!(f, "R")?,
+            Some(false) => write!(f, "r")?,
+            None => {}
+        }
+        if let Some(oversampling) = oversampling {
+            write!(f, "x{oversampling}")?;
+        }
+        Ok(())
     }
 }
 
+/// A k nearest neighbor query.
+#[derive(Clone, Copy, Debug)]
+pub struct QueryRequest<'a> {
+    pub vector: &'a [f32],
+    pub k: usize,
+    /// `ef` for qdrant, `num_candidates` for elasticsearch and `targetHits + exploreAdditionalHits`
+    /// for vespa.
+    pub ef: usize,
+    /// Only documents matching the filter are returned, `None` for an unfiltered query.
+    pub filter: Option<&'a QueryPayload>,
+    pub payload: PayloadSelection,
+    /// Overrides the default server side timeout of the provider.
+    pub timeout: Option<Duration>,
+    pub consistency: Consistency,
+    pub quantization: QuantizationSearch,
+}
+
+impl<'a> QueryRequest<'a> {
+    pub fn new(vector: &'a [f32], k: usize, ef: usize) -> Self {
+        Self {
+            vector,
+            k,
+            ef,
+            filter: None,
+            payload: PayloadSelection::default(),
+            timeout: None,
+            consistency: Consistency::default(),
+            quantization: QuantizationSearch::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PayloadSelection {
+    /// Only the ids are returned.
+    #[default]
+    None,
+    /// The whole document payload is returned.
+    All,
+}
+
+/// How many replicas have to answer a query.
+///
+/// Qdrant supports all levels. Elasticsearch always reads a single copy of each shard, `one`
+/// prefers the copies local to the coordinating node (`preference=_local`). Vespa maps the
+/// level to the dispatch policy of the application.
+///
+/// See [`crate::databases::Provider::supports_consistency`] for the levels of each provider.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Consistency {
+    All,
+    Majority,
+    #[default]
+    Quorum,
+    One,
+}
+
+impl Display for Consistency {
+    /// Empty for the default, the suffix of the query bench id otherwise.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Consistency::All => write!(f, "-ca"),
+            Consistency::Majority => write!(f, "-cm"),
+            Consistency::Quorum => Ok(()),
+            Consistency::One => write!(f, "-c1"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryResponse {
+    /// The hits, in the order returned by the provider.
+    pub hits: Vec<QueryHit>,
+    /// The query time as reported by the provider, if it reports one.
+    pub took: Option<Duration>,
+}
+
+impl QueryResponse {
+    pub fn ids(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.hits.iter().map(|hit| hit.id)
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryHit {
+    pub id: Uuid,
+    /// The raw score of the provider, how it relates to the distance depends on the provider.
+    pub score: f32,
+    /// Only set if requested with [`PayloadSelection::All`].
+    pub payload: Option<DocumentPayload>,
+}
+
 #[async_trait]
 pub trait QueryVectorDatabase: DatabaseName + Send + Sync + 'static {
-    async fn query(
+    async fn query(&self, request: &QueryRequest<'_>) -> Result<QueryResponse, Error>;
+
+    /// Applies the settings of the request which can't be changed per query.
+    ///
+    /// Called before the queries of each benchmark (point) are sent, outside of the measurements.
+    async fn prepare_queries(&self, _request: &QueryRequest<'_>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Reverts the similarity transform of the provider, i.e. turns the score of a hit
+    /// back into the distance to the query vector, see [`Distance::distance`].
+    fn score_to_distance(&self, score: f32) -> f32;
+}
+
+/// The database and the test queries shared by all query benchmarks.
+pub struct QueryInputs<DB>
+where
+    DB: QueryVectorDatabase,
+{
+    pub database: DB,
+    pub distance: Distance,
+    pub payloads: Vec<QueryPayload>,
+    pub vectors: Vec<Vec<f32>>,
+}
+
+impl<DB> QueryInputs<DB>
+where
+    DB: QueryVectorDatabase,
+{
+    pub fn load(
+        paths: &ResolvedPaths,
+        distance: Distance,
+        database: DB,
+    ) -> Result<Arc<Self>, Error> {
+        paths.check_files_exists()?;
+
+        let payloads: Vec<QueryPayload> =
+            load_bincode(&paths.query_payload_file).context("loading bincode payloads")?;
+        let vectors = load_vectors(&paths.vectors_file, "test").context("loading vector data")?;
+        assert!(payloads.len() == vectors.len());
+
+        Ok(Arc::new(Self {
+            database,
+            distance,
+            payloads,
+            vectors,
+        }))
+    }
+
+    /// Builds the request for the test query `idx` as used by the benchmarks.
+    pub fn request(
         &self,
+        idx: usize,
         k: usize,
         ef: usize,
-        vector: &[f32],
-        payload: &QueryPayload,
-        return_payload: bool,
+        fetch_payload: bool,
         use_filters: bool,
-    ) -> Result<Vec<Uuid>, Error>;
+    ) -> QueryRequest<'_> {
+        QueryRequest {
+            filter: use_filters.then(|| &self.payloads[idx]),
+            payload: if fetch_payload {
+                PayloadSelection::All
+            } else {
+                PayloadSelection::None
+            },
+            ..QueryRequest::new(&self.vectors[idx], k, ef)
+        }
+    }
 }
@@ -0,0 +1,98 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Checks that the hits of filtered queries actually match the filter.
+//!
+//! A mistranslated filter would otherwise just look like a faster provider.
+
+use serde::{Deserialize, Serialize};
+
+use crate::distribution::{DocumentPayload, QueryPayload};
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FilterComplianceReport {
+    pub checked_queries: usize,
+    pub checked_hits: usize,
+    /// Hits whose document payload doesn't match the query filter.
+    pub violations: usize,
+    /// Queries with at least one violation.
+    pub violating_queries: usize,
+    /// Hits which aren't part of the ingested documents.
+    pub unknown_ids: usize,
+}
+
+impl FilterComplianceReport {
+    /// Checks the hits, given as document indices, of a single query.
+    pub fn check(&mut self, filter: &QueryPayload, hits: &[usize], documents: &[DocumentPayload]) {
+        self.checked_queries += 1;
+        let mut violations = 0;
+        for &idx in hits {
+            self.checked_hits += 1;
+            match documents.get(idx) {
+                Some(document) if !filter.matches(document) => violations += 1,
+                Some(_) => {}
+                None => self.unknown_ids += 1,
+            }
+        }
+        self.violations += violations;
+        if violations > 0 {
+            self.violating_queries += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::distribution::{DateFilter, Label, LabelFilter, Labels};
+
+    use super::*;
+
+    #[test]
+    fn test_check_counts_non_matching_hits() {
+        let document = |year, tag| DocumentPayload {
+            publication_date: Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap(),
+            authors: Labels::default(),
+            tags: Labels(vec![Label(tag)]),
+            link: String::new(),
+        };
+        let documents = [document(2000, 1), document(2020, 1), document(2020, 2)];
+        let filter = QueryPayload {
+            publication_date: DateFilter {
+                lower_bound: Some(Utc.with_ymd_and_hms(2010, 1, 1, 0, 0, 0).unwrap()),
+                upper_bound: None,
+            },
+            authors: LabelFilter::default(),
+            tags: LabelFilter {
+                include: Labels::default(),
+                exclude: Labels(vec![Label(2)]),
+            },
+        };
+
+        let mut report = FilterComplianceReport::default();
+        report.check(&filter, &[1], &documents);
+        report.check(&filter, &[1, 0, 2, 7], &documents);
+        assert_eq!(
+            report,
+            FilterComplianceReport {
+                checked_queries: 2,
+                checked_hits: 5,
+                violations: 2,
+                violating_queries: 1,
+                unknown_ids: 1,
+            }
+        );
+    }
+}
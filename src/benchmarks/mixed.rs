@@ -0,0 +1,474 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Mixed read/write benchmark running queries while documents are upserted,
+//! updated and deleted.
+//!
+//! The writes only touch documents created by the benchmark itself (with ids after
+//! the ids of the dataset) and all of them are deleted again at the end, so the
+//! dataset stays intact for following benchmarks.
+
+use std::{
+    fmt::{self, Display},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Error};
+use rand::{distributions::WeightedIndex, rngs::StdRng, thread_rng, Rng, SeedableRng};
+use rand_distr::Uniform;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    runtime::Runtime,
+    task::{JoinSet, LocalSet},
+    time::Instant,
+};
+use uuid::Uuid;
+
+use crate::{
+    benchmarks::{
+        plan::BenchmarkPlan, Consistency, IngestionParameters, QuantizationSearch, QueryRequest,
+    },
+    distribution::{
+        ids::index_to_fake_uuid,
+        ingestion::{IngestionInfo, PayloadUpdate, PrepareVectorDatabase},
+        DocumentPayload,
+    },
+    docker::DockerStatScanner,
+    math::LatencyHistogram,
+    resources::{load_bincode, load_first_vectors, ResolvedPaths, ResourceWriter},
+};
+
+use super::{QueryInputs, QueryVectorDatabase};
+
+/// How many vectors/payloads of the dataset are loaded to create new documents from.
+const WRITE_SOURCE_SIZE: usize = 10_000;
+
+/// The relative weights of the write operations.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct WriteMix {
+    pub upsert: u32,
+    pub update: u32,
+    pub delete: u32,
+}
+
+impl Default for WriteMix {
+    fn default() -> Self {
+        Self {
+            upsert: 2,
+            update: 1,
+            delete: 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct MixedParameters {
+    pub k: usize,
+    pub ef: usize,
+    pub fetch_payload: bool,
+    pub use_filters: bool,
+    pub number_of_tasks: usize,
+    /// The percentage of `number_of_tasks` which write instead of query.
+    pub write_percent: usize,
+    pub write_batch_size: usize,
+    pub write_mix: WriteMix,
+    pub duration: Duration,
+    pub quantization: QuantizationSearch,
+    pub consistency: Consistency,
+    pub cpus: f32,
+    pub mem_limit: f32,
+}
+
+impl MixedParameters {
+    fn number_of_writers(&self) -> usize {
+        (self.number_of_tasks * self.write_percent + 50) / 100
+    }
+}
+
+impl Display for MixedParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            k,
+            ef,
+            fetch_payload,
+            use_filters,
+            number_of_tasks,
+            write_percent,
+            write_batch_size: _,
+            write_mix: _,
+            duration: _,
+            quantization,
+            consistency,
+            cpus,
+            mem_limit,
+        } = self;
+        let fetch_payload = if *fetch_payload { "P" } else { "p" };
+        let use_filters = if *use_filters { "F" } else { "f" };
+        write!(
+            f,
+            "{cpus:.2}:{mem_limit:.2}-{k}:{ef}{fetch_payload}{use_filters}-{number_of_tasks}:w{write_percent}{quantization}{consistency}"
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MixedReport {
+    pub query_tasks: usize,
+    pub write_tasks: usize,
+    pub write_batch_size: usize,
+    pub write_mix: WriteMix,
+    pub duration_secs: f64,
+    pub queries: u64,
+    pub query_qps: f64,
+    /// Written documents (summed over all operations) per second.
+    pub write_dps: f64,
+}
+
+/// Latencies of the write operations, each for a whole batch.
+#[derive(Default, Serialize, Deserialize)]
+pub struct WriteLatencies {
+    pub upsert: LatencyHistogram,
+    pub update: LatencyHistogram,
+    pub delete: LatencyHistogram,
+}
+
+impl WriteLatencies {
+    fn merge(&mut self, other: &WriteLatencies) -> Result<(), Error> {
+        self.upsert.merge(&other.upsert)?;
+        self.update.merge(&other.update)?;
+        self.delete.merge(&other.delete)
+    }
+
+    fn count(&self) -> u64 {
+        self.upsert.count() + self.update.count() + self.delete.count()
+    }
+}
+
+/// The vectors and payloads new documents are created from.
+struct WriteInputs {
+    vectors: Vec<Vec<f32>>,
+    payloads: Vec<DocumentPayload>,
+    next_id: AtomicU64,
+}
+
+impl WriteInputs {
+    fn load(paths: &ResolvedPaths) -> Result<Self, Error> {
+        let mut payloads: Vec<DocumentPayload> =
+            load_bincode(&paths.document_payload_file).context("loading bincode payloads")?;
+        // new ids start after the ids of the dataset
+        let next_id = AtomicU64::new(payloads.len() as u64);
+        payloads.truncate(WRITE_SOURCE_SIZE);
+        let vectors = load_first_vectors(&paths.vectors_file, "train", WRITE_SOURCE_SIZE)
+            .context("loading vector data")?;
+        assert!(payloads.len() == vectors.len());
+        Ok(Self {
+            vectors,
+            payloads,
+            next_id,
+        })
+    }
+}
+
+/// Runs all mixed read/write benchmarks of the plan against the index build with `iparams`.
+pub fn benchmark<DB>(
+    writer: &ResourceWriter,
+    paths: &ResolvedPaths,
+    plan: &BenchmarkPlan,
+    iparams: IngestionParameters,
+    inputs: &Arc<QueryInputs<DB>>,
+) -> Result<(), Error>
+where
+    DB: PrepareVectorDatabase + QueryVectorDatabase,
+{
+    let provider = inputs.database.name();
+    let parameters = plan.mixed_parameters(provider);
+    if parameters.is_empty() {
+        return Ok(());
+    }
+
+    let writer = &writer.sub_writer("mixed")?;
+    let write_inputs = Arc::new(WriteInputs::load(paths)?);
+    let rt = &Runtime::new()?;
+    for mparams in parameters {
+        let bench_id = format!("{iparams}_{mparams}");
+        eprintln!("{provider}/mixed/{bench_id}");
+        let writer = writer.sub_writer(&bench_id)?;
+        let docker_stats = DockerStatScanner::start(rt.handle(), inputs.database.service())?;
+        let node_requests = inputs.database.node_requests();
+        // the write futures are not `Send` so they run on a local set
+        let (report, query_latencies, write_latencies) =
+            rt.block_on(LocalSet::new().run_until(run(inputs, &write_inputs, mparams)))?;
+        let stats = rt.block_on(docker_stats.stop())?;
+        eprintln!(
+            "{:.1} queries/s, {:.1} written documents/s",
+            report.query_qps, report.write_dps,
+        );
+        writer.write_file("docker_stats.json", &stats)?;
+        writer.write_file(
+            "node_requests.json",
+            &inputs.database.node_requests().since(&node_requests),
+        )?;
+        writer.write_file("latency.json", &query_latencies)?;
+        writer.write_file("write_latency.json", &write_latencies)?;
+        writer.write_file("mixed.json", &report)?;
+    }
+    Ok(())
+}
+
+async fn run<DB>(
+    inputs: &Arc<QueryInputs<DB>>,
+    write_inputs: &Arc<WriteInputs>,
+    params: MixedParameters,
+) -> Result<(MixedReport, LatencyHistogram, WriteLatencies), Error>
+where
+    DB: PrepareVectorDatabase + QueryVectorDatabase,
+{
+    let write_tasks = params.number_of_writers();
+    let query_tasks = params.number_of_tasks - write_tasks;
+    inputs
+        .database
+        .prepare_queries(&QueryRequest {
+            quantization: params.quantization,
+            consistency: params.consistency,
+            ..inputs.request(
+                0,
+                params.k,
+                params.ef,
+                params.fetch_payload,
+                params.use_filters,
+            )
+        })
+        .await?;
+    let start = Instant::now();
+    let deadline = start + params.duration;
+
+    let mut queries = JoinSet::new();
+    for _ in 0..query_tasks {
+        queries.spawn(query_loop(inputs.clone(), params, deadline));
+    }
+    let mut writes = JoinSet::new();
+    for _ in 0..write_tasks {
+        let inputs = inputs.clone();
+        let write_inputs = write_inputs.clone();
+        writes.spawn_local(async move {
+            let mut created = Vec::new();
+            let result = write_loop(&inputs, &write_inputs, params, deadline, &mut created).await;
+            (result, created)
+        });
+    }
+
+    // all tasks are awaited even if some fail, so that the created documents can be deleted
+    let mut error = None;
+    let mut query_latencies = LatencyHistogram::new();
+    while let Some(result) = queries.join_next().await {
+        match result.map_err(Error::from).and_then(|latencies| latencies) {
+            Ok(latencies) => query_latencies.merge(&latencies)?,
+            Err(err) => {
+                error.get_or_insert(err);
+            }
+        }
+    }
+    let mut write_latencies = WriteLatencies::default();
+    let mut created = Vec::new();
+    while let Some(result) = writes.join_next().await {
+        match result {
+            Ok((result, task_created)) => {
+                created.extend(task_created);
+                match result {
+                    Ok(latencies) => write_latencies.merge(&latencies)?,
+                    Err(err) => {
+                        error.get_or_insert(err);
+                    }
+                }
+            }
+            Err(err) => {
+                error.get_or_insert(err.into());
+            }
+        }
+    }
+    // the tasks only check the deadline between operations
+    let duration_secs = start.elapsed().as_secs_f64();
+
+    // leave the dataset as we found it
+    for batch in created.chunks(params.write_batch_size) {
+        if let Err(err) = inputs.database.delete_batch(batch).await {
+            error.get_or_insert(err);
+        }
+    }
+    if let Some(error) = error {
+        return Err(error);
+    }
+
+    let report = MixedReport {
+        query_tasks,
+        write_tasks,
+        write_batch_size: params.write_batch_size,
+        write_mix: params.write_mix,
+        duration_secs,
+        queries: query_latencies.count(),
+        query_qps: query_latencies.count() as f64 / duration_secs,
+        write_dps: (write_latencies.count() * params.write_batch_size as u64) as f64
+            / duration_secs,
+    };
+    Ok((report, query_latencies, write_latencies))
+}
+
+async fn query_loop<DB>(
+    inputs: Arc<QueryInputs<DB>>,
+    params: MixedParameters,
+    deadline: Instant,
+) -> Result<LatencyHistogram, Error>
+where
+    DB: QueryVectorDatabase,
+{
+    let mut latencies = LatencyHistogram::new();
+    let mut rng = StdRng::from_rng(thread_rng())?;
+    let queries = Uniform::new(0, inputs.vectors.len());
+    while Instant::now() < deadline {
+        let idx = rng.sample(queries);
+        let start = Instant::now();
+        inputs
+            .database
+            .query(&QueryRequest {
+                quantization: params.quantization,
+                consistency: params.consistency,
+                ..inputs.request(
+                    idx,
+                    params.k,
+                    params.ef,
+                    params.fetch_payload,
+                    params.use_filters,
+                )
+            })
+            .await?;
+        latencies.record(start.elapsed());
+    }
+    Ok(latencies)
+}
+
+/// Writes until the deadline, `created` tracks the documents created by this task which still exist.
+async fn write_loop<DB>(
+    inputs: &QueryInputs<DB>,
+    write_inputs: &WriteInputs,
+    params: MixedParameters,
+    deadline: Instant,
+    created: &mut Vec<Uuid>,
+) -> Result<WriteLatencies, Error>
+where
+    DB: PrepareVectorDatabase + QueryVectorDatabase,
+{
+    let database = &inputs.database;
+    let WriteInputs {
+        vectors,
+        payloads,
+        next_id,
+    } = write_inputs;
+    let WriteMix {
+        upsert,
+        update,
+        delete,
+    } = params.write_mix;
+    let batch_size = params.write_batch_size;
+
+    let mut latencies = WriteLatencies::default();
+    let mut rng = StdRng::from_rng(thread_rng())?;
+    let sources = Uniform::new(0, vectors.len());
+    let operations = WeightedIndex::new([upsert, update, delete])?;
+
+    while Instant::now() < deadline {
+        // index into `[upsert, update, delete]`, we can only update/delete what we created
+        let operation = if created.len() < batch_size {
+            0
+        } else {
+            rng.sample(&operations)
+        };
+        let start = Instant::now();
+        match operation {
+            0 => {
+                let batch = (0..batch_size)
+                    .map(|_| {
+                        let id = index_to_fake_uuid(next_id.fetch_add(1, Ordering::Relaxed));
+                        (id, rng.sample(sources))
+                    })
+                    .collect::<Vec<_>>();
+                // a partially failed batch has to be deleted too, deleting missing ids is a no-op
+                created.extend(batch.iter().map(|&(id, _)| id));
+                database
+                    .ingest_batch(batch.iter().map(|&(id, source)| IngestionInfo {
+                        id,
+                        vector: &vectors[source],
+                        payload: &payloads[source],
+                    }))
+                    .await?;
+                latencies.upsert.record(start.elapsed());
+            }
+            1 => {
+                let batch = (0..batch_size)
+                    .map(|_| {
+                        let id = created[rng.gen_range(0..created.len())];
+                        (id, rng.sample(sources))
+                    })
+                    .collect::<Vec<_>>();
+                database
+                    .update_payloads(batch.iter().map(|&(id, source)| PayloadUpdate {
+                        id,
+                        payload: &payloads[source],
+                    }))
+                    .await?;
+                latencies.update.record(start.elapsed());
+            }
+            _ => {
+                let first = created.len() - batch_size;
+                // only forget the ids once they are deleted, otherwise the cleanup retries them
+                database.delete_batch(&created[first..]).await?;
+                created.truncate(first);
+                latencies.delete.record(start.elapsed());
+            }
+        }
+    }
+
+    Ok(latencies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number_of_writers_is_rounded() {
+        let params = |number_of_tasks, write_percent| MixedParameters {
+            k: 10,
+            ef: 10,
+            fetch_payload: false,
+            use_filters: false,
+            number_of_tasks,
+            write_percent,
+            write_batch_size: 10,
+            write_mix: WriteMix::default(),
+            duration: Duration::from_secs(1),
+            quantization: QuantizationSearch::default(),
+            consistency: Consistency::default(),
+            cpus: 0.,
+            mem_limit: 0.,
+        };
+        assert_eq!(params(10, 20).number_of_writers(), 2);
+        assert_eq!(params(5, 10).number_of_writers(), 1);
+        assert_eq!(params(5, 0).number_of_writers(), 0);
+        assert_eq!(params(5, 100).number_of_writers(), 5);
+    }
+}
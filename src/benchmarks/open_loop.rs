@@ -0,0 +1,317 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Open-loop benchmark issuing queries at a fixed arrival rate.
+//!
+//! Unlike `query_throughput` the next query is sent at its scheduled time no matter
+//! whether previous queries finished, so queueing in the database shows up in the
+//! latencies instead of silently lowering the request rate (coordinated omission).
+
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Error;
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use rand_distr::{Exp, Uniform};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    runtime::Runtime,
+    task::JoinSet,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    benchmarks::{
+        plan::BenchmarkPlan, Consistency, IngestionParameters, QuantizationSearch, QueryRequest,
+    },
+    docker::DockerStatScanner,
+    math::LatencyHistogram,
+    resources::ResourceWriter,
+};
+
+use super::{QueryInputs, QueryVectorDatabase};
+
+/// Queries sent later than this after their intended send time are counted as late.
+///
+/// The tokio timer has a granularity of 1ms, so this can't be much lower.
+const LATE_THRESHOLD: Duration = Duration::from_millis(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Arrival {
+    /// Queries are sent in fixed intervals.
+    Constant,
+    /// Intervals between queries are exponentially distributed.
+    Poisson,
+}
+
+#[derive(Clone, Copy)]
+pub struct OpenLoopParameters {
+    pub k: usize,
+    pub ef: usize,
+    pub fetch_payload: bool,
+    pub use_filters: bool,
+    pub arrival: Arrival,
+    pub qps: usize,
+    pub duration: Duration,
+    /// If this many queries are in flight new queries are dropped instead of sent.
+    pub max_in_flight: usize,
+    pub quantization: QuantizationSearch,
+    pub consistency: Consistency,
+    pub cpus: f32,
+    pub mem_limit: f32,
+}
+
+impl Display for OpenLoopParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            k,
+            ef,
+            fetch_payload,
+            use_filters,
+            arrival,
+            qps,
+            duration: _,
+            max_in_flight: _,
+            quantization,
+            consistency,
+            cpus,
+            mem_limit,
+        } = self;
+        let fetch_payload = if *fetch_payload { "P" } else { "p" };
+        let use_filters = if *use_filters { "F" } else { "f" };
+        let arrival = match arrival {
+            Arrival::Constant => "c",
+            Arrival::Poisson => "p",
+        };
+        write!(
+            f,
+            "{cpus:.2}:{mem_limit:.2}-{k}:{ef}{fetch_payload}{use_filters}-{arrival}{qps}{quantization}{consistency}"
+        )
+    }
+}
+
+/// The outcome of a single open-loop run.
+#[derive(Serialize, Deserialize)]
+pub struct OpenLoopReport {
+    pub target_qps: usize,
+    pub achieved_qps: f64,
+    pub scheduled: usize,
+    pub completed: usize,
+    pub errors: usize,
+    pub dropped: usize,
+    pub late: usize,
+    pub duration_secs: f64,
+    /// Latencies measured from the intended send time.
+    #[serde(skip)]
+    pub latencies: LatencyHistogram,
+}
+
+/// Runs all open-loop benchmarks of the plan against the index build with `iparams`.
+pub fn benchmark<DB>(
+    writer: &ResourceWriter,
+    plan: &BenchmarkPlan,
+    iparams: IngestionParameters,
+    inputs: &Arc<QueryInputs<DB>>,
+) -> Result<(), Error>
+where
+    DB: QueryVectorDatabase,
+{
+    let writer = &writer.sub_writer("open_loop")?;
+    let provider = inputs.database.name();
+    let rt = &Runtime::new()?;
+    for oparams in plan.open_loop_parameters(provider) {
+        let bench_id = format!("{iparams}_{oparams}");
+        eprintln!("{provider}/open_loop/{bench_id}");
+        let writer = writer.sub_writer(&bench_id)?;
+        let docker_stats = DockerStatScanner::start(rt.handle(), inputs.database.service())?;
+        let node_requests = inputs.database.node_requests();
+        let report = rt.block_on(run(inputs, oparams))?;
+        let stats = rt.block_on(docker_stats.stop())?;
+        eprintln!(
+            "achieved {:.1}/{} qps, p99 {:?}, errors {}, dropped {}, late {}",
+            report.achieved_qps,
+            report.target_qps,
+            report.latencies.quantile(0.99),
+            report.errors,
+            report.dropped,
+            report.late,
+        );
+        writer.write_file("docker_stats.json", &stats)?;
+        writer.write_file(
+            "node_requests.json",
+            &inputs.database.node_requests().since(&node_requests),
+        )?;
+        writer.write_file("latency.json", &report.latencies)?;
+        writer.write_file("open_loop.json", &report)?;
+    }
+    Ok(())
+}
+
+/// Sends queries following the arrival schedule for the configured duration.
+pub async fn run<DB>(
+    inputs: &Arc<QueryInputs<DB>>,
+    params: OpenLoopParameters,
+) -> Result<OpenLoopReport, Error>
+where
+    DB: QueryVectorDatabase,
+{
+    let OpenLoopParameters {
+        k,
+        ef,
+        fetch_payload,
+        use_filters,
+        arrival,
+        qps,
+        duration,
+        max_in_flight,
+        quantization,
+        consistency,
+        cpus: _,
+        mem_limit: _,
+    } = params;
+    assert!(qps > 0);
+
+    let mut rng = StdRng::from_rng(thread_rng())?;
+    let mut schedule = Schedule::new(arrival, qps)?;
+    let queries = Uniform::new(0, inputs.vectors.len());
+
+    let mut tasks = JoinSet::<Result<Duration, Error>>::new();
+    let mut report = OpenLoopReport {
+        target_qps: qps,
+        achieved_qps: 0.,
+        scheduled: 0,
+        completed: 0,
+        errors: 0,
+        dropped: 0,
+        late: 0,
+        duration_secs: 0.,
+        latencies: LatencyHistogram::new(),
+    };
+
+    inputs
+        .database
+        .prepare_queries(&QueryRequest {
+            quantization,
+            consistency,
+            ..inputs.request(0, k, ef, fetch_payload, use_filters)
+        })
+        .await?;
+
+    let start = Instant::now();
+    let end = start + duration;
+    let mut intended = start;
+    while intended < end {
+        // collect finished queries while waiting for the next send time
+        loop {
+            tokio::select! {
+                Some(result) = tasks.join_next(), if !tasks.is_empty() => {
+                    report.record(result?);
+                }
+                _ = sleep_until(intended) => break,
+            }
+        }
+
+        report.scheduled += 1;
+        if intended.elapsed() > LATE_THRESHOLD {
+            report.late += 1;
+        }
+        if tasks.len() >= max_in_flight {
+            report.dropped += 1;
+        } else {
+            let inputs = inputs.clone();
+            let idx = rng.sample(queries);
+            tasks.spawn(async move {
+                inputs
+                    .database
+                    .query(&QueryRequest {
+                        quantization,
+                        consistency,
+                        ..inputs.request(idx, k, ef, fetch_payload, use_filters)
+                    })
+                    .await?;
+                Ok(intended.elapsed())
+            });
+        }
+        intended += schedule.next_interval(&mut rng);
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        report.record(result?);
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    report.duration_secs = elapsed;
+    report.achieved_qps = report.completed as f64 / elapsed;
+    Ok(report)
+}
+
+impl OpenLoopReport {
+    fn record(&mut self, result: Result<Duration, Error>) {
+        match result {
+            Ok(latency) => {
+                self.completed += 1;
+                self.latencies.record(latency);
+            }
+            Err(error) => {
+                if self.errors == 0 {
+                    eprintln!("first query error: {error:#}");
+                }
+                self.errors += 1;
+            }
+        }
+    }
+}
+
+enum Schedule {
+    Constant(Duration),
+    Poisson(Exp<f64>),
+}
+
+impl Schedule {
+    fn new(arrival: Arrival, qps: usize) -> Result<Self, Error> {
+        Ok(match arrival {
+            Arrival::Constant => Self::Constant(Duration::from_secs_f64(1. / qps as f64)),
+            Arrival::Poisson => Self::Poisson(Exp::new(qps as f64)?),
+        })
+    }
+
+    fn next_interval(&mut self, rng: &mut impl Rng) -> Duration {
+        match self {
+            Self::Constant(interval) => *interval,
+            Self::Poisson(distribution) => Duration::from_secs_f64(rng.sample(*distribution)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedules_match_target_rate() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for arrival in [Arrival::Constant, Arrival::Poisson] {
+            let mut schedule = Schedule::new(arrival, 200).unwrap();
+            let total = (0..20_000)
+                .map(|_| schedule.next_interval(&mut rng))
+                .sum::<Duration>();
+            let qps = 20_000. / total.as_secs_f64();
+            assert!((qps - 200.).abs() < 5., "{arrival:?}: {qps}");
+        }
+    }
+}
@@ -0,0 +1,1110 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{bail, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consts::{BENCH_MEASUREMENT_TIME, DOCKER_LIMIT_CPUS, DOCKER_LIMIT_MEMORY},
+    databases::Provider,
+};
+
+use super::{
+    mixed::{MixedParameters, WriteMix},
+    open_loop::{Arrival, OpenLoopParameters},
+    saturation::SaturationParameters,
+    sweep::SweepParameters,
+    validation::ValidationParameters,
+    Consistency, ElementType, IndexType, IngestionParameters, Quantization, QuantizationSearch,
+    QueryParameters,
+};
+
+const DEFAULT_MAX_IN_FLIGHT: usize = 1000;
+const DEFAULT_RESOLUTION_QPS: usize = 10;
+const DEFAULT_WRITE_BATCH_SIZE: usize = 10;
+const DEFAULT_VALIDATION_QUERIES: usize = 100;
+const DEFAULT_VALIDATION_TOLERANCE: f32 = 1e-3;
+
+/// A benchmark plan as loaded from e.g. `./benchmark_plan.toml`.
+///
+/// Each matrix describes the cartesian product of all listed values.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BenchmarkPlan {
+    #[serde(default)]
+    ingestion: Vec<IngestionMatrix>,
+    #[serde(default)]
+    query_throughput: Vec<QueryMatrix>,
+    #[serde(default)]
+    open_loop: Vec<OpenLoopMatrix>,
+    #[serde(default)]
+    saturation: Vec<SaturationMatrix>,
+    #[serde(default)]
+    mixed: Vec<MixedMatrix>,
+    #[serde(default)]
+    sweep: Vec<SweepMatrix>,
+    #[serde(default)]
+    validation: Vec<ValidationMatrix>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IngestionMatrix {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    providers: Option<Vec<String>>,
+    m: Vec<usize>,
+    ef_construct: Vec<usize>,
+    #[serde(default = "default_index_type")]
+    index_type: Vec<IndexType>,
+    #[serde(default = "default_element_type")]
+    element_type: Vec<ElementType>,
+    #[serde(default = "default_quantization")]
+    quantization: Vec<Quantization>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct QueryMatrix {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    providers: Option<Vec<String>>,
+    k: Vec<usize>,
+    ef: Vec<usize>,
+    fetch_payload: Vec<bool>,
+    use_filters: Vec<bool>,
+    number_of_tasks: Vec<usize>,
+    queries_per_task: Vec<usize>,
+    /// The fraction of filtered queries whose hits are checked against the filter.
+    #[serde(default)]
+    filter_check_rate: f64,
+    #[serde(default = "default_quantization_search")]
+    quantization: Vec<QuantizationSearch>,
+    #[serde(default = "default_consistency")]
+    consistency: Vec<Consistency>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OpenLoopMatrix {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    providers: Option<Vec<String>>,
+    k: Vec<usize>,
+    ef: Vec<usize>,
+    fetch_payload: Vec<bool>,
+    use_filters: Vec<bool>,
+    arrival: Vec<Arrival>,
+    qps: Vec<usize>,
+    /// Seconds per run, defaults to `BENCH_MEASUREMENT_TIME`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration: Option<u64>,
+    #[serde(default = "default_max_in_flight")]
+    max_in_flight: usize,
+    #[serde(default = "default_quantization_search")]
+    quantization: Vec<QuantizationSearch>,
+    #[serde(default = "default_consistency")]
+    consistency: Vec<Consistency>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SaturationMatrix {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    providers: Option<Vec<String>>,
+    k: Vec<usize>,
+    ef: Vec<usize>,
+    fetch_payload: Vec<bool>,
+    use_filters: Vec<bool>,
+    #[serde(default = "default_arrival")]
+    arrival: Arrival,
+    p99_sla_ms: u64,
+    start_qps: usize,
+    max_qps: usize,
+    #[serde(default = "default_resolution_qps")]
+    resolution_qps: usize,
+    /// Seconds per step, defaults to `BENCH_MEASUREMENT_TIME`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    step_duration: Option<u64>,
+    #[serde(default = "default_max_in_flight")]
+    max_in_flight: usize,
+    #[serde(default = "default_quantization_search")]
+    quantization: Vec<QuantizationSearch>,
+    #[serde(default = "default_consistency")]
+    consistency: Vec<Consistency>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MixedMatrix {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    providers: Option<Vec<String>>,
+    k: Vec<usize>,
+    ef: Vec<usize>,
+    fetch_payload: Vec<bool>,
+    use_filters: Vec<bool>,
+    number_of_tasks: Vec<usize>,
+    write_percent: Vec<usize>,
+    #[serde(default = "default_write_batch_size")]
+    write_batch_size: usize,
+    #[serde(default)]
+    write_mix: WriteMix,
+    /// Seconds per run, defaults to `BENCH_MEASUREMENT_TIME`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration: Option<u64>,
+    #[serde(default = "default_quantization_search")]
+    quantization: Vec<QuantizationSearch>,
+    #[serde(default = "default_consistency")]
+    consistency: Vec<Consistency>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SweepMatrix {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    providers: Option<Vec<String>>,
+    k: Vec<usize>,
+    /// The swept values, not part of the cartesian product.
+    ef: Vec<usize>,
+    fetch_payload: Vec<bool>,
+    use_filters: Vec<bool>,
+    number_of_tasks: Vec<usize>,
+    /// Test queries per point, defaults to all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    queries: Option<usize>,
+    #[serde(default = "default_quantization_search")]
+    quantization: Vec<QuantizationSearch>,
+    #[serde(default = "default_consistency")]
+    consistency: Vec<Consistency>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ValidationMatrix {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    providers: Option<Vec<String>>,
+    k: Vec<usize>,
+    ef: Vec<usize>,
+    fetch_payload: Vec<bool>,
+    use_filters: Vec<bool>,
+    #[serde(default = "default_validation_queries")]
+    queries: usize,
+    #[serde(default = "default_validation_tolerance")]
+    tolerance: f32,
+}
+
+fn default_index_type() -> Vec<IndexType> {
+    vec![IndexType::Hnsw]
+}
+
+fn default_element_type() -> Vec<ElementType> {
+    vec![ElementType::Float]
+}
+
+fn default_quantization() -> Vec<Quantization> {
+    vec![Quantization::None]
+}
+
+fn default_quantization_search() -> Vec<QuantizationSearch> {
+    vec![QuantizationSearch::default()]
+}
+
+fn default_consistency() -> Vec<Consistency> {
+    vec![Consistency::default()]
+}
+
+fn default_validation_queries() -> usize {
+    DEFAULT_VALIDATION_QUERIES
+}
+
+fn default_validation_tolerance() -> f32 {
+    DEFAULT_VALIDATION_TOLERANCE
+}
+
+fn default_write_batch_size() -> usize {
+    DEFAULT_WRITE_BATCH_SIZE
+}
+
+fn default_max_in_flight() -> usize {
+    DEFAULT_MAX_IN_FLIGHT
+}
+
+fn default_arrival() -> Arrival {
+    Arrival::Poisson
+}
+
+fn default_resolution_qps() -> usize {
+    DEFAULT_RESOLUTION_QPS
+}
+
+fn validate_quantization_search(
+    quantization: &QuantizationSearch,
+    providers: &Option<Vec<String>>,
+) -> Result<(), Error> {
+    if *quantization == QuantizationSearch::default() {
+        return Ok(());
+    }
+    if let Some(oversampling) = quantization.oversampling {
+        if oversampling.is_nan() || oversampling < 1. {
+            bail!("oversampling must be >= 1, got: {oversampling}");
+        }
+    }
+    if let Some(provider) = Provider::ALL.iter().find(|provider| {
+        applies_to(providers, provider.name()) && !provider.supports_quantization_search()
+    }) {
+        bail!(
+            "{} doesn't support query time quantization settings, limit the matrix to e.g. `providers = [\"qdrant\"]`",
+            provider.name(),
+        );
+    }
+    Ok(())
+}
+
+fn validate_providers(providers: &Option<Vec<String>>) -> Result<(), Error> {
+    for provider in providers.iter().flatten() {
+        if !Provider::ALL.iter().any(|known| known.name() == provider) {
+            bail!("unknown provider {provider:?}");
+        }
+    }
+    Ok(())
+}
+
+fn validate_ingestion(
+    parameters: &IngestionParameters,
+    providers: &Option<Vec<String>>,
+) -> Result<(), Error> {
+    if let Some(provider) = Provider::ALL.iter().find(|provider| {
+        applies_to(providers, provider.name()) && !provider.supports_ingestion(parameters)
+    }) {
+        bail!(
+            "{} doesn't support {parameters}, limit the matrix to e.g. `providers = [\"elasticsearch\"]`",
+            provider.name(),
+        );
+    }
+    Ok(())
+}
+
+fn validate_consistency(
+    consistency: Consistency,
+    providers: &Option<Vec<String>>,
+) -> Result<(), Error> {
+    if let Some(provider) = Provider::ALL.iter().find(|provider| {
+        applies_to(providers, provider.name()) && !provider.supports_consistency(consistency)
+    }) {
+        bail!(
+            "{} doesn't support consistency {consistency:?}, limit the matrix to e.g. `providers = [\"qdrant\"]`",
+            provider.name(),
+        );
+    }
+    Ok(())
+}
+
+impl BenchmarkPlan {
+    pub fn load(file: impl AsRef<Path>) -> Result<Self, Error> {
+        let bytes = fs::read(file)?;
+        let text = String::from_utf8(bytes)?;
+        Self::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<Self, Error> {
+        let plan: Self = toml::from_str(text)?;
+        plan.validate()?;
+        Ok(plan)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        for matrix in &self.ingestion {
+            validate_providers(&matrix.providers)?;
+            let params = matrix.expand();
+            if params.is_empty() {
+                bail!("empty ingestion matrix: {matrix:?}");
+            }
+            for params in params {
+                match params.quantization {
+                    Quantization::Scalar {
+                        quantile: Some(quantile),
+                        ..
+                    } if !(0.5..=1.).contains(&quantile) => {
+                        bail!("quantile must be in [0.5;1], got: {quantile}");
+                    }
+                    Quantization::None => {}
+                    _ if params.element_type == ElementType::Byte => {
+                        bail!("only float vectors can be quantized");
+                    }
+                    _ => {}
+                }
+                validate_ingestion(&params, &matrix.providers)?;
+            }
+        }
+        for matrix in &self.query_throughput {
+            validate_providers(&matrix.providers)?;
+            for params in matrix.expand(0., 0.) {
+                if params.k > params.ef {
+                    bail!("k must be <= ef, got: k={} ef={}", params.k, params.ef);
+                }
+                if params.number_of_tasks == 0 || params.queries_per_task == 0 {
+                    bail!("number_of_tasks and queries_per_task must be > 0");
+                }
+                if !(0. ..=1.).contains(&params.filter_check_rate) {
+                    bail!("filter_check_rate must be in [0;1]");
+                }
+                validate_quantization_search(&params.quantization, &matrix.providers)?;
+                validate_consistency(params.consistency, &matrix.providers)?;
+            }
+        }
+        for matrix in &self.open_loop {
+            validate_providers(&matrix.providers)?;
+            for params in matrix.expand(0., 0.) {
+                if params.k > params.ef {
+                    bail!("k must be <= ef, got: k={} ef={}", params.k, params.ef);
+                }
+                if params.qps == 0 || params.max_in_flight == 0 || params.duration.is_zero() {
+                    bail!("qps, max_in_flight and duration must be > 0");
+                }
+                validate_quantization_search(&params.quantization, &matrix.providers)?;
+                validate_consistency(params.consistency, &matrix.providers)?;
+            }
+        }
+        for matrix in &self.saturation {
+            validate_providers(&matrix.providers)?;
+            for params in matrix.expand(0., 0.) {
+                if params.k > params.ef {
+                    bail!("k must be <= ef, got: k={} ef={}", params.k, params.ef);
+                }
+                if params.start_qps == 0 || params.start_qps > params.max_qps {
+                    bail!("start_qps must be in [1;max_qps]");
+                }
+                if params.p99_sla.is_zero()
+                    || params.resolution_qps == 0
+                    || params.max_in_flight == 0
+                    || params.step_duration.is_zero()
+                {
+                    bail!(
+                        "p99_sla_ms, resolution_qps, max_in_flight and step_duration must be > 0"
+                    );
+                }
+                validate_quantization_search(&params.quantization, &matrix.providers)?;
+                validate_consistency(params.consistency, &matrix.providers)?;
+            }
+        }
+        for matrix in &self.mixed {
+            validate_providers(&matrix.providers)?;
+            if matrix.write_mix.upsert == 0 {
+                bail!("write_mix.upsert must be > 0, update/delete only touch upserted documents");
+            }
+            for params in matrix.expand(0., 0.) {
+                if params.k > params.ef {
+                    bail!("k must be <= ef, got: k={} ef={}", params.k, params.ef);
+                }
+                if params.number_of_tasks == 0
+                    || params.write_batch_size == 0
+                    || params.duration.is_zero()
+                {
+                    bail!("number_of_tasks, write_batch_size and duration must be > 0");
+                }
+                if params.write_percent > 100 {
+                    bail!("write_percent must be <= 100");
+                }
+                validate_quantization_search(&params.quantization, &matrix.providers)?;
+                validate_consistency(params.consistency, &matrix.providers)?;
+            }
+        }
+        for matrix in &self.sweep {
+            validate_providers(&matrix.providers)?;
+            for params in matrix.expand(0., 0.) {
+                if params.ef.is_empty() {
+                    bail!("empty sweep ef");
+                }
+                if let Some(&ef) = params.ef.iter().find(|&&ef| params.k > ef) {
+                    bail!("k must be <= ef, got: k={} ef={ef}", params.k);
+                }
+                if params.number_of_tasks == 0 || params.queries == 0 {
+                    bail!("number_of_tasks and queries must be > 0");
+                }
+                validate_quantization_search(&params.quantization, &matrix.providers)?;
+                validate_consistency(params.consistency, &matrix.providers)?;
+            }
+        }
+        for matrix in &self.validation {
+            validate_providers(&matrix.providers)?;
+            for params in matrix.expand(0., 0.) {
+                if params.k > params.ef {
+                    bail!("k must be <= ef, got: k={} ef={}", params.k, params.ef);
+                }
+                if params.queries == 0 {
+                    bail!("queries must be > 0");
+                }
+                if params.tolerance.is_nan() || params.tolerance < 0. {
+                    bail!("tolerance must be >= 0");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn ingestion_parameters(&self, provider: &str) -> Vec<IngestionParameters> {
+        self.ingestion
+            .iter()
+            .filter(|matrix| applies_to(&matrix.providers, provider))
+            .flat_map(IngestionMatrix::expand)
+            .collect()
+    }
+
+    pub fn query_throughput_parameters(&self, provider: &str) -> Vec<QueryParameters> {
+        let cpus = *DOCKER_LIMIT_CPUS;
+        let mem_limit = *DOCKER_LIMIT_MEMORY;
+        self.query_throughput
+            .iter()
+            .filter(|matrix| applies_to(&matrix.providers, provider))
+            .flat_map(|matrix| matrix.expand(cpus, mem_limit))
+            .collect()
+    }
+
+    pub fn open_loop_parameters(&self, provider: &str) -> Vec<OpenLoopParameters> {
+        let cpus = *DOCKER_LIMIT_CPUS;
+        let mem_limit = *DOCKER_LIMIT_MEMORY;
+        self.open_loop
+            .iter()
+            .filter(|matrix| applies_to(&matrix.providers, provider))
+            .flat_map(|matrix| matrix.expand(cpus, mem_limit))
+            .collect()
+    }
+
+    pub fn mixed_parameters(&self, provider: &str) -> Vec<MixedParameters> {
+        let cpus = *DOCKER_LIMIT_CPUS;
+        let mem_limit = *DOCKER_LIMIT_MEMORY;
+        self.mixed
+            .iter()
+            .filter(|matrix| applies_to(&matrix.providers, provider))
+            .flat_map(|matrix| matrix.expand(cpus, mem_limit))
+            .collect()
+    }
+
+    pub fn saturation_parameters(&self, provider: &str) -> Vec<SaturationParameters> {
+        let cpus = *DOCKER_LIMIT_CPUS;
+        let mem_limit = *DOCKER_LIMIT_MEMORY;
+        self.saturation
+            .iter()
+            .filter(|matrix| applies_to(&matrix.providers, provider))
+            .flat_map(|matrix| matrix.expand(cpus, mem_limit))
+            .collect()
+    }
+
+    pub fn sweep_parameters(&self, provider: &str) -> Vec<SweepParameters> {
+        let cpus = *DOCKER_LIMIT_CPUS;
+        let mem_limit = *DOCKER_LIMIT_MEMORY;
+        self.sweep
+            .iter()
+            .filter(|matrix| applies_to(&matrix.providers, provider))
+            .flat_map(|matrix| matrix.expand(cpus, mem_limit))
+            .collect()
+    }
+
+    pub fn validation_parameters(&self, provider: &str) -> Vec<ValidationParameters> {
+        let cpus = *DOCKER_LIMIT_CPUS;
+        let mem_limit = *DOCKER_LIMIT_MEMORY;
+        self.validation
+            .iter()
+            .filter(|matrix| applies_to(&matrix.providers, provider))
+            .flat_map(|matrix| matrix.expand(cpus, mem_limit))
+            .collect()
+    }
+}
+
+impl IngestionMatrix {
+    fn expand(&self) -> Vec<IngestionParameters> {
+        let mut out = Vec::new();
+        for &m in &self.m {
+            for &ef_construct in &self.ef_construct {
+                for &index_type in &self.index_type {
+                    for &element_type in &self.element_type {
+                        for &quantization in &self.quantization {
+                            out.push(IngestionParameters {
+                                m,
+                                ef_construct,
+                                index_type,
+                                element_type,
+                                quantization,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl QueryMatrix {
+    fn expand(&self, cpus: f32, mem_limit: f32) -> Vec<QueryParameters> {
+        let mut out = Vec::new();
+        for &k in &self.k {
+            for &ef in &self.ef {
+                for &fetch_payload in &self.fetch_payload {
+                    for &use_filters in &self.use_filters {
+                        for &number_of_tasks in &self.number_of_tasks {
+                            for &queries_per_task in &self.queries_per_task {
+                                for &quantization in &self.quantization {
+                                    for &consistency in &self.consistency {
+                                        out.push(QueryParameters {
+                                            k,
+                                            ef,
+                                            fetch_payload,
+                                            number_of_tasks,
+                                            queries_per_task,
+                                            use_filters,
+                                            filter_check_rate: self.filter_check_rate,
+                                            quantization,
+                                            consistency,
+                                            cpus,
+                                            mem_limit,
+                                        });
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl OpenLoopMatrix {
+    fn expand(&self, cpus: f32, mem_limit: f32) -> Vec<OpenLoopParameters> {
+        let duration = Duration::from_secs(self.duration.unwrap_or(*BENCH_MEASUREMENT_TIME));
+        let mut out = Vec::new();
+        for &k in &self.k {
+            for &ef in &self.ef {
+                for &fetch_payload in &self.fetch_payload {
+                    for &use_filters in &self.use_filters {
+                        for &arrival in &self.arrival {
+                            for &qps in &self.qps {
+                                for &quantization in &self.quantization {
+                                    for &consistency in &self.consistency {
+                                        out.push(OpenLoopParameters {
+                                            k,
+                                            ef,
+                                            fetch_payload,
+                                            use_filters,
+                                            arrival,
+                                            qps,
+                                            duration,
+                                            max_in_flight: self.max_in_flight,
+                                            quantization,
+                                            consistency,
+                                            cpus,
+                                            mem_limit,
+                                        });
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl SaturationMatrix {
+    fn expand(&self, cpus: f32, mem_limit: f32) -> Vec<SaturationParameters> {
+        let step_duration =
+            Duration::from_secs(self.step_duration.unwrap_or(*BENCH_MEASUREMENT_TIME));
+        let mut out = Vec::new();
+        for &k in &self.k {
+            for &ef in &self.ef {
+                for &fetch_payload in &self.fetch_payload {
+                    for &use_filters in &self.use_filters {
+                        for &quantization in &self.quantization {
+                            for &consistency in &self.consistency {
+                                out.push(SaturationParameters {
+                                    k,
+                                    ef,
+                                    fetch_payload,
+                                    use_filters,
+                                    arrival: self.arrival,
+                                    p99_sla: Duration::from_millis(self.p99_sla_ms),
+                                    start_qps: self.start_qps,
+                                    max_qps: self.max_qps,
+                                    resolution_qps: self.resolution_qps,
+                                    step_duration,
+                                    max_in_flight: self.max_in_flight,
+                                    quantization,
+                                    consistency,
+                                    cpus,
+                                    mem_limit,
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl MixedMatrix {
+    fn expand(&self, cpus: f32, mem_limit: f32) -> Vec<MixedParameters> {
+        let duration = Duration::from_secs(self.duration.unwrap_or(*BENCH_MEASUREMENT_TIME));
+        let mut out = Vec::new();
+        for &k in &self.k {
+            for &ef in &self.ef {
+                for &fetch_payload in &self.fetch_payload {
+                    for &use_filters in &self.use_filters {
+                        for &number_of_tasks in &self.number_of_tasks {
+                            for &write_percent in &self.write_percent {
+                                for &quantization in &self.quantization {
+                                    for &consistency in &self.consistency {
+                                        out.push(MixedParameters {
+                                            k,
+                                            ef,
+                                            fetch_payload,
+                                            use_filters,
+                                            number_of_tasks,
+                                            write_percent,
+                                            write_batch_size: self.write_batch_size,
+                                            write_mix: self.write_mix,
+                                            duration,
+                                            quantization,
+                                            consistency,
+                                            cpus,
+                                            mem_limit,
+                                        });
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl SweepMatrix {
+    fn expand(&self, cpus: f32, mem_limit: f32) -> Vec<SweepParameters> {
+        let mut out = Vec::new();
+        for &k in &self.k {
+            for &fetch_payload in &self.fetch_payload {
+                for &use_filters in &self.use_filters {
+                    for &number_of_tasks in &self.number_of_tasks {
+                        for &quantization in &self.quantization {
+                            for &consistency in &self.consistency {
+                                out.push(SweepParameters {
+                                    k,
+                                    ef: self.ef.clone(),
+                                    fetch_payload,
+                                    use_filters,
+                                    number_of_tasks,
+                                    queries: self.queries.unwrap_or(usize::MAX),
+                                    quantization,
+                                    consistency,
+                                    cpus,
+                                    mem_limit,
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl ValidationMatrix {
+    fn expand(&self, cpus: f32, mem_limit: f32) -> Vec<ValidationParameters> {
+        let mut out = Vec::new();
+        for &k in &self.k {
+            for &ef in &self.ef {
+                for &fetch_payload in &self.fetch_payload {
+                    for &use_filters in &self.use_filters {
+                        out.push(ValidationParameters {
+                            k,
+                            ef,
+                            fetch_payload,
+                            use_filters,
+                            queries: self.queries,
+                            tolerance: self.tolerance,
+                            cpus,
+                            mem_limit,
+                        });
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn applies_to(providers: &Option<Vec<String>>, provider: &str) -> bool {
+    providers
+        .as_ref()
+        .is_none_or(|providers| providers.iter().any(|p| p == provider))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_expands_quantization() {
+        let plan = BenchmarkPlan::parse(
+            r#"
+            [[ingestion]]
+            providers = ["qdrant", "qdrant-rest"]
+            m = [16]
+            ef_construct = [100]
+            quantization = [
+                { type = "none" },
+                { type = "scalar", quantile = 0.99, always_ram = true },
+                { type = "product", compression = "x16" },
+                { type = "binary" },
+            ]
+
+            [[query_throughput]]
+            providers = ["qdrant", "qdrant-rest"]
+            k = [10]
+            ef = [100]
+            fetch_payload = [false]
+            use_filters = [false]
+            number_of_tasks = [5]
+            queries_per_task = [10]
+            quantization = [{}, { ignore = true }, { rescore = true, oversampling = 2.0 }]
+
+            [[open_loop]]
+            providers = ["qdrant"]
+            k = [10]
+            ef = [100]
+            fetch_payload = [false]
+            use_filters = [false]
+            arrival = ["poisson"]
+            qps = [50]
+            quantization = [{ rescore = false }]
+            "#,
+        )
+        .unwrap();
+
+        let ingestion = plan.ingestion_parameters("qdrant");
+        let ids = ingestion
+            .iter()
+            .map(|params| (params.to_string(), params.index_name()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                ("16:100", "content_m16_ef100"),
+                ("16:100-s0.99R", "content_m16_ef100_scalar_q0_99_ram"),
+                ("16:100-p16r", "content_m16_ef100_product_x16"),
+                ("16:100-br", "content_m16_ef100_binary"),
+            ]
+            .map(|(id, name)| (id.to_owned(), name.to_owned()))
+        );
+
+        let ids = plan
+            .query_throughput_parameters("qdrant")
+            .iter()
+            .map(|params| params.to_string().split_once('-').unwrap().1.to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            ["10:100pf-5:10", "10:100pf-5:10-qI", "10:100pf-5:10-qRx2"]
+        );
+        let open_loop = plan.open_loop_parameters("qdrant");
+        assert!(open_loop[0].to_string().ends_with("-p50-qr"));
+
+        // the other providers would ignore it
+        assert!(BenchmarkPlan::parse(
+            r#"
+            [[mixed]]
+            k = [10]
+            ef = [100]
+            fetch_payload = [false]
+            use_filters = [false]
+            number_of_tasks = [10]
+            write_percent = [20]
+            quantization = [{ ignore = true }]
+            "#,
+        )
+        .is_err());
+
+        assert!(BenchmarkPlan::parse(
+            r#"
+            [[ingestion]]
+            m = [16]
+            ef_construct = [100]
+            quantization = [{ type = "scalar", quantile = 0.1 }]
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_plan_rejects_unsupported_ingestion_parameters() {
+        let ingestion = |providers: &str| {
+            BenchmarkPlan::parse(&format!(
+                r#"
+                [[ingestion]]
+                {providers}
+                m = [16]
+                ef_construct = [100]
+                index_type = ["flat"]
+                element_type = ["byte"]
+                "#
+            ))
+        };
+        // only elasticsearch supports byte vectors
+        assert!(ingestion("").is_err());
+        assert!(ingestion(r#"providers = ["elasticsearch", "pgvector"]"#).is_err());
+        assert!(ingestion(r#"providers = ["elasticsearch"]"#).is_ok());
+    }
+
+    #[test]
+    fn test_plan_rejects_unknown_providers() {
+        let validation = |provider: &str| {
+            BenchmarkPlan::parse(&format!(
+                r#"
+                [[validation]]
+                providers = ["{provider}"]
+                k = [10]
+                ef = [100]
+                fetch_payload = [false]
+                use_filters = [false]
+                "#
+            ))
+        };
+        assert!(validation("qdrant").is_ok());
+        // a typo would silently drop the matrix
+        assert!(validation("qdarnt").is_err());
+    }
+
+    #[test]
+    fn test_plan_expands_consistency() {
+        let plan = BenchmarkPlan::parse(
+            r#"
+            [[query_throughput]]
+            k = [10]
+            ef = [100]
+            fetch_payload = [false]
+            use_filters = [false]
+            number_of_tasks = [5]
+            queries_per_task = [10]
+            consistency = ["quorum", "all", "majority", "one"]
+            providers = ["qdrant", "qdrant-rest"]
+
+            [[sweep]]
+            k = [10]
+            ef = [10, 20]
+            fetch_payload = [false]
+            use_filters = [false]
+            number_of_tasks = [5]
+            consistency = ["one"]
+            providers = ["qdrant", "elasticsearch", "vespa", "weaviate"]
+
+            [[open_loop]]
+            k = [10]
+            ef = [100]
+            fetch_payload = [false]
+            use_filters = [false]
+            arrival = ["constant"]
+            qps = [100]
+            consistency = ["quorum", "all"]
+            providers = ["qdrant"]
+            "#,
+        )
+        .unwrap();
+
+        let ids = plan
+            .query_throughput_parameters("qdrant")
+            .iter()
+            .map(|params| params.to_string().split_once('-').unwrap().1.to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                "10:100pf-5:10",
+                "10:100pf-5:10-ca",
+                "10:100pf-5:10-cm",
+                "10:100pf-5:10-c1"
+            ]
+        );
+        let sweep = plan.sweep_parameters("qdrant");
+        assert_eq!(sweep.len(), 1);
+        assert!(sweep[0].to_string().ends_with("-10:10..20pf-5-c1"));
+        let open_loop = plan
+            .open_loop_parameters("qdrant")
+            .iter()
+            .map(|params| params.to_string().split_once('-').unwrap().1.to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(open_loop, ["10:100pf-c100", "10:100pf-c100-ca"]);
+
+        assert!(BenchmarkPlan::parse(
+            r#"
+            [[query_throughput]]
+            k = [10]
+            ef = [100]
+            fetch_payload = [false]
+            use_filters = [false]
+            number_of_tasks = [5]
+            queries_per_task = [10]
+            consistency = ["eventual"]
+            "#,
+        )
+        .is_err());
+        // pgvector and milvus only support the default
+        assert!(BenchmarkPlan::parse(
+            r#"
+            [[mixed]]
+            k = [10]
+            ef = [100]
+            fetch_payload = [false]
+            use_filters = [false]
+            number_of_tasks = [5]
+            write_percent = [20]
+            consistency = ["one"]
+            "#,
+        )
+        .is_err());
+        assert!(BenchmarkPlan::parse(
+            r#"
+            [[sweep]]
+            k = [10]
+            ef = [10, 20]
+            fetch_payload = [false]
+            use_filters = [false]
+            number_of_tasks = [5]
+            consistency = ["one"]
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_plan_expands_to_cartesian_product() {
+        let plan = BenchmarkPlan::parse(
+            r#"
+            [[ingestion]]
+            m = [16, 32]
+            ef_construct = [100]
+
+            [[query_throughput]]
+            k = [10]
+            ef = [10, 200]
+            fetch_payload = [false]
+            use_filters = [false, true]
+            number_of_tasks = [5]
+            queries_per_task = [10]
+
+            [[open_loop]]
+            providers = ["qdrant"]
+            k = [10]
+            ef = [100]
+            fetch_payload = [false]
+            use_filters = [true]
+            arrival = ["poisson", "constant"]
+            qps = [100]
+            duration = 10
+
+            [[query_throughput]]
+            providers = ["vespa"]
+            k = [5]
+            ef = [50]
+            fetch_payload = [true]
+            use_filters = [true]
+            number_of_tasks = [5, 10]
+            queries_per_task = [1]
+            "#,
+        )
+        .unwrap();
+
+        let ingestion = plan
+            .ingestion_parameters("qdrant")
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(ingestion, ["16:100", "32:100"]);
+
+        let ids = |provider| {
+            plan.query_throughput_parameters(provider)
+                .iter()
+                .map(|params| params.to_string().split_once('-').unwrap().1.to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids("qdrant"),
+            [
+                "10:10pf-5:10",
+                "10:10pF-5:10",
+                "10:200pf-5:10",
+                "10:200pF-5:10"
+            ]
+        );
+        assert_eq!(
+            ids("vespa"),
+            [
+                "10:10pf-5:10",
+                "10:10pF-5:10",
+                "10:200pf-5:10",
+                "10:200pF-5:10",
+                "5:50PF-5:1",
+                "5:50PF-10:1"
+            ]
+        );
+
+        let open_loop = plan.open_loop_parameters("qdrant");
+        assert_eq!(
+            open_loop
+                .iter()
+                .map(|params| params.to_string().split_once('-').unwrap().1.to_owned())
+                .collect::<Vec<_>>(),
+            ["10:100pF-p100", "10:100pF-c100"]
+        );
+        assert_eq!(open_loop[0].duration, Duration::from_secs(10));
+        assert_eq!(open_loop[0].max_in_flight, DEFAULT_MAX_IN_FLIGHT);
+        assert!(plan.open_loop_parameters("vespa").is_empty());
+    }
+
+    #[test]
+    fn test_plan_does_not_expand_sweep_ef() {
+        let plan = BenchmarkPlan::parse(
+            r#"
+            [[sweep]]
+            k = [10]
+            ef = [10, 20, 40]
+            fetch_payload = [false]
+            use_filters = [false, true]
+            number_of_tasks = [5]
+            "#,
+        )
+        .unwrap();
+        let ids = plan
+            .sweep_parameters("qdrant")
+            .iter()
+            .map(|params| params.to_string().split_once('-').unwrap().1.to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["10:10..40pf-5", "10:10..40pF-5"]);
+    }
+
+    #[test]
+    fn test_plan_rejects_k_larger_than_ef() {
+        let result = BenchmarkPlan::parse(
+            r#"
+            [[query_throughput]]
+            k = [100]
+            ef = [10]
+            fetch_payload = [false]
+            use_filters = [false]
+            number_of_tasks = [5]
+            queries_per_task = [10]
+            "#,
+        );
+        assert!(result.is_err());
+    }
+}
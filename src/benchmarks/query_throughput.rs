@@ -12,7 +12,11 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use anyhow::{Context, Error};
 use criterion::{measurement::Measurement, BenchmarkGroup, BenchmarkId, Criterion, Throughput};
@@ -23,192 +27,53 @@ use tokio::{runtime::Runtime, sync::mpsc, task::JoinSet};
 use uuid::Uuid;
 
 use crate::{
-    benchmarks::{IngestionParameters, QueryParameters},
-    consts::{DOCKER_LIMIT_CPUS, DOCKER_LIMIT_MEMORY},
-    distribution::{ids::fake_uuid_to_index, QueryPayload},
+    benchmarks::{
+        filter_compliance::FilterComplianceReport, plan::BenchmarkPlan, IngestionParameters,
+        QueryParameters, QueryRequest,
+    },
+    consts::BENCH_SAMPLE_SIZE,
+    distribution::{ids::fake_uuid_to_index, DocumentPayload},
     docker::DockerStatScanner,
-    resources::{load_bincode, load_vectors, ResolvedPaths, ResourceWriter},
+    math::LatencyHistogram,
+    resources::{load_bincode, ResolvedPaths, ResourceWriter},
 };
 
-use super::QueryVectorDatabase;
+use super::{QueryInputs, QueryVectorDatabase};
 
-struct Inputs<DB>
-where
-    DB: QueryVectorDatabase,
-{
-    database: DB,
-    payloads: Vec<QueryPayload>,
-    vectors: Vec<Vec<f32>>,
-}
-
-pub fn benchmark(
+/// Runs all query throughput benchmarks of the plan against the index build with `iparams`.
+pub fn benchmark<DB>(
     writer: &ResourceWriter,
     paths: &ResolvedPaths,
-    database: impl QueryVectorDatabase,
+    plan: &BenchmarkPlan,
+    iparams: IngestionParameters,
+    inputs: &Arc<QueryInputs<DB>>,
     c: &mut Criterion,
-) -> Result<(), Error> {
-    paths.check_files_exists()?;
-
-    let cpus = *DOCKER_LIMIT_CPUS;
-    let mem_limit = *DOCKER_LIMIT_MEMORY;
-
+) -> Result<(), Error>
+where
+    DB: QueryVectorDatabase,
+{
     let writer = &writer.sub_writer("query_throughput")?;
-    writer.write_file("path.json", paths)?;
 
-    let payloads: Vec<QueryPayload> =
-        load_bincode(&paths.query_payload_file).context("loading bincode payloads")?;
-    let vectors = load_vectors(&paths.vectors_file, "test").context("loading vector data")?;
-    let nr_elements = vectors.len();
-    assert!(payloads.len() == nr_elements);
-
-    let inputs = Arc::new(Inputs {
-        database,
-        payloads,
-        vectors,
-    });
-
-    //TODO don't hardcode this
-    let ingestion_parameters = IngestionParameters {
-        m: 16,
-        ef_construct: 100,
-    };
+    let provider = inputs.database.name();
+    let query_parameters = plan.query_throughput_parameters(provider);
+    let documents = query_parameters
+        .iter()
+        .any(|qparams| qparams.use_filters && qparams.filter_check_rate > 0.)
+        .then(|| {
+            load_bincode::<Vec<DocumentPayload>>(&paths.document_payload_file)
+                .context("loading bincode payloads")
+                .map(Arc::new)
+        })
+        .transpose()?;
 
     let rt = &Runtime::new()?;
-    let mut group = c.benchmark_group(inputs.database.name());
-
-    bench(
-        writer,
-        &mut group,
-        rt,
-        &inputs,
-        ingestion_parameters,
-        QueryParameters {
-            k: 10,
-            ef: 10,
-            number_of_tasks: 5,
-            queries_per_task: 10,
-            fetch_payload: false,
-            use_filters: false,
-            cpus,
-            mem_limit,
-        },
-    )?;
-
-    bench(
-        writer,
-        &mut group,
-        rt,
-        &inputs,
-        ingestion_parameters,
-        QueryParameters {
-            k: 10,
-            ef: 200,
-            number_of_tasks: 5,
-            queries_per_task: 10,
-            fetch_payload: false,
-            use_filters: false,
-            cpus,
-            mem_limit,
-        },
-    )?;
-
-    for k in [10, 50, 100] {
-        bench(
-            writer,
-            &mut group,
-            rt,
-            &inputs,
-            ingestion_parameters,
-            QueryParameters {
-                k,
-                fetch_payload: false,
-                ef: k,
-                number_of_tasks: 5,
-                queries_per_task: 10,
-                use_filters: true,
-                cpus,
-                mem_limit,
-            },
-        )?;
-    }
-
-    bench(
-        writer,
-        &mut group,
-        rt,
-        &inputs,
-        ingestion_parameters,
-        QueryParameters {
-            k: 100,
-            fetch_payload: true,
-            ef: 100,
-            number_of_tasks: 5,
-            queries_per_task: 10,
-            use_filters: true,
-            cpus,
-            mem_limit,
-        },
-    )?;
-
-    bench(
-        writer,
-        &mut group,
-        rt,
-        &inputs,
-        ingestion_parameters,
-        QueryParameters {
-            k: 10,
-            fetch_payload: false,
-            ef: 100,
-            number_of_tasks: 5,
-            queries_per_task: 10,
-            use_filters: true,
-            cpus,
-            mem_limit,
-        },
-    )?;
+    let mut group = c.benchmark_group(provider);
 
     // with given resource limits we have to be careful to not
-    // go too high as it will timeout (it's non stop 5/10/20 requests
-    // not 5/10/20 hy\pothetical users)
-    for number_of_tasks in [5, 10, 20] {
-        bench(
-            writer,
-            &mut group,
-            rt,
-            &inputs,
-            ingestion_parameters,
-            QueryParameters {
-                k: 10,
-                fetch_payload: false,
-                ef: 100,
-                number_of_tasks,
-                queries_per_task: 1,
-                use_filters: true,
-                cpus,
-                mem_limit,
-            },
-        )?;
-    }
-
-    for k in [5, 25] {
-        bench(
-            writer,
-            &mut group,
-            rt,
-            &inputs,
-            ingestion_parameters,
-            QueryParameters {
-                k,
-                fetch_payload: false,
-                ef: 50,
-                number_of_tasks: 5,
-                queries_per_task: 10,
-                use_filters: true,
-                cpus,
-                mem_limit,
-            },
-        )?;
+    // go too high with `number_of_tasks` as it will timeout (it's
+    // non stop 5/10/20 requests not 5/10/20 hypothetical users)
+    for qparams in query_parameters {
+        bench(writer, &mut group, rt, inputs, &documents, iparams, qparams)?;
     }
 
     Ok(())
@@ -218,14 +83,15 @@ fn bench<M, DB>(
     writer: &ResourceWriter,
     group: &mut BenchmarkGroup<'_, M>,
     rt: &Runtime,
-    inputs: &Arc<Inputs<DB>>,
+    inputs: &Arc<QueryInputs<DB>>,
+    documents: &Option<Arc<Vec<DocumentPayload>>>,
     iparams: IngestionParameters,
     qparams: QueryParameters,
 ) -> Result<(), Error>
 where
     DB: QueryVectorDatabase,
     M: Measurement + 'static,
-    Arc<Inputs<DB>>: Send,
+    Arc<QueryInputs<DB>>: Send,
 {
     let QueryParameters {
         k,
@@ -234,53 +100,97 @@ where
         number_of_tasks,
         queries_per_task,
         use_filters,
+        filter_check_rate,
+        quantization,
+        consistency,
         cpus: _,
         mem_limit: _,
     } = qparams;
-    //FIXME We currently can only have recall data for the non filter case
-    //      for now, but running a perfect KNN with filters would be nice.
-    let enable_recall_stats = !use_filters;
     assert!(k <= ef);
     assert!(queries_per_task > 0);
     assert!(number_of_tasks > 0);
 
+    rt.block_on(inputs.database.prepare_queries(&QueryRequest {
+        quantization,
+        consistency,
+        ..inputs.request(0, k, ef, fetch_payload, use_filters)
+    }))?;
+
     let bench_id = format!("{iparams}_{qparams}");
-    let writer = writer.sub_writer(&bench_id)?;
-    let writer2 = writer.clone();
-    let docker_stats = DockerStatScanner::start(rt.handle(), inputs.database.name())?;
+    // criterion skips benchmarks which don't match `--filter`, so the
+    // directory of the benchmark is only created once there are results
+    let bench_writer = {
+        let writer = writer.clone();
+        let bench_id = bench_id.clone();
+        move || writer.sub_writer(&bench_id)
+    };
+    let docker_stats = DockerStatScanner::start(rt.handle(), inputs.database.service())?;
+    let node_requests = inputs.database.node_requests();
+    // criterion only times whole batches, so we additionally time each query,
+    // the latencies are kept per sample to drop the ones recorded during the warm-up
+    let latencies = Arc::new(Mutex::new(VecDeque::with_capacity(BENCH_SAMPLE_SIZE + 1)));
+    let latencies2 = latencies.clone();
+    // criterion can't stop a benchmark, so the first error is kept and reported afterwards
+    let failure = Arc::new(Mutex::new(None::<Error>));
+    let failure2 = failure.clone();
 
     // We send the recall data out of the benchmark and write it in a separate task.
+    // The filter compliance is checked there too to keep it out of the measurements.
+    let compliance = documents
+        .clone()
+        .filter(|_| use_filters && filter_check_rate > 0.)
+        .map(|documents| (documents, inputs.clone()));
 
     let (recall_sender, mut recall_receiver) = mpsc::unbounded_channel();
     let writer_task = rt.spawn(async move {
-        if !enable_recall_stats {
-            recall_receiver.close();
-            return Ok(());
-        }
-
+        let mut rng = StdRng::from_rng(thread_rng())?;
+        let mut compliance_report = FilterComplianceReport::default();
         let recall_file = "recall_data.jsonl";
-        writer.write_file(
-            recall_file,
-            &json!({
-                "expected_hits": k,
-            }),
-        )?;
+        let mut recall_writer = None;
         let mut recall_data = Vec::new();
         while let Some(group) = recall_receiver.recv().await {
             match group {
-                Group::Add { query_id, vectors } => recall_data.push((
-                    query_id,
-                    vectors
+                Group::Add { query_id, vectors } => {
+                    let hits = vectors
                         .into_iter()
-                        .map(fake_uuid_to_index)
-                        .collect::<Vec<_>>(),
-                )),
+                        .map(|id| usize::try_from(fake_uuid_to_index(id)).unwrap_or(usize::MAX))
+                        .collect::<Vec<_>>();
+                    if let Some((documents, inputs)) = &compliance {
+                        if rng.gen_bool(filter_check_rate) {
+                            compliance_report.check(&inputs.payloads[query_id], &hits, documents);
+                        }
+                    }
+                    recall_data.push((query_id, hits));
+                }
                 Group::Write => {
+                    let writer = if let Some(writer) = &recall_writer {
+                        writer
+                    } else {
+                        let writer = bench_writer()?;
+                        writer.write_file(
+                            recall_file,
+                            &json!({
+                                "expected_hits": k,
+                                // filtered recall needs the filtered ground truth
+                                "filtered": use_filters,
+                            }),
+                        )?;
+                        recall_writer.insert(writer)
+                    };
                     writer.append_line_to_file(recall_file, &recall_data)?;
                     recall_data.truncate(0);
                 }
             }
         }
+        if let (Some(writer), Some(_)) = (&recall_writer, &compliance) {
+            if compliance_report.violations > 0 {
+                eprintln!(
+                    "{} of {} checked hits violate the filter",
+                    compliance_report.violations, compliance_report.checked_hits,
+                );
+            }
+            writer.write_file("filter_compliance.json", &compliance_report)?;
+        }
         Result::<_, Error>::Ok(())
     });
 
@@ -289,62 +199,90 @@ where
             (queries_per_task * number_of_tasks) as _,
         ))
         .bench_with_input(
-            BenchmarkId::new("query_throughput", bench_id),
+            BenchmarkId::new("query_throughput", &bench_id),
             inputs,
             move |b, inputs| {
+                let sample_latencies = Mutex::new(LatencyHistogram::new());
                 b.to_async(rt).iter(|| async {
-                    let mut tasks = JoinSet::<Result<(), Error>>::new();
+                    if failure.lock().unwrap().is_some() {
+                        return;
+                    }
+                    let mut tasks = JoinSet::<Result<LatencyHistogram, Error>>::new();
                     for _ in 0..number_of_tasks {
                         let recall_sender = recall_sender.clone();
                         let inputs = inputs.clone();
                         tasks.spawn(async move {
-                            let Inputs {
-                                database,
-                                payloads,
-                                vectors,
-                            } = &*inputs;
+                            let mut latencies = LatencyHistogram::new();
                             // we randomly sample queries from the set of test queries
                             let rng = StdRng::from_rng(thread_rng())?;
                             for idx in rng
                                 .sample_iter(Uniform::new(0, inputs.vectors.len()))
                                 .take(queries_per_task)
                             {
-                                let vectors = database
-                                    .query(
-                                        k,
-                                        ef,
-                                        &vectors[idx],
-                                        &payloads[idx],
-                                        fetch_payload,
-                                        use_filters,
-                                    )
-                                    .await?;
+                                let start = Instant::now();
+                                let vectors = inputs
+                                    .database
+                                    .query(&QueryRequest {
+                                        quantization,
+                                        consistency,
+                                        ..inputs.request(idx, k, ef, fetch_payload, use_filters)
+                                    })
+                                    .await?
+                                    .ids()
+                                    .collect();
+                                latencies.record(start.elapsed());
 
-                                if enable_recall_stats {
-                                    recall_sender.send(Group::Add {
-                                        query_id: idx,
-                                        vectors,
-                                    })?;
-                                }
+                                recall_sender.send(Group::Add {
+                                    query_id: idx,
+                                    vectors,
+                                })?;
                             }
-                            Ok(())
+                            Ok(latencies)
                         });
                     }
 
-                    while let Some(result) = tasks.join_next().await {
-                        result.unwrap().unwrap();
+                    let mut result = Ok(());
+                    while let Some(task_result) = tasks.join_next().await {
+                        result = result
+                            .and_then(|()| sample_latencies.lock().unwrap().merge(&task_result??));
                     }
-
-                    if enable_recall_stats {
-                        recall_sender.send(Group::Write).unwrap();
+                    result = result.and_then(|()| Ok(recall_sender.send(Group::Write)?));
+                    if let Err(error) = result {
+                        failure.lock().unwrap().get_or_insert(error);
                     }
-                })
+                });
+                // criterion calls this once per warm-up step followed by
+                // once per sample, so the last calls are the measurement
+                let mut latencies = latencies.lock().unwrap();
+                latencies.push_back(sample_latencies.into_inner().unwrap());
+                if latencies.len() > BENCH_SAMPLE_SIZE {
+                    latencies.pop_front();
+                }
             },
         );
 
     let stats = rt.block_on(docker_stats.stop())?;
-    writer2.write_file("docker_stats.json", &stats)?;
+    if let Some(error) = failure2.lock().unwrap().take() {
+        return Err(error);
+    }
     rt.block_on(writer_task)??;
+    let latencies = latencies2.lock().unwrap().iter().try_fold(
+        LatencyHistogram::new(),
+        |mut latencies, sample| {
+            latencies.merge(sample)?;
+            Result::<_, Error>::Ok(latencies)
+        },
+    )?;
+    if latencies.count() == 0 {
+        return Ok(());
+    }
+    let writer = writer.sub_writer(&bench_id)?;
+    writer.write_file("docker_stats.json", &stats)?;
+    writer.write_file(
+        "node_requests.json",
+        &inputs.database.node_requests().since(&node_requests),
+    )?;
+    writer.write_file("latency.json", &latencies)?;
     Ok(())
 }
 
@@ -0,0 +1,286 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Searches the highest QPS a provider can sustain under a p99 latency SLA.
+//!
+//! The offered load is doubled until a step fails and then binary searched
+//! between the last passing and the first failing step. Each step is an
+//! open-loop run, see [`super::open_loop`].
+
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+use crate::{
+    benchmarks::{plan::BenchmarkPlan, Consistency, IngestionParameters, QuantizationSearch},
+    docker::DockerStatScanner,
+    resources::ResourceWriter,
+};
+
+use super::{
+    open_loop::{self, Arrival, OpenLoopParameters, OpenLoopReport},
+    QueryInputs, QueryVectorDatabase,
+};
+
+#[derive(Clone, Copy)]
+pub struct SaturationParameters {
+    pub k: usize,
+    pub ef: usize,
+    pub fetch_payload: bool,
+    pub use_filters: bool,
+    pub arrival: Arrival,
+    pub p99_sla: Duration,
+    pub start_qps: usize,
+    pub max_qps: usize,
+    /// The search stops once the passing and failing QPS are at most this far apart.
+    pub resolution_qps: usize,
+    pub step_duration: Duration,
+    pub max_in_flight: usize,
+    pub quantization: QuantizationSearch,
+    pub consistency: Consistency,
+    pub cpus: f32,
+    pub mem_limit: f32,
+}
+
+impl SaturationParameters {
+    fn step(&self, qps: usize) -> OpenLoopParameters {
+        OpenLoopParameters {
+            k: self.k,
+            ef: self.ef,
+            fetch_payload: self.fetch_payload,
+            use_filters: self.use_filters,
+            arrival: self.arrival,
+            qps,
+            duration: self.step_duration,
+            max_in_flight: self.max_in_flight,
+            quantization: self.quantization,
+            consistency: self.consistency,
+            cpus: self.cpus,
+            mem_limit: self.mem_limit,
+        }
+    }
+}
+
+impl Display for SaturationParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            k,
+            ef,
+            fetch_payload,
+            use_filters,
+            arrival,
+            p99_sla,
+            start_qps: _,
+            max_qps: _,
+            resolution_qps: _,
+            step_duration: _,
+            max_in_flight: _,
+            quantization,
+            consistency,
+            cpus,
+            mem_limit,
+        } = self;
+        let fetch_payload = if *fetch_payload { "P" } else { "p" };
+        let use_filters = if *use_filters { "F" } else { "f" };
+        let arrival = match arrival {
+            Arrival::Constant => "c",
+            Arrival::Poisson => "p",
+        };
+        let sla = p99_sla.as_millis();
+        write!(
+            f,
+            "{cpus:.2}:{mem_limit:.2}-{k}:{ef}{fetch_payload}{use_filters}-{arrival}{sla}ms{quantization}{consistency}"
+        )
+    }
+}
+
+/// The load/latency curve of a saturation search.
+#[derive(Serialize, Deserialize)]
+pub struct SaturationReport {
+    pub p99_sla_ms: f64,
+    /// The highest passing QPS, `None` if not even the lowest probed QPS passed.
+    pub max_sustainable_qps: Option<usize>,
+    /// All steps in the order they were run.
+    pub steps: Vec<SaturationStep>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SaturationStep {
+    pub target_qps: usize,
+    pub achieved_qps: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub p99_9_ms: f64,
+    pub errors: usize,
+    pub dropped: usize,
+    pub late: usize,
+    pub passed: bool,
+}
+
+impl SaturationStep {
+    fn new(report: &OpenLoopReport, p99_sla: Duration) -> Self {
+        let ms = |quantile| report.latencies.quantile(quantile).as_secs_f64() * 1e3;
+        let p99 = report.latencies.quantile(0.99);
+        // dropped queries mean we couldn't even keep the requested rate in flight
+        let passed = p99 < p99_sla && report.errors == 0 && report.dropped == 0;
+        Self {
+            target_qps: report.target_qps,
+            achieved_qps: report.achieved_qps,
+            p50_ms: ms(0.5),
+            p90_ms: ms(0.9),
+            p99_ms: ms(0.99),
+            p99_9_ms: ms(0.999),
+            errors: report.errors,
+            dropped: report.dropped,
+            late: report.late,
+            passed,
+        }
+    }
+}
+
+/// Runs all saturation searches of the plan against the index build with `iparams`.
+pub fn benchmark<DB>(
+    writer: &ResourceWriter,
+    plan: &BenchmarkPlan,
+    iparams: IngestionParameters,
+    inputs: &Arc<QueryInputs<DB>>,
+) -> Result<(), Error>
+where
+    DB: QueryVectorDatabase,
+{
+    let writer = &writer.sub_writer("saturation")?;
+    let provider = inputs.database.name();
+    let rt = &Runtime::new()?;
+    for sparams in plan.saturation_parameters(provider) {
+        let bench_id = format!("{iparams}_{sparams}");
+        eprintln!("{provider}/saturation/{bench_id}");
+        let writer = writer.sub_writer(&bench_id)?;
+        let docker_stats = DockerStatScanner::start(rt.handle(), inputs.database.service())?;
+        let node_requests = inputs.database.node_requests();
+
+        let mut search = Search::new(sparams.start_qps, sparams.max_qps, sparams.resolution_qps);
+        let mut steps = Vec::new();
+        while let Some(qps) = search.next {
+            let report = rt.block_on(open_loop::run(inputs, sparams.step(qps)))?;
+            let step = SaturationStep::new(&report, sparams.p99_sla);
+            eprintln!(
+                "{qps} qps: p99 {:.2}ms, errors {}, dropped {} => {}",
+                step.p99_ms,
+                step.errors,
+                step.dropped,
+                if step.passed { "pass" } else { "fail" },
+            );
+            search.update(qps, step.passed);
+            steps.push(step);
+        }
+
+        let stats = rt.block_on(docker_stats.stop())?;
+        writer.write_file("docker_stats.json", &stats)?;
+        writer.write_file(
+            "node_requests.json",
+            &inputs.database.node_requests().since(&node_requests),
+        )?;
+        writer.write_file(
+            "saturation.json",
+            &SaturationReport {
+                p99_sla_ms: sparams.p99_sla.as_secs_f64() * 1e3,
+                max_sustainable_qps: search.passed,
+                steps,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Doubles the QPS until a step fails, then binary searches the boundary.
+struct Search {
+    /// The highest passing QPS.
+    passed: Option<usize>,
+    /// The lowest failing QPS.
+    failed: Option<usize>,
+    next: Option<usize>,
+    max_qps: usize,
+    resolution_qps: usize,
+}
+
+impl Search {
+    fn new(start_qps: usize, max_qps: usize, resolution_qps: usize) -> Self {
+        Self {
+            passed: None,
+            failed: None,
+            next: Some(start_qps.min(max_qps)),
+            max_qps,
+            resolution_qps,
+        }
+    }
+
+    fn update(&mut self, qps: usize, passed: bool) {
+        if passed {
+            self.passed = Some(qps);
+        } else {
+            self.failed = Some(qps);
+        }
+        self.next = match (self.passed, self.failed) {
+            (Some(_), None) => (qps < self.max_qps).then(|| (qps * 2).min(self.max_qps)),
+            (None, Some(failed)) => (failed > self.resolution_qps).then_some(failed / 2),
+            (Some(passed), Some(failed)) => {
+                (failed - passed > self.resolution_qps).then(|| passed + (failed - passed) / 2)
+            }
+            (None, None) => unreachable!(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_search(start: usize, max: usize, capacity: usize) -> (Option<usize>, Vec<usize>) {
+        let mut search = Search::new(start, max, 10);
+        let mut probed = Vec::new();
+        while let Some(qps) = search.next {
+            probed.push(qps);
+            search.update(qps, qps <= capacity);
+        }
+        (search.passed, probed)
+    }
+
+    #[test]
+    fn test_search_finds_capacity() {
+        let (max, probed) = run_search(50, 2000, 330);
+        assert_eq!(probed, [50, 100, 200, 400, 300, 350, 325, 337, 331]);
+        assert_eq!(max, Some(325));
+    }
+
+    #[test]
+    fn test_search_stops_at_max_qps() {
+        let (max, probed) = run_search(50, 150, 1000);
+        assert_eq!(probed, [50, 100, 150]);
+        assert_eq!(max, Some(150));
+    }
+
+    #[test]
+    fn test_search_goes_below_start() {
+        let (max, probed) = run_search(100, 1000, 30);
+        assert_eq!(probed, [100, 50, 25, 37, 31]);
+        assert_eq!(max, Some(25));
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Recall vs. QPS trade-off of the search time `ef` parameter.
+//!
+//! For each `ef` (`num_candidates` for elasticsearch, `exploreAdditionalHits` for vespa)
+//! the test queries are run once and throughput and recall@k are recorded, like the
+//! ann-benchmarks plots. The points not dominated in both recall and QPS form the
+//! Pareto frontier.
+
+use std::{
+    fmt::{self, Display},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use anyhow::{bail, Error};
+use plotters::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::{runtime::Runtime, task::JoinSet};
+
+use crate::{
+    benchmarks::{
+        plan::BenchmarkPlan, Consistency, IngestionParameters, QuantizationSearch, QueryRequest,
+    },
+    distribution::{ground_truth::load_filtered_neighbors, ids::fake_uuid_to_index},
+    docker::DockerStatScanner,
+    math::LatencyHistogram,
+    resources::{load_neighbors, ResolvedPaths, ResourceWriter},
+};
+
+use super::{QueryInputs, QueryVectorDatabase};
+
+#[derive(Clone)]
+pub struct SweepParameters {
+    pub k: usize,
+    /// The swept values, in the order they are run.
+    pub ef: Vec<usize>,
+    pub fetch_payload: bool,
+    pub use_filters: bool,
+    pub number_of_tasks: usize,
+    /// How many of the test queries are run per point.
+    pub queries: usize,
+    pub quantization: QuantizationSearch,
+    pub consistency: Consistency,
+    pub cpus: f32,
+    pub mem_limit: f32,
+}
+
+impl Display for SweepParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            k,
+            ef,
+            fetch_payload,
+            use_filters,
+            number_of_tasks,
+            queries: _,
+            quantization,
+            consistency,
+            cpus,
+            mem_limit,
+        } = self;
+        let fetch_payload = if *fetch_payload { "P" } else { "p" };
+        let use_filters = if *use_filters { "F" } else { "f" };
+        let min_ef = ef.iter().min().unwrap_or(&0);
+        let max_ef = ef.iter().max().unwrap_or(&0);
+        write!(
+            f,
+            "{cpus:.2}:{mem_limit:.2}-{k}:{min_ef}..{max_ef}{fetch_payload}{use_filters}-{number_of_tasks}{quantization}{consistency}"
+        )
+    }
+}
+
+/// The measured trade-off of a sweep.
+#[derive(Serialize, Deserialize)]
+pub struct SweepReport {
+    pub k: usize,
+    pub filtered: bool,
+    /// All points in the order they were run.
+    pub points: Vec<SweepPoint>,
+    /// The points not dominated by any other point, by ascending recall.
+    pub pareto: Vec<SweepPoint>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SweepPoint {
+    pub ef: usize,
+    pub recall: f64,
+    pub qps: f64,
+    pub p50_ms: f64,
+    pub p99_ms: f64,
+}
+
+/// Runs all sweeps of the plan against the index build with `iparams`.
+pub fn benchmark<DB>(
+    writer: &ResourceWriter,
+    paths: &ResolvedPaths,
+    plan: &BenchmarkPlan,
+    iparams: IngestionParameters,
+    inputs: &Arc<QueryInputs<DB>>,
+) -> Result<(), Error>
+where
+    DB: QueryVectorDatabase,
+{
+    let provider = inputs.database.name();
+    let all_sparams = plan.sweep_parameters(provider);
+    if all_sparams.is_empty() {
+        return Ok(());
+    }
+    let unfiltered = all_sparams
+        .iter()
+        .any(|sparams| !sparams.use_filters)
+        .then(|| load_neighbors(&paths.vectors_file).map(Arc::new))
+        .transpose()?;
+    let filtered = all_sparams
+        .iter()
+        .any(|sparams| sparams.use_filters)
+        .then(|| {
+            if !paths.filtered_neighbors_file.exists() {
+                bail!("missing filtered ground truth, run `vdb-bench ground-truth`");
+            }
+            load_filtered_neighbors(paths).map(Arc::new)
+        })
+        .transpose()?;
+
+    let writer = &writer.sub_writer("sweep")?;
+    let rt = &Runtime::new()?;
+    for sparams in all_sparams {
+        let neighbors = if sparams.use_filters {
+            &filtered
+        } else {
+            &unfiltered
+        };
+        let neighbors = neighbors.as_ref().expect("ground truth is loaded");
+
+        let bench_id = format!("{iparams}_{sparams}");
+        eprintln!("{provider}/sweep/{bench_id}");
+        let writer = writer.sub_writer(&bench_id)?;
+        let docker_stats = DockerStatScanner::start(rt.handle(), inputs.database.service())?;
+        let node_requests = inputs.database.node_requests();
+
+        // warm up caches so that the first point isn't disadvantaged
+        rt.block_on(run_point(inputs, neighbors, &sparams, sparams.ef[0]))?;
+        let mut points = Vec::with_capacity(sparams.ef.len());
+        for &ef in &sparams.ef {
+            let point = rt.block_on(run_point(inputs, neighbors, &sparams, ef))?;
+            eprintln!(
+                "ef {ef}: recall {:.4}, {:.1} qps, p99 {:.2}ms",
+                point.recall, point.qps, point.p99_ms
+            );
+            points.push(point);
+        }
+
+        let stats = rt.block_on(docker_stats.stop())?;
+        let report = SweepReport {
+            k: sparams.k,
+            filtered: sparams.use_filters,
+            pareto: pareto_frontier(&points),
+            points,
+        };
+        writer.write_file("docker_stats.json", &stats)?;
+        writer.write_file(
+            "node_requests.json",
+            &inputs.database.node_requests().since(&node_requests),
+        )?;
+        writer.write_raw_file(
+            "sweep.svg",
+            plot(&format!("{provider} {bench_id}"), &report)?.as_bytes(),
+        )?;
+        writer.write_file("sweep.json", &report)?;
+    }
+    Ok(())
+}
+
+/// Runs the first `sparams.queries` test queries once with given `ef`.
+async fn run_point<DB>(
+    inputs: &Arc<QueryInputs<DB>>,
+    neighbors: &Arc<Vec<Vec<usize>>>,
+    sparams: &SweepParameters,
+    ef: usize,
+) -> Result<SweepPoint, Error>
+where
+    DB: QueryVectorDatabase,
+{
+    let SweepParameters {
+        k,
+        fetch_payload,
+        use_filters,
+        number_of_tasks,
+        queries,
+        quantization,
+        consistency,
+        ..
+    } = *sparams;
+    let queries = queries.min(inputs.vectors.len());
+    let next_query = Arc::new(AtomicUsize::new(0));
+    inputs
+        .database
+        .prepare_queries(&QueryRequest {
+            quantization,
+            consistency,
+            ..inputs.request(0, k, ef, fetch_payload, use_filters)
+        })
+        .await?;
+
+    let start = Instant::now();
+    let mut tasks = JoinSet::new();
+    for _ in 0..number_of_tasks {
+        let inputs = inputs.clone();
+        let neighbors = neighbors.clone();
+        let next_query = next_query.clone();
+        tasks.spawn(async move {
+            let mut latencies = LatencyHistogram::new();
+            let mut recall = (0., 0);
+            loop {
+                let idx = next_query.fetch_add(1, Ordering::Relaxed);
+                if idx >= queries {
+                    break;
+                }
+                let query_start = Instant::now();
+                let response = inputs
+                    .database
+                    .query(&QueryRequest {
+                        quantization,
+                        consistency,
+                        ..inputs.request(idx, k, ef, fetch_payload, use_filters)
+                    })
+                    .await?;
+                latencies.record(query_start.elapsed());
+                let ids = response.ids().collect::<Vec<_>>();
+                if let Some(recall_at_k) = recall_at_k(k, &neighbors[idx], &ids) {
+                    recall.0 += recall_at_k;
+                    recall.1 += 1;
+                }
+            }
+            Ok::<_, Error>((latencies, recall))
+        });
+    }
+
+    let mut latencies = LatencyHistogram::new();
+    let (mut recall_sum, mut recall_count) = (0., 0);
+    while let Some(result) = tasks.join_next().await {
+        let (task_latencies, (task_recall_sum, task_recall_count)) = result??;
+        latencies.merge(&task_latencies)?;
+        recall_sum += task_recall_sum;
+        recall_count += task_recall_count;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let ms = |quantile| latencies.quantile(quantile).as_secs_f64() * 1e3;
+    Ok(SweepPoint {
+        ef,
+        recall: if recall_count == 0 {
+            0.
+        } else {
+            recall_sum / recall_count as f64
+        },
+        qps: queries as f64 / elapsed,
+        p50_ms: ms(0.5),
+        p99_ms: ms(0.99),
+    })
+}
+
+/// Returns `None` if no document matches the query.
+fn recall_at_k(k: usize, neighbors: &[usize], ids: &[uuid::Uuid]) -> Option<f64> {
+    let expected = &neighbors[..neighbors.len().min(k)];
+    if expected.is_empty() {
+        return None;
+    }
+    let true_positive = ids
+        .iter()
+        .take(k)
+        .filter(|id| {
+            let idx = fake_uuid_to_index(**id);
+            usize::try_from(idx).is_ok_and(|idx| expected.contains(&idx))
+        })
+        .count();
+    Some(true_positive as f64 / expected.len() as f64)
+}
+
+/// Returns the points for which no other point has both a higher recall and QPS.
+fn pareto_frontier(points: &[SweepPoint]) -> Vec<SweepPoint> {
+    let mut sorted = points.to_vec();
+    sorted.sort_by(|l, r| r.recall.total_cmp(&l.recall).then(r.qps.total_cmp(&l.qps)));
+    let mut frontier = Vec::<SweepPoint>::new();
+    for point in sorted {
+        if frontier.last().is_none_or(|last| point.qps > last.qps) {
+            frontier.push(point);
+        }
+    }
+    frontier.reverse();
+    frontier
+}
+
+/// Plots QPS (log scale) over recall, ann-benchmarks style.
+fn plot(title: &str, report: &SweepReport) -> Result<String, Error> {
+    const DIMENSIONS: (u32, u32) = (1024, 768);
+
+    let max_qps = report
+        .points
+        .iter()
+        .map(|point| point.qps)
+        .fold(1., f64::max);
+    let min_qps = report
+        .points
+        .iter()
+        .map(|point| point.qps)
+        .fold(max_qps, f64::min);
+
+    let mut svg = String::new();
+    {
+        let root = SVGBackend::with_string(&mut svg, DIMENSIONS).into_drawing_area();
+        root.fill(&WHITE)?;
+        let mut chart = ChartBuilder::on(&root)
+            .caption(title, ("sans-serif", 20))
+            .margin(20)
+            .x_label_area_size(40)
+            .y_label_area_size(60)
+            .build_cartesian_2d(0f64..1f64, (min_qps * 0.8..max_qps * 1.2).log_scale())?;
+        chart
+            .configure_mesh()
+            .x_desc(format!("recall@{}", report.k))
+            .y_desc("QPS")
+            .draw()?;
+
+        chart
+            .draw_series(
+                report
+                    .points
+                    .iter()
+                    .map(|point| Circle::new((point.recall, point.qps), 3, BLUE.filled())),
+            )?
+            .label("ef")
+            .legend(|(x, y)| Circle::new((x, y), 3, BLUE.filled()));
+        chart
+            .draw_series(LineSeries::new(
+                report.pareto.iter().map(|point| (point.recall, point.qps)),
+                RED.stroke_width(2),
+            ))?
+            .label("pareto frontier")
+            .legend(|(x, y)| PathElement::new([(x - 10, y), (x + 10, y)], RED.stroke_width(2)));
+        chart.draw_series(report.points.iter().map(|point| {
+            Text::new(
+                point.ef.to_string(),
+                (point.recall, point.qps),
+                ("sans-serif", 12),
+            )
+        }))?;
+        chart
+            .configure_series_labels()
+            .position(SeriesLabelPosition::UpperRight)
+            .background_style(WHITE)
+            .border_style(BLACK)
+            .draw()?;
+        root.present()?;
+    }
+    Ok(svg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(ef: usize, recall: f64, qps: f64) -> SweepPoint {
+        SweepPoint {
+            ef,
+            recall,
+            qps,
+            p50_ms: 0.,
+            p99_ms: 0.,
+        }
+    }
+
+    #[test]
+    fn test_pareto_frontier_drops_dominated_points() {
+        let points = [
+            point(10, 0.5, 900.),
+            point(20, 0.7, 1000.),
+            point(40, 0.8, 600.),
+            point(80, 0.8, 500.),
+            point(160, 0.9, 200.),
+            point(320, 0.85, 100.),
+        ];
+        let efs = pareto_frontier(&points)
+            .iter()
+            .map(|point| point.ef)
+            .collect::<Vec<_>>();
+        assert_eq!(efs, [20, 40, 160]);
+    }
+
+    #[test]
+    fn test_recall_at_k_ignores_queries_without_matches() {
+        let ids = [3, 1, 7].map(crate::distribution::ids::index_to_fake_uuid);
+        assert_eq!(recall_at_k(2, &[1, 3, 7], &ids), Some(1.));
+        assert_eq!(recall_at_k(3, &[1, 2], &ids), Some(0.5));
+        assert_eq!(recall_at_k(3, &[], &ids), None);
+    }
+}
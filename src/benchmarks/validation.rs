@@ -0,0 +1,356 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Validates the ordering and scores of query results.
+//!
+//! The distance of each hit is recomputed from the train vectors and compared
+//! against the order of the hits and the distance derived from the returned score, see
+//! [`QueryVectorDatabase::score_to_distance`]. A mis-ordered result would silently skew
+//! recall, so the benchmark fails if any violation is found.
+//!
+//! The scores of quantized or byte vectors only approximate the distance, for those only
+//! the order of the scores and the ids are checked.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    sync::Arc,
+};
+
+use anyhow::{bail, Error};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+use crate::{
+    benchmarks::{plan::BenchmarkPlan, ElementType, IngestionParameters, Quantization},
+    distribution::ids::fake_uuid_to_index,
+    math::Distance,
+    resources::{load_vectors_by_index, ResolvedPaths, ResourceWriter},
+};
+
+use super::{QueryInputs, QueryVectorDatabase};
+
+/// How many violations are written to the report as examples.
+const MAX_EXAMPLES: usize = 20;
+
+#[derive(Clone, Copy)]
+pub struct ValidationParameters {
+    pub k: usize,
+    pub ef: usize,
+    pub fetch_payload: bool,
+    pub use_filters: bool,
+    /// How many of the test queries are validated.
+    pub queries: usize,
+    /// The relative tolerance when comparing distances.
+    pub tolerance: f32,
+    pub cpus: f32,
+    pub mem_limit: f32,
+}
+
+impl Display for ValidationParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            k,
+            ef,
+            fetch_payload,
+            use_filters,
+            queries,
+            tolerance: _,
+            cpus,
+            mem_limit,
+        } = self;
+        let fetch_payload = if *fetch_payload { "P" } else { "p" };
+        let use_filters = if *use_filters { "F" } else { "f" };
+        write!(
+            f,
+            "{cpus:.2}:{mem_limit:.2}-{k}:{ef}{fetch_payload}{use_filters}-v{queries}"
+        )
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ValidationReport {
+    pub queries: usize,
+    pub hits: usize,
+    /// Hits which are farther away than the next hit.
+    pub misordered: usize,
+    /// Hits whose score-derived distance is larger than the one of the next hit.
+    pub non_monotonic_scores: usize,
+    /// Hits whose score doesn't match the recomputed distance.
+    pub score_mismatches: usize,
+    /// Hits which aren't part of the train vectors.
+    pub unknown_ids: usize,
+    /// The first violations found.
+    pub examples: Vec<Violation>,
+}
+
+impl ValidationReport {
+    pub fn violations(&self) -> usize {
+        self.misordered + self.non_monotonic_scores + self.score_mismatches + self.unknown_ids
+    }
+
+    fn record(&mut self, violation: Violation) {
+        match violation.kind {
+            ViolationKind::Misordered => self.misordered += 1,
+            ViolationKind::NonMonotonicScore => self.non_monotonic_scores += 1,
+            ViolationKind::ScoreMismatch => self.score_mismatches += 1,
+            ViolationKind::UnknownId => self.unknown_ids += 1,
+        }
+        if self.examples.len() < MAX_EXAMPLES {
+            self.examples.push(violation);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Violation {
+    pub query: usize,
+    /// The position of the hit in the result.
+    pub rank: usize,
+    pub kind: ViolationKind,
+    /// The recomputed distance, `None` for unknown ids.
+    pub distance: Option<f32>,
+    /// The distance derived from the returned score.
+    pub score_distance: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationKind {
+    Misordered,
+    NonMonotonicScore,
+    ScoreMismatch,
+    UnknownId,
+}
+
+/// Runs all validations of the plan against the index build with `iparams`.
+pub fn benchmark<DB>(
+    writer: &ResourceWriter,
+    paths: &ResolvedPaths,
+    plan: &BenchmarkPlan,
+    iparams: IngestionParameters,
+    inputs: &Arc<QueryInputs<DB>>,
+) -> Result<(), Error>
+where
+    DB: QueryVectorDatabase,
+{
+    let writer = &writer.sub_writer("validation")?;
+    let provider = inputs.database.name();
+    let rt = &Runtime::new()?;
+    let exact_scores =
+        iparams.quantization == Quantization::None && iparams.element_type == ElementType::Float;
+    for vparams in plan.validation_parameters(provider) {
+        let bench_id = format!("{iparams}_{vparams}");
+        eprintln!("{provider}/validation/{bench_id}");
+        let writer = writer.sub_writer(&bench_id)?;
+
+        let ValidationParameters {
+            k,
+            ef,
+            fetch_payload,
+            use_filters,
+            queries,
+            tolerance,
+            ..
+        } = vparams;
+        let queries = queries.min(inputs.vectors.len());
+        let responses = rt.block_on(async {
+            inputs
+                .database
+                .prepare_queries(&inputs.request(0, k, ef, fetch_payload, use_filters))
+                .await?;
+            let mut responses = Vec::with_capacity(queries);
+            for idx in 0..queries {
+                let response = inputs
+                    .database
+                    .query(&inputs.request(idx, k, ef, fetch_payload, use_filters))
+                    .await?;
+                let hits = response
+                    .hits
+                    .iter()
+                    .map(|hit| {
+                        let idx = usize::try_from(fake_uuid_to_index(hit.id)).unwrap_or(usize::MAX);
+                        (idx, inputs.database.score_to_distance(hit.score))
+                    })
+                    .collect::<Vec<_>>();
+                responses.push(hits);
+            }
+            Ok::<_, Error>(responses)
+        })?;
+
+        let train = load_vectors_by_index(
+            &paths.vectors_file,
+            "train",
+            responses.iter().flatten().map(|(idx, _)| *idx),
+        )?;
+        let mut report = ValidationReport {
+            queries,
+            ..ValidationReport::default()
+        };
+        for (query, hits) in responses.iter().enumerate() {
+            report.hits += hits.len();
+            let vector = &inputs.vectors[query];
+            let violations = validate(
+                inputs.distance,
+                query,
+                vector,
+                hits,
+                &train,
+                tolerance,
+                exact_scores,
+            );
+            for violation in violations {
+                report.record(violation);
+            }
+        }
+
+        writer.write_file("validation.json", &report)?;
+        if report.violations() > 0 {
+            bail!(
+                "{provider}/validation/{bench_id}: {} violations in {} hits ({} misordered, {} non-monotonic scores, {} score mismatches, {} unknown ids), see validation.json",
+                report.violations(),
+                report.hits,
+                report.misordered,
+                report.non_monotonic_scores,
+                report.score_mismatches,
+                report.unknown_ids,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks the hits of a single query given as train vector index and score-derived distance.
+///
+/// Without `exact_scores` the scores aren't compared with the recomputed distances.
+fn validate(
+    distance: Distance,
+    query: usize,
+    vector: &[f32],
+    hits: &[(usize, f32)],
+    train: &HashMap<usize, Vec<f32>>,
+    tolerance: f32,
+    exact_scores: bool,
+) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let distances = hits
+        .iter()
+        .map(|(idx, _)| {
+            train
+                .get(idx)
+                .map_or(f32::NAN, |train| distance.distance(vector, train))
+        })
+        .collect::<Vec<_>>();
+
+    for (rank, (&(_, score_distance), &distance)) in hits.iter().zip(&distances).enumerate() {
+        let violation = |kind| Violation {
+            query,
+            rank,
+            kind,
+            distance: (!distance.is_nan()).then_some(distance),
+            score_distance,
+        };
+        if distance.is_nan() {
+            violations.push(violation(ViolationKind::UnknownId));
+            continue;
+        }
+        if exact_scores && !approx_eq(distance, score_distance, tolerance) {
+            violations.push(violation(ViolationKind::ScoreMismatch));
+        }
+        if let Some(&(_, next_score_distance)) = hits.get(rank + 1) {
+            if !approx_le(score_distance, next_score_distance, tolerance) {
+                violations.push(violation(ViolationKind::NonMonotonicScore));
+            }
+        }
+        if let Some(&next_distance) = distances.get(rank + 1) {
+            if exact_scores
+                && !next_distance.is_nan()
+                && !approx_le(distance, next_distance, tolerance)
+            {
+                violations.push(violation(ViolationKind::Misordered));
+            }
+        }
+    }
+    violations
+}
+
+fn approx_le(a: f32, b: f32, tolerance: f32) -> bool {
+    a <= b + tolerance * a.abs().max(b.abs()).max(1.)
+}
+
+fn approx_eq(a: f32, b: f32, tolerance: f32) -> bool {
+    approx_le(a, b, tolerance) && approx_le(b, a, tolerance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_detects_violations() {
+        let train = HashMap::from([(0, vec![1., 0.]), (1, vec![2., 0.]), (2, vec![3., 0.])]);
+        let query = [0., 0.];
+
+        assert!(validate(
+            Distance::Euclidean,
+            0,
+            &query,
+            &[(0, 1.), (1, 2.), (2, 3.)],
+            &train,
+            1e-3,
+            true,
+        )
+        .is_empty());
+
+        let kinds = |hits: &[(usize, f32)], exact_scores| {
+            validate(
+                Distance::Euclidean,
+                0,
+                &query,
+                hits,
+                &train,
+                1e-3,
+                exact_scores,
+            )
+            .into_iter()
+            .map(|violation| (violation.rank, violation.kind))
+            .collect::<Vec<_>>()
+        };
+        // what the ascending elasticsearch sort did
+        assert_eq!(
+            kinds(&[(2, 3.), (1, 2.), (0, 1.)], true),
+            [
+                (0, ViolationKind::NonMonotonicScore),
+                (0, ViolationKind::Misordered),
+                (1, ViolationKind::NonMonotonicScore),
+                (1, ViolationKind::Misordered),
+            ]
+        );
+        assert_eq!(
+            kinds(&[(0, 1.), (1, 2.5), (7, 3.)], true),
+            [
+                (1, ViolationKind::ScoreMismatch),
+                (2, ViolationKind::UnknownId)
+            ]
+        );
+        // approximated scores only need to be ordered
+        assert_eq!(
+            kinds(&[(1, 1.), (0, 1.5), (7, 1.2)], false),
+            [
+                (1, ViolationKind::NonMonotonicScore),
+                (2, ViolationKind::UnknownId)
+            ]
+        );
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::Error;
+use clap::{Args, Parser, Subcommand};
+use criterion::Criterion;
+use tokio::runtime::Runtime;
+use vdb_benchmarks::{
+    benchmarks::{
+        mixed, open_loop, plan::BenchmarkPlan, query_throughput, saturation, sweep, validation,
+        IngestionParameters, QueryInputs, QueryVectorDatabase,
+    },
+    consts::{BENCH_MEASUREMENT_TIME, BENCH_SAMPLE_SIZE},
+    databases::{balancer::Balancing, Provider, ProviderVisitor},
+    distribution::{
+        ground_truth::generate_filtered_ground_truth,
+        ingestion::{ingest_database, PrepareVectorDatabase},
+        Distributions,
+    },
+    docker::compose::ComposeProject,
+    math::Distance,
+    resources::{archive_reports, load_distance, ResolvedPaths, ResourceWriter},
+    stats::print_stats,
+};
+
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generates the document and query payloads and the filtered ground truth for a dataset.
+    Generate {
+        #[command(flatten)]
+        dataset: DatasetArgs,
+
+        /// The settings describing the payload distributions.
+        #[arg(long, default_value = "./generation_settings.toml")]
+        settings: PathBuf,
+    },
+    /// (Re-)generates the exact nearest neighbors of the queries under their filters.
+    ///
+    /// This is done as part of `generate` and only needed for existing payloads.
+    GroundTruth {
+        #[command(flatten)]
+        dataset: DatasetArgs,
+    },
+    /// Initializes an index per ingestion parameters of the plan and ingests the dataset if needed.
+    Ingest {
+        #[command(flatten)]
+        providers: ProviderArgs,
+
+        #[command(flatten)]
+        dataset: DatasetArgs,
+
+        #[command(flatten)]
+        output: OutputArgs,
+
+        #[command(flatten)]
+        plan: PlanArgs,
+    },
+    /// Runs the benchmarks of the plan against the providers.
+    Bench {
+        #[command(flatten)]
+        providers: ProviderArgs,
+
+        #[command(flatten)]
+        dataset: DatasetArgs,
+
+        #[command(flatten)]
+        output: OutputArgs,
+
+        #[command(flatten)]
+        plan: PlanArgs,
+
+        /// Only run benchmarks which match this regex.
+        #[arg(long)]
+        filter: Option<String>,
+
+        /// Start each provider with docker compose (stopping all others), ingest if needed
+        /// and tear it down afterwards.
+        #[arg(long)]
+        managed: bool,
+
+        /// Recreate the docker volumes before starting a managed provider.
+        #[arg(long, requires = "managed")]
+        recreate_volumes: bool,
+    },
+    /// Prints recall, precision and docker stats of all benchmarks in a report dir.
+    Stats {
+        #[command(flatten)]
+        dataset: DatasetArgs,
+
+        /// The report dir either `./reports` or `./committed_reports/<reports_name>/`
+        #[arg(index = 1, default_value = "./reports")]
+        report_dir: PathBuf,
+    },
+    /// Moves the reports into the committed reports dir.
+    Report {
+        #[command(flatten)]
+        output: OutputArgs,
+
+        /// The dir in which a `<date>.<git-hash>` sub-dir is created for the reports.
+        #[arg(long, default_value = "./comitted_reports")]
+        committed_dir: PathBuf,
+    },
+}
+
+#[derive(Args, Debug)]
+struct ProviderArgs {
+    /// The providers to run against, defaults to all providers.
+    #[arg(short, long = "provider", value_enum)]
+    providers: Vec<Provider>,
+
+    /// How requests are spread across the nodes of a provider.
+    #[arg(long, value_enum, default_value_t)]
+    balancing: Balancing,
+}
+
+impl ProviderArgs {
+    fn providers(&self) -> &[Provider] {
+        if self.providers.is_empty() {
+            Provider::ALL
+        } else {
+            &self.providers
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct DatasetArgs {
+    /// The ann-benchmarks hdf5 file, payload files are placed next to it.
+    #[arg(long, default_value = "./resources/gist-960-euclidean.hdf5")]
+    vectors: PathBuf,
+
+    /// The distance metric, defaults to the `distance` attribute of the dataset.
+    #[arg(long, value_enum)]
+    distance: Option<Distance>,
+}
+
+impl DatasetArgs {
+    fn distance(&self) -> Result<Distance, Error> {
+        self.distance
+            .map_or_else(|| load_distance(&self.vectors), Ok)
+    }
+}
+
+#[derive(Args, Debug)]
+struct PlanArgs {
+    /// The plan describing which indices are build and which benchmarks are run.
+    #[arg(long = "plan", default_value = "./benchmark_plan.toml")]
+    file: PathBuf,
+}
+
+impl PlanArgs {
+    fn load(&self) -> Result<BenchmarkPlan, Error> {
+        BenchmarkPlan::load(&self.file)
+    }
+}
+
+#[derive(Args, Debug)]
+struct OutputArgs {
+    /// The dir into which `additional_data` and `criterion` reports are written.
+    #[arg(long, default_value = "./reports")]
+    out_dir: PathBuf,
+}
+
+impl OutputArgs {
+    fn writer(
+        &self,
+        provider: Provider,
+        paths: &ResolvedPaths,
+        plan: &BenchmarkPlan,
+    ) -> Result<ResourceWriter, Error> {
+        let writer = ResourceWriter::new(self.out_dir.join("additional_data"), [provider.name()])?;
+        writer.write_file("path.json", paths)?;
+        writer.write_file("plan.json", plan)?;
+        Ok(writer)
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Generate { dataset, settings } => {
+            let distance = dataset.distance()?;
+            let paths = ResolvedPaths::resolve(dataset.vectors);
+            Distributions::load(settings)?.generate_payload_files(&paths)?;
+            generate_filtered_ground_truth(&paths, distance)?;
+        }
+        Command::GroundTruth { dataset } => {
+            let distance = dataset.distance()?;
+            generate_filtered_ground_truth(&ResolvedPaths::resolve(dataset.vectors), distance)?;
+        }
+        Command::Ingest {
+            providers,
+            dataset,
+            output,
+            plan,
+        } => {
+            let rt = Runtime::new()?;
+            let distance = dataset.distance()?;
+            let paths = ResolvedPaths::resolve(dataset.vectors);
+            let plan = plan.load()?;
+            for &provider in providers.providers() {
+                let writer = output.writer(provider, &paths, &plan)?;
+                for iparams in plan.ingestion_parameters(provider.name()) {
+                    provider.visit(
+                        &iparams,
+                        distance,
+                        providers.balancing,
+                        Ingest {
+                            rt: &rt,
+                            writer: &writer,
+                            paths: &paths,
+                            iparams,
+                        },
+                    )??;
+                }
+                writer.write_close_msg()?;
+            }
+        }
+        Command::Bench {
+            providers,
+            dataset,
+            output,
+            plan,
+            filter,
+            managed,
+            recreate_volumes,
+        } => {
+            let rt = Runtime::new()?;
+            let distance = dataset.distance()?;
+            let paths = ResolvedPaths::resolve(dataset.vectors);
+            let plan = plan.load()?;
+            let mut c = Criterion::default()
+                .output_directory(&output.out_dir.join("criterion"))
+                .measurement_time(Duration::from_secs(*BENCH_MEASUREMENT_TIME))
+                .sample_size(BENCH_SAMPLE_SIZE);
+            if let Some(filter) = filter {
+                c = c.with_filter(filter);
+            }
+            for &provider in providers.providers() {
+                let writer = output.writer(provider, &paths, &plan)?;
+                let project = managed.then(|| ComposeProject::new(provider));
+                if let Some(project) = &project {
+                    rt.block_on(project.start(recreate_volumes))?;
+                }
+                let result = plan
+                    .ingestion_parameters(provider.name())
+                    .into_iter()
+                    .try_for_each(|iparams| {
+                        provider.visit(
+                            &iparams,
+                            distance,
+                            providers.balancing,
+                            Bench {
+                                ingest_with: managed.then_some(&rt),
+                                writer: &writer,
+                                paths: &paths,
+                                distance,
+                                plan: &plan,
+                                iparams,
+                                c: &mut c,
+                            },
+                        )?
+                    });
+                if let Some(project) = &project {
+                    rt.block_on(project.down())?;
+                }
+                result?;
+                writer.write_close_msg()?;
+            }
+            c.final_summary();
+        }
+        Command::Stats {
+            dataset,
+            report_dir,
+        } => {
+            print_stats(&report_dir, &ResolvedPaths::resolve(dataset.vectors))?;
+        }
+        Command::Report {
+            output,
+            committed_dir,
+        } => {
+            let target = archive_reports(&output.out_dir, &committed_dir)?;
+            println!("{}", target.display());
+        }
+    }
+    Ok(())
+}
+
+struct Ingest<'a> {
+    rt: &'a Runtime,
+    writer: &'a ResourceWriter,
+    paths: &'a ResolvedPaths,
+    iparams: IngestionParameters,
+}
+
+impl ProviderVisitor for Ingest<'_> {
+    type Output = Result<(), Error>;
+
+    fn visit<DB>(self, database: DB) -> Self::Output
+    where
+        DB: PrepareVectorDatabase + QueryVectorDatabase,
+    {
+        self.rt.block_on(async {
+            ingest_database(self.writer, self.paths, &database, &self.iparams).await
+        })
+    }
+}
+
+struct Bench<'a> {
+    ingest_with: Option<&'a Runtime>,
+    writer: &'a ResourceWriter,
+    paths: &'a ResolvedPaths,
+    distance: Distance,
+    plan: &'a BenchmarkPlan,
+    iparams: IngestionParameters,
+    c: &'a mut Criterion,
+}
+
+impl ProviderVisitor for Bench<'_> {
+    type Output = Result<(), Error>;
+
+    fn visit<DB>(self, database: DB) -> Self::Output
+    where
+        DB: PrepareVectorDatabase + QueryVectorDatabase,
+    {
+        if let Some(rt) = self.ingest_with {
+            rt.block_on(async {
+                ingest_database(self.writer, self.paths, &database, &self.iparams).await
+            })?;
+        }
+        let inputs = QueryInputs::load(self.paths, self.distance, database)?;
+        // no point in benchmarking results which are wrong
+        validation::benchmark(self.writer, self.paths, self.plan, self.iparams, &inputs)?;
+        query_throughput::benchmark(
+            self.writer,
+            self.paths,
+            self.plan,
+            self.iparams,
+            &inputs,
+            self.c,
+        )?;
+        open_loop::benchmark(self.writer, self.plan, self.iparams, &inputs)?;
+        mixed::benchmark(self.writer, self.paths, self.plan, self.iparams, &inputs)?;
+        saturation::benchmark(self.writer, self.plan, self.iparams, &inputs)?;
+        sweep::benchmark(self.writer, self.paths, self.plan, self.iparams, &inputs)
+    }
+}
@@ -28,7 +28,29 @@ pub static DOCKER_LIMIT_MEMORY: Lazy<f32> = Lazy::new(|| {
     parse_env("DOCKER_LIMIT_MEM", 8.).unwrap()
 });
 
+//WARNING: Has to be kept in sync with the nodes in docker compose
+/// The number of database nodes of each docker compose setup.
+pub const CLUSTER_NODES: u16 = 3;
+
+/// The most authors or tags a generated document can have.
+///
+/// The capacity of the label array fields of milvus, it can't be changed after creating a collection.
+pub const MAX_LABELS_PER_DOCUMENT: usize = 64;
+
+pub static VESPA_MIN_REDUNDANCY: Lazy<usize> = Lazy::new(|| {
+    // copies of each document stored across the nodes
+    parse_env("VESPA_MIN_REDUNDANCY", 1).unwrap()
+});
+
+pub static VESPA_SEARCHABLE_COPIES: Lazy<usize> = Lazy::new(|| {
+    // copies of each document which are indexed, by default on every node
+    parse_env("VESPA_SEARCHABLE_COPIES", CLUSTER_NODES.into()).unwrap()
+});
+
 pub static BENCH_MEASUREMENT_TIME: Lazy<u64> = Lazy::new(|| {
     // FIXME accept 30s
     parse_env("BENCH_MEASUREMENT_TIME", 30).unwrap()
 });
+
+/// Number of samples criterion takes per benchmark.
+pub const BENCH_SAMPLE_SIZE: usize = 10;
@@ -12,10 +12,310 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod balancer;
 pub mod elasticsearch;
+pub mod milvus;
+pub mod pgvector;
 pub mod qdrant;
 pub mod vespa;
+pub mod weaviate;
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Error};
+use clap::ValueEnum;
+use reqwest::Url;
+use tokio::time::sleep;
+
+use crate::{
+    benchmarks::{
+        Consistency, ElementType, IndexType, IngestionParameters, Quantization, QueryVectorDatabase,
+    },
+    consts::CLUSTER_NODES,
+    distribution::ingestion::PrepareVectorDatabase,
+    math::Distance,
+};
+
+use self::{
+    balancer::{Balancing, NodeRequests},
+    elasticsearch::{elastic_index_options, Elasticsearch},
+    milvus::Milvus,
+    pgvector::Pgvector,
+    qdrant::{rest::QdrantRest, Qdrant},
+    vespa::Vespa,
+    weaviate::Weaviate,
+};
 
 pub trait DatabaseName {
     fn name(&self) -> &str;
+
+    /// The name of the docker compose service, the same as [`DatabaseName::name`] except
+    /// for alternative adapters of the same database.
+    fn service(&self) -> &str {
+        self.name()
+    }
+
+    /// The number of requests sent to each node so far.
+    fn node_requests(&self) -> NodeRequests {
+        NodeRequests::default()
+    }
+}
+
+/// Registry of all supported vector database providers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Provider {
+    Qdrant,
+    /// Qdrant over the REST API instead of gRPC.
+    QdrantRest,
+    Vespa,
+    Elasticsearch,
+    /// PostgreSQL with the pgvector extension.
+    Pgvector,
+    Milvus,
+    Weaviate,
+}
+
+impl Provider {
+    pub const ALL: &'static [Provider] = &[
+        Provider::Qdrant,
+        Provider::QdrantRest,
+        Provider::Vespa,
+        Provider::Elasticsearch,
+        Provider::Pgvector,
+        Provider::Milvus,
+        Provider::Weaviate,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Provider::Qdrant => "qdrant",
+            Provider::QdrantRest => "qdrant-rest",
+            Provider::Vespa => "vespa",
+            Provider::Elasticsearch => "elasticsearch",
+            Provider::Pgvector => "pgvector",
+            Provider::Milvus => "milvus",
+            Provider::Weaviate => "weaviate",
+        }
+    }
+
+    /// The provider running the docker compose setup, see [`DatabaseName::service`].
+    pub fn service(self) -> Provider {
+        match self {
+            Provider::QdrantRest => Provider::Qdrant,
+            provider => provider,
+        }
+    }
+
+    /// Whether the query time usage of quantized vectors can be configured.
+    pub fn supports_quantization_search(self) -> bool {
+        match self {
+            Provider::Qdrant | Provider::QdrantRest => true,
+            Provider::Vespa
+            | Provider::Elasticsearch
+            | Provider::Pgvector
+            | Provider::Milvus
+            | Provider::Weaviate => false,
+        }
+    }
+
+    /// Whether a collection/index can be created with given ingestion parameters.
+    ///
+    /// Restrictions depending on the dataset, like elasticsearch byte vectors with the dot
+    /// product, are only checked when the collection/index is created.
+    pub fn supports_ingestion(self, parameters: &IngestionParameters) -> bool {
+        let IngestionParameters {
+            index_type,
+            element_type,
+            quantization,
+            ..
+        } = *parameters;
+        match self {
+            Provider::Qdrant | Provider::QdrantRest => {
+                index_type == IndexType::Hnsw && element_type == ElementType::Float
+            }
+            Provider::Vespa => {
+                index_type == IndexType::Hnsw
+                    && element_type == ElementType::Float
+                    && quantization == Quantization::None
+            }
+            Provider::Elasticsearch => elastic_index_options(parameters).is_ok(),
+            Provider::Pgvector | Provider::Milvus | Provider::Weaviate => {
+                element_type == ElementType::Float && quantization == Quantization::None
+            }
+        }
+    }
+
+    /// Whether queries can be sent with given read consistency, see [`Consistency`].
+    pub fn supports_consistency(self, consistency: Consistency) -> bool {
+        match self {
+            Provider::Qdrant | Provider::QdrantRest => true,
+            Provider::Vespa | Provider::Elasticsearch => {
+                matches!(consistency, Consistency::Quorum | Consistency::One)
+            }
+            Provider::Weaviate => consistency != Consistency::Majority,
+            Provider::Pgvector | Provider::Milvus => consistency == Consistency::default(),
+        }
+    }
+
+    /// Creates the database client for this provider and passes it to the visitor.
+    ///
+    /// The client targets the collection/index build with given ingestion parameters
+    /// and the distance metric of the dataset, requests are spread across all nodes of
+    /// the docker compose setup according to `balancing`.
+    pub fn visit<V>(
+        self,
+        parameters: &IngestionParameters,
+        distance: Distance,
+        balancing: Balancing,
+        visitor: V,
+    ) -> Result<V::Output, Error>
+    where
+        V: ProviderVisitor,
+    {
+        let index = parameters.index_name();
+        Ok(match self {
+            Provider::Qdrant => {
+                visitor.visit(Qdrant::new(&node_ids(1), balancing, index, distance)?)
+            }
+            Provider::QdrantRest => {
+                visitor.visit(QdrantRest::new(&node_ids(1), balancing, index, distance)?)
+            }
+            Provider::Vespa => visitor.visit(Vespa::new(&node_ids(0), balancing, index, distance)?),
+            Provider::Elasticsearch => visitor.visit(Elasticsearch::new(
+                &node_ids(0),
+                balancing,
+                index,
+                distance,
+                parameters.element_type,
+            )?),
+            Provider::Pgvector => visitor.visit(Pgvector::new(
+                &node_ids(1),
+                balancing,
+                parameters,
+                distance,
+            )?),
+            Provider::Milvus => {
+                visitor.visit(Milvus::new(&node_ids(1), balancing, index, distance)?)
+            }
+            Provider::Weaviate => {
+                visitor.visit(Weaviate::new(&node_ids(1), balancing, &index, distance)?)
+            }
+        })
+    }
+}
+
+/// The ids of the nodes of the docker compose setup, the first one being `first`.
+fn node_ids(first: u16) -> Vec<u16> {
+    (first..first + CLUSTER_NODES).collect()
+}
+
+/// Checks that the node id fits the single digit port pattern of the docker compose setups.
+pub(crate) fn check_node_id(node_id: u16, first: u16) -> Result<(), Error> {
+    if node_id < first || node_id > 9 {
+        bail!("port pattern only supports node ids [{first};9]");
+    }
+    Ok(())
+}
+
+/// The urls of nodes listening on `base_port + node_id`, together with their names.
+pub(crate) fn http_nodes(
+    node_ids: &[u16],
+    first: u16,
+    base_port: u16,
+) -> Result<Vec<(String, Url)>, Error> {
+    node_ids
+        .iter()
+        .map(|&node_id| {
+            check_node_id(node_id, first)?;
+            let url = format!("http://localhost:{}/", base_port + node_id);
+            Ok((url.clone(), url.parse()?))
+        })
+        .collect()
+}
+
+/// Polls `is_ready` once per second until it returns `true` `confirmations` times in a row.
+///
+/// Fails if that doesn't happen within `timeout`, `action` names what was waited for.
+pub(crate) async fn poll_until<F, Fut>(
+    action: &str,
+    timeout: Duration,
+    confirmations: usize,
+    mut is_ready: F,
+) -> Result<(), Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool, Error>>,
+{
+    let start = Instant::now();
+    let mut ready_count = 0;
+    while start.elapsed() < timeout {
+        if is_ready().await? {
+            ready_count += 1;
+            if ready_count >= confirmations {
+                return Ok(());
+            }
+        } else {
+            ready_count = 0;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+    bail!(
+        "failed to {action} even after {:.2}s",
+        start.elapsed().as_secs_f32()
+    )
+}
+
+/// Runs some code generic over the database of a [`Provider`].
+pub trait ProviderVisitor {
+    type Output;
+
+    fn visit<DB>(self, database: DB) -> Self::Output
+    where
+        DB: PrepareVectorDatabase + QueryVectorDatabase;
+}
+
+/// Requests shared by the query building tests of the databases.
+#[cfg(test)]
+pub(crate) mod test_fixtures {
+    use chrono::{TimeZone, Utc};
+
+    use crate::{
+        benchmarks::{PayloadSelection, QueryRequest},
+        distribution::{DateFilter, Label, LabelFilter, Labels, QueryPayload},
+    };
+
+    /// A filter using every kind of condition, the date bounds are the timestamps `3661` and `3662`.
+    pub(crate) fn filter() -> QueryPayload {
+        QueryPayload {
+            publication_date: DateFilter {
+                lower_bound: Some(Utc.with_ymd_and_hms(1970, 1, 1, 1, 1, 1).unwrap()),
+                upper_bound: Some(Utc.with_ymd_and_hms(1970, 1, 1, 1, 1, 2).unwrap()),
+            },
+            authors: LabelFilter {
+                include: Labels(vec![Label(12)]),
+                exclude: Labels::default(),
+            },
+            tags: LabelFilter {
+                include: Labels::default(),
+                exclude: Labels(vec![Label(3), Label(4)]),
+            },
+        }
+    }
+
+    /// A request for the `k = 10` nearest neighbors of `[2, 4]` with `ef = 20`.
+    pub(crate) fn request() -> QueryRequest<'static> {
+        QueryRequest::new(&[2., 4.], 10, 20)
+    }
+
+    /// Like [`request`] but with the filter and all payload fields.
+    pub(crate) fn filtered_request(filter: &QueryPayload) -> QueryRequest<'_> {
+        QueryRequest {
+            filter: Some(filter),
+            payload: PayloadSelection::All,
+            ..request()
+        }
+    }
 }
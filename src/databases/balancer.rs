@@ -0,0 +1,207 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Client side load balancing of requests across the nodes of a cluster.
+
+use std::{
+    collections::BTreeMap,
+    ops::Deref,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{bail, Error};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// How the node of a request is selected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Balancing {
+    /// All requests are sent to the first node which coordinates them.
+    Single,
+    /// The nodes take turns.
+    #[default]
+    RoundRobin,
+    /// The node with the fewest in-flight requests, ties are broken round-robin.
+    LeastLoaded,
+}
+
+/// The number of requests sent to each node by node endpoint.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeRequests(BTreeMap<String, usize>);
+
+impl NodeRequests {
+    pub fn iter(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.0
+            .iter()
+            .map(|(node, &requests)| (node.as_str(), requests))
+    }
+
+    /// The requests sent since `start` was taken.
+    pub fn since(&self, start: &NodeRequests) -> NodeRequests {
+        NodeRequests(
+            self.0
+                .iter()
+                .map(|(node, &requests)| {
+                    let before = start.0.get(node).copied().unwrap_or_default();
+                    (node.clone(), requests.saturating_sub(before))
+                })
+                .collect(),
+        )
+    }
+}
+
+struct Node<T> {
+    name: String,
+    endpoint: T,
+    in_flight: AtomicUsize,
+    requests: AtomicUsize,
+}
+
+pub(crate) struct Balancer<T> {
+    nodes: Vec<Node<T>>,
+    balancing: Balancing,
+    next: AtomicUsize,
+}
+
+impl<T> Balancer<T> {
+    /// Creates a balancer over the named endpoints of the nodes.
+    pub(crate) fn new(
+        nodes: impl IntoIterator<Item = (String, T)>,
+        balancing: Balancing,
+    ) -> Result<Self, Error> {
+        let nodes = nodes
+            .into_iter()
+            .map(|(name, endpoint)| Node {
+                name,
+                endpoint,
+                in_flight: AtomicUsize::new(0),
+                requests: AtomicUsize::new(0),
+            })
+            .collect::<Vec<_>>();
+        if nodes.is_empty() {
+            bail!("at least one node is needed");
+        }
+        Ok(Self {
+            nodes,
+            balancing,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Selects the node for a request, the request counts as in-flight until the lease is dropped.
+    pub(crate) fn pick(&self) -> Lease<'_, T> {
+        let idx = match self.balancing {
+            Balancing::Single => 0,
+            Balancing::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.nodes.len(),
+            Balancing::LeastLoaded => {
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..self.nodes.len())
+                    .map(|idx| (idx + offset) % self.nodes.len())
+                    .min_by_key(|&idx| self.nodes[idx].in_flight.load(Ordering::Relaxed))
+                    .unwrap_or_default()
+            }
+        };
+        self.lease(idx)
+    }
+
+    /// Selects the first node for requests only it can serve, e.g. writes to a primary.
+    pub(crate) fn pick_first(&self) -> Lease<'_, T> {
+        self.lease(0)
+    }
+
+    fn lease(&self, idx: usize) -> Lease<'_, T> {
+        let node = &self.nodes[idx];
+        node.in_flight.fetch_add(1, Ordering::Relaxed);
+        node.requests.fetch_add(1, Ordering::Relaxed);
+        Lease { node }
+    }
+
+    /// The endpoints of all nodes, e.g. to check that a setting reached every node.
+    ///
+    /// Requests to them aren't counted.
+    pub(crate) fn endpoints(&self) -> impl Iterator<Item = &T> {
+        self.nodes.iter().map(|node| &node.endpoint)
+    }
+
+    pub(crate) fn node_requests(&self) -> NodeRequests {
+        NodeRequests(
+            self.nodes
+                .iter()
+                .map(|node| (node.name.clone(), node.requests.load(Ordering::Relaxed)))
+                .collect(),
+        )
+    }
+}
+
+/// An in-flight request to a node, derefs to the endpoint of the node.
+pub(crate) struct Lease<'a, T> {
+    node: &'a Node<T>,
+}
+
+impl<T> Deref for Lease<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.node.endpoint
+    }
+}
+
+impl<T> Drop for Lease<'_, T> {
+    fn drop(&mut self) {
+        self.node.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(balancing: Balancing) -> Balancer<usize> {
+        Balancer::new((0..3).map(|idx| (format!("node-{idx}"), idx)), balancing).unwrap()
+    }
+
+    #[test]
+    fn test_round_robin_takes_turns() {
+        let balancer = balancer(Balancing::RoundRobin);
+        let picked = (0..7).map(|_| *balancer.pick()).collect::<Vec<_>>();
+        assert_eq!(picked, [0, 1, 2, 0, 1, 2, 0]);
+
+        let start = balancer.node_requests();
+        balancer.pick();
+        assert_eq!(
+            balancer.node_requests().since(&start),
+            NodeRequests(BTreeMap::from([
+                ("node-0".into(), 0),
+                ("node-1".into(), 1),
+                ("node-2".into(), 0),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_least_loaded_avoids_busy_nodes() {
+        let balancer = balancer(Balancing::LeastLoaded);
+        let first = balancer.pick();
+        let second = balancer.pick();
+        assert_ne!(*first, *second);
+        let third = balancer.pick();
+        drop(second);
+        let idle = *balancer.pick();
+        assert!(idle != *first && idle != *third);
+
+        let balancer = self::balancer(Balancing::Single);
+        let _lease = balancer.pick();
+        assert_eq!(*balancer.pick(), 0);
+    }
+}
@@ -12,9 +12,9 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
-use anyhow::Error;
+use anyhow::{bail, Error};
 use async_trait::async_trait;
 use derive_more::{Deref, DerefMut};
 use reqwest::{Client, Method, Response, StatusCode};
@@ -24,33 +24,58 @@ use url::Url;
 use uuid::Uuid;
 
 use crate::{
-    benchmarks::QueryVectorDatabase,
+    benchmarks::{
+        byte_vector, Consistency, ElementType, IndexType, IngestionParameters, PayloadSelection,
+        Quantization, QueryHit, QueryRequest, QueryResponse, QueryVectorDatabase, BYTE_SCALE,
+    },
     distribution::{
-        ingestion::{IngestionInfo, PrepareVectorDatabase},
-        DateFilter, LabelFilter, Labels, QueryPayload,
+        ingestion::{IngestionInfo, PayloadUpdate, PrepareVectorDatabase},
+        DateFilter, DocumentPayload, LabelFilter, Labels, StoredDocumentPayload,
     },
+    math::Distance,
     utils::{await_and_check_request, body_to_error},
 };
 
-use super::DatabaseName;
+use super::{
+    balancer::{Balancer, Balancing, NodeRequests},
+    http_nodes, DatabaseName,
+};
 
 pub struct Elasticsearch {
     client: Client,
-    base_url: Url,
+    nodes: Balancer<Url>,
     index: String,
+    distance: Distance,
+    element_type: ElementType,
 }
 
 impl Elasticsearch {
-    pub fn new() -> Result<Elasticsearch, Error> {
+    pub fn new(
+        node_ids: &[u16],
+        balancing: Balancing,
+        index: impl Into<String>,
+        distance: Distance,
+        element_type: ElementType,
+    ) -> Result<Elasticsearch, Error> {
+        let nodes = http_nodes(node_ids, 0, 9200)?;
         Ok(Self {
             client: Client::new(),
-            base_url: "http://localhost:9200/".parse()?,
-            index: "content".into(),
+            nodes: Balancer::new(nodes, balancing)?,
+            index: index.into(),
+            distance,
+            element_type,
+        })
+    }
+
+    fn elastic_vector<'a>(&self, vector: &'a [f32]) -> Result<ElasticVector<'a>, Error> {
+        Ok(match self.element_type {
+            ElementType::Float => ElasticVector::Float(vector),
+            ElementType::Byte => ElasticVector::Byte(byte_vector(vector)?),
         })
     }
 
-    fn make_url(&self, segments: impl IntoIterator<Item = impl AsRef<str>>) -> Url {
-        let mut url = self.base_url.clone();
+    fn make_url(&self, node: &Url, segments: impl IntoIterator<Item = impl AsRef<str>>) -> Url {
+        let mut url = node.clone();
         url.path_segments_mut()
             .unwrap()
             .extend([&self.index])
@@ -64,28 +89,75 @@ impl Elasticsearch {
         segments: impl IntoIterator<Item = impl AsRef<str>>,
         data: &impl Serialize,
     ) -> Result<Response, Error> {
+        let node = self.nodes.pick();
         let fut = self
             .client
-            .request(method, self.make_url(segments))
+            .request(method, self.make_url(&node, segments))
             .json(data)
             .send();
 
         await_and_check_request(fut).await
     }
+
+    async fn bulk_request(&self, body: Vec<u8>) -> Result<(), Error> {
+        let node = self.nodes.pick();
+        let fut = self
+            .client
+            .post(self.make_url(&node, ["_bulk"]))
+            .header("Content-Type", "application/x-ndjson")
+            .body(body)
+            .send();
+
+        let result = await_and_check_request(fut)
+            .await?
+            .json::<BulkResult>()
+            .await?;
+        // a bulk request succeeds even if some of its items fail
+        if result.errors {
+            let mut errors = result
+                .items
+                .into_iter()
+                .flat_map(|item| item.into_values())
+                .filter_map(|item| item.error);
+            let first = errors.next().unwrap_or_default();
+            bail!(
+                "{} items of the bulk request failed, first error: {first}",
+                errors.count() + 1,
+            );
+        }
+
+        Ok(())
+    }
 }
 
 impl DatabaseName for Elasticsearch {
     fn name(&self) -> &str {
         "elasticsearch"
     }
+
+    fn node_requests(&self) -> NodeRequests {
+        self.nodes.node_requests()
+    }
 }
 
 #[async_trait(?Send)]
 impl PrepareVectorDatabase for Elasticsearch {
-    async fn initialize(&self) -> Result<bool, Error> {
+    async fn initialize(
+        &self,
+        parameters: &IngestionParameters,
+        dimensions: usize,
+    ) -> Result<bool, Error> {
+        let index_options = elastic_index_options(parameters)?;
+        if parameters.element_type == ElementType::Byte && self.distance == Distance::DotProduct {
+            // the score would depend on the dimensions
+            bail!(
+                "elasticsearch byte vectors are only supported with euclidean and cosine distance"
+            );
+        }
+        let node = self.nodes.pick();
         let response = self
             .client
-            .get(self.make_url([] as [&str; 0]))
+            .get(self.make_url(&node, [] as [&str; 0]))
             .send()
             .await?;
 
@@ -108,15 +180,11 @@ impl PrepareVectorDatabase for Elasticsearch {
                         "properties": {
                             "embedding": {
                                 "type": "dense_vector",
-                                "dims": 960,
+                                "dims": dimensions,
                                 "index": true,
-                                "element_type": "float",
-                                "similarity": "l2_norm",
-                                "index_options": {
-                                    "type": "hnsw",
-                                    "m": 16,
-                                    "ef_construction": 100,
-                                }
+                                "element_type": elastic_element_type(parameters.element_type),
+                                "similarity": elastic_similarity(self.distance),
+                                "index_options": index_options,
                             },
                             "publication_date": {
                                 // for simplicity timestamp instead of proper date
@@ -148,7 +216,8 @@ impl PrepareVectorDatabase for Elasticsearch {
     }
 
     async fn finish_mass_ingestion(&self, target_max_time: Duration) -> Result<(), Error> {
-        let mut url = self.base_url.clone();
+        let node = self.nodes.pick();
+        let mut url = Url::clone(&node);
         url.path_segments_mut()
             .unwrap()
             .extend(["_cluster", "health"]);
@@ -181,7 +250,7 @@ impl PrepareVectorDatabase for Elasticsearch {
             serde_json::to_writer(
                 &mut body,
                 &json!({
-                    "embedding": &vector,
+                    "embedding": self.elastic_vector(vector)?,
                     "publication_date": payload.publication_date.timestamp(),
                     "authors": payload.authors.to_uuid_string_vec(),
                     "tags": payload.tags.to_uuid_string_vec(),
@@ -191,50 +260,198 @@ impl PrepareVectorDatabase for Elasticsearch {
             body.push(b'\n');
         }
 
-        let fut = self
-            .client
-            .post(self.make_url(["_bulk"]))
-            .header("Content-Type", "application/x-ndjson")
-            .body(body)
-            .send();
+        self.bulk_request(body).await
+    }
 
-        await_and_check_request(fut).await?;
+    async fn update_payloads(
+        &self,
+        batch: impl IntoIterator<Item = PayloadUpdate<'_>>,
+    ) -> Result<(), Error> {
+        let mut body = Vec::new();
 
-        Ok(())
+        for PayloadUpdate { id, payload } in batch {
+            serde_json::to_writer(
+                &mut body,
+                &json!({
+                    "update": { "_id": id.to_string() },
+                }),
+            )?;
+            body.push(b'\n');
+            serde_json::to_writer(
+                &mut body,
+                &json!({
+                    "doc": {
+                        "publication_date": payload.publication_date.timestamp(),
+                        "authors": payload.authors.to_uuid_string_vec(),
+                        "tags": payload.tags.to_uuid_string_vec(),
+                        "link": &payload.link,
+                    }
+                }),
+            )?;
+            body.push(b'\n');
+        }
+
+        self.bulk_request(body).await
+    }
+
+    async fn delete_batch(&self, ids: &[Uuid]) -> Result<(), Error> {
+        let mut body = Vec::new();
+
+        for id in ids {
+            serde_json::to_writer(
+                &mut body,
+                &json!({
+                    "delete": { "_id": id.to_string() },
+                }),
+            )?;
+            body.push(b'\n');
+        }
+
+        self.bulk_request(body).await
     }
 }
 
 #[async_trait]
 impl QueryVectorDatabase for Elasticsearch {
-    async fn query(
-        &self,
-        k: usize,
-        ef: usize,
-        vector: &[f32],
-        payload: &QueryPayload,
-        return_payload: bool,
-        use_filters: bool,
-    ) -> Result<Vec<Uuid>, Error> {
-        let mut results = self
-            .json_request(
-                Method::POST,
-                ["_search"],
-                &ElasticQuery::new(k, ef, vector, payload, return_payload, use_filters),
-            )
+    async fn query(&self, request: &QueryRequest<'_>) -> Result<QueryResponse, Error> {
+        let node = self.nodes.pick();
+        let mut url = self.make_url(&node, ["_search"]);
+        if let Some(preference) = elastic_preference(request.consistency)? {
+            url.query_pairs_mut().append_pair("preference", preference);
+        }
+        let fut = self
+            .client
+            .post(url)
+            .json(&ElasticQuery::new(
+                request,
+                self.elastic_vector(request.vector)?,
+            ))
+            .send();
+        let result = await_and_check_request(fut)
             .await?
             .json::<SearchResult>()
-            .await?
-            .hits
-            .hits;
+            .await?;
+
+        let mut results = result.hits.hits;
+        // higher scores are better
+        results.sort_by(|l, r| r.score.total_cmp(&l.score));
+
+        let hits = results
+            .into_iter()
+            .map(|hit| {
+                Ok(QueryHit {
+                    id: hit.id,
+                    score: hit.score,
+                    payload: hit.source.map(DocumentPayload::try_from).transpose()?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(QueryResponse {
+            hits,
+            took: Some(Duration::from_millis(result.took)),
+        })
+    }
+
+    fn score_to_distance(&self, score: f32) -> f32 {
+        let scale = match self.element_type {
+            ElementType::Float => 1.,
+            ElementType::Byte => BYTE_SCALE,
+        };
+        match self.distance {
+            // `l2_norm` score is `1 / (1 + l2_norm(a, b)^2)`
+            Distance::Euclidean => (1. / score - 1.).max(0.).sqrt() / scale,
+            // `cosine` and `dot_product` scores are `(1 + similarity) / 2`
+            Distance::Cosine => 2. - 2. * score,
+            Distance::DotProduct => 1. - 2. * score,
+        }
+    }
+}
+
+/// Maps the consistency to the `preference` of a search.
+///
+/// Elasticsearch always reads a single copy of each shard, the preference only selects which.
+fn elastic_preference(consistency: Consistency) -> Result<Option<&'static str>, Error> {
+    match consistency {
+        // adaptive replica selection
+        Consistency::Quorum => Ok(None),
+        Consistency::One => Ok(Some("_local")),
+        Consistency::All | Consistency::Majority => {
+            bail!("elasticsearch reads a single copy of each shard, got: {consistency:?}")
+        }
+    }
+}
+
+fn elastic_element_type(element_type: ElementType) -> &'static str {
+    match element_type {
+        ElementType::Float => "float",
+        ElementType::Byte => "byte",
+    }
+}
 
-        results.sort_by(|l, r| l.score.total_cmp(&r.score));
+/// The `index_options` of the vector field, quantization is mapped to the `int8_*` index types.
+pub(super) fn elastic_index_options(parameters: &IngestionParameters) -> Result<Value, Error> {
+    let IngestionParameters {
+        m,
+        ef_construct,
+        index_type,
+        element_type,
+        quantization,
+    } = *parameters;
+    let confidence_interval = match quantization {
+        Quantization::None => None,
+        Quantization::Scalar {
+            quantile,
+            always_ram: false,
+        } if element_type == ElementType::Float => Some(quantile),
+        _ => bail!("elasticsearch doesn't support {parameters}"),
+    };
+    let mut options = match (index_type, confidence_interval) {
+        (IndexType::Hnsw, None) => json!({
+            "type": "hnsw",
+            "m": m,
+            "ef_construction": ef_construct,
+        }),
+        (IndexType::Hnsw, Some(_)) => json!({
+            "type": "int8_hnsw",
+            "m": m,
+            "ef_construction": ef_construct,
+        }),
+        (IndexType::Flat, None) => json!({ "type": "flat" }),
+        (IndexType::Flat, Some(_)) => json!({ "type": "int8_flat" }),
+    };
+    if let Some(Some(confidence_interval)) = confidence_interval {
+        options["confidence_interval"] = confidence_interval.into();
+    }
+    Ok(options)
+}
 
-        Ok(results.into_iter().map(|hit| hit.id).collect())
+fn elastic_similarity(distance: Distance) -> &'static str {
+    match distance {
+        Distance::Euclidean => "l2_norm",
+        Distance::Cosine => "cosine",
+        // Hint: requires unit length vectors, `max_inner_product` is only available from 8.11 on
+        Distance::DotProduct => "dot_product",
     }
 }
 
+#[derive(Deserialize)]
+struct BulkResult {
+    errors: bool,
+    /// The result of each item keyed by its action.
+    items: Vec<HashMap<String, BulkItem>>,
+}
+
+#[derive(Deserialize)]
+struct BulkItem {
+    #[serde(default)]
+    error: Option<Value>,
+}
+
 #[derive(Deserialize)]
 struct SearchResult {
+    /// Milliseconds
+    took: u64,
     hits: Hits,
 }
 
@@ -249,6 +466,15 @@ struct Hit {
     id: Uuid,
     #[serde(rename = "_score")]
     score: f32,
+    #[serde(rename = "_source", default)]
+    source: Option<StoredDocumentPayload>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ElasticVector<'a> {
+    Float(&'a [f32]),
+    Byte(Vec<i8>),
 }
 
 #[derive(Serialize)]
@@ -256,35 +482,31 @@ struct ElasticQuery<'a> {
     knn: KnnQuery<'a>,
     #[serde(rename = "_source")]
     return_payload: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<String>,
 }
 
 impl<'a> ElasticQuery<'a> {
-    fn new(
-        k: usize,
-        ef: usize,
-        vector: &'a [f32],
-        payload: &QueryPayload,
-        return_payload: bool,
-        use_filters: bool,
-    ) -> Self {
+    fn new(request: &QueryRequest<'a>, query_vector: ElasticVector<'a>) -> Self {
         Self {
             knn: KnnQuery {
                 field: "embedding",
-                query_vector: vector,
-                k,
+                query_vector,
+                k: request.k,
                 //WARNING: This isn't exactly the same as `ef`, but the closest thing to `ef` we get.
-                num_candidates: ef,
-                filter: use_filters
-                    .then(|| {
-                        BoolQuery::default()
-                            .with_date_range_filter("publication_date", &payload.publication_date)
-                            .with_label_filter("authors", &payload.authors)
-                            .with_label_filter("tags", &payload.tags)
-                            .into_option()
-                    })
-                    .flatten(),
+                num_candidates: request.ef,
+                filter: request.filter.and_then(|payload| {
+                    BoolQuery::default()
+                        .with_date_range_filter("publication_date", &payload.publication_date)
+                        .with_label_filter("authors", &payload.authors)
+                        .with_label_filter("tags", &payload.tags)
+                        .into_option()
+                }),
             },
-            return_payload,
+            return_payload: request.payload == PayloadSelection::All,
+            timeout: request
+                .timeout
+                .map(|timeout| format!("{}ms", timeout.as_millis())),
         }
     }
 }
@@ -292,7 +514,7 @@ impl<'a> ElasticQuery<'a> {
 #[derive(Serialize)]
 struct KnnQuery<'a> {
     field: &'a str,
-    query_vector: &'a [f32],
+    query_vector: ElasticVector<'a>,
     k: usize,
     num_candidates: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -352,3 +574,49 @@ fn elastic_match_keyword_constraints(field: &str, keywords: &Labels, out: &mut V
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_options() {
+        let options = |index_type, element_type, quantization| {
+            elastic_index_options(&IngestionParameters {
+                m: 16,
+                ef_construct: 100,
+                index_type,
+                element_type,
+                quantization,
+            })
+        };
+        let int8 = Quantization::Scalar {
+            quantile: Some(0.95),
+            always_ram: false,
+        };
+
+        assert_eq!(
+            options(IndexType::Hnsw, ElementType::Byte, Quantization::None).unwrap(),
+            json!({ "type": "hnsw", "m": 16, "ef_construction": 100 })
+        );
+        assert_eq!(
+            options(IndexType::Hnsw, ElementType::Float, int8).unwrap(),
+            json!({ "type": "int8_hnsw", "m": 16, "ef_construction": 100, "confidence_interval": 0.95f32 })
+        );
+        assert_eq!(
+            options(IndexType::Flat, ElementType::Float, Quantization::None).unwrap(),
+            json!({ "type": "flat" })
+        );
+        assert_eq!(
+            options(IndexType::Flat, ElementType::Float, int8).unwrap(),
+            json!({ "type": "int8_flat", "confidence_interval": 0.95f32 })
+        );
+        assert!(options(IndexType::Hnsw, ElementType::Byte, int8).is_err());
+        assert!(options(
+            IndexType::Hnsw,
+            ElementType::Float,
+            Quantization::Binary { always_ram: false }
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,558 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Milvus over its (v2) JSON REST API.
+//!
+//! Every node runs a proxy, so requests can be sent to any of them.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Error};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    benchmarks::{
+        Consistency, ElementType, IndexType, IngestionParameters, PayloadSelection, Quantization,
+        QueryHit, QueryRequest, QueryResponse, QueryVectorDatabase,
+    },
+    consts::MAX_LABELS_PER_DOCUMENT,
+    databases::{
+        balancer::{Balancer, Balancing, NodeRequests},
+        http_nodes, poll_until, DatabaseName,
+    },
+    distribution::{
+        ingestion::{IngestionInfo, PayloadUpdate, PrepareVectorDatabase},
+        DateFilter, DocumentPayload, LabelFilter, Labels, QueryPayload, StoredDocumentPayload,
+    },
+    math::Distance,
+    utils::await_and_check_request,
+};
+
+/// The name of the vector index, the scalar indices are named after their field.
+const VECTOR_INDEX: &str = "embedding";
+const PAYLOAD_FIELDS: [&str; 4] = ["publication_date", "authors", "tags", "link"];
+
+pub struct Milvus {
+    client: Client,
+    nodes: Balancer<Url>,
+    collection: String,
+    distance: Distance,
+}
+
+impl Milvus {
+    pub fn new(
+        node_ids: &[u16],
+        balancing: Balancing,
+        collection: impl Into<String>,
+        distance: Distance,
+    ) -> Result<Self, Error> {
+        let nodes = http_nodes(node_ids, 1, 19530)?;
+
+        Ok(Milvus {
+            client: Client::new(),
+            nodes: Balancer::new(nodes, balancing)?,
+            collection: collection.into(),
+            distance,
+        })
+    }
+
+    /// Sends a request to `/v2/vectordb/{resource}/{action}` and returns the `data`.
+    async fn json_request<T>(&self, resource: &str, action: &str, data: Value) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        self.json_request_with_timeout(resource, action, data, None)
+            .await
+    }
+
+    async fn json_request_with_timeout<T>(
+        &self,
+        resource: &str,
+        action: &str,
+        mut data: Value,
+        timeout: Option<Duration>,
+    ) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        data["collectionName"] = json!(self.collection);
+        let node = self.nodes.pick();
+        let mut url = node.clone();
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .extend(["v2", "vectordb", resource, action]);
+        let mut request = self.client.post(url).json(&data);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let response = await_and_check_request(request.send()).await?;
+        parse_data(response.json().await?)
+    }
+
+    async fn has_collection(&self) -> Result<bool, Error> {
+        let HasCollection { has } = self.json_request("collections", "has", json!({})).await?;
+        Ok(has)
+    }
+
+    async fn is_index_finished(&self) -> Result<bool, Error> {
+        let indices: Vec<IndexInfo> = self
+            .json_request("indexes", "describe", json!({ "indexName": VECTOR_INDEX }))
+            .await?;
+        for index in &indices {
+            if index.index_state == "Failed" {
+                bail!("building the index failed: {}", index.fail_reason);
+            }
+        }
+        Ok(!indices.is_empty()
+            && indices
+                .iter()
+                .all(|index| index.index_state == "Finished" && index.pending_rows == 0))
+    }
+
+    async fn is_loaded(&self) -> Result<bool, Error> {
+        let LoadState { load_state } = self
+            .json_request("collections", "get_load_state", json!({}))
+            .await?;
+        Ok(load_state == "LoadStateLoaded")
+    }
+
+    async fn upsert(&self, entities: Vec<Value>) -> Result<(), Error> {
+        let _: Value = self
+            .json_request("entities", "upsert", json!({ "data": entities }))
+            .await?;
+        Ok(())
+    }
+}
+
+impl DatabaseName for Milvus {
+    fn name(&self) -> &str {
+        "milvus"
+    }
+
+    fn node_requests(&self) -> NodeRequests {
+        self.nodes.node_requests()
+    }
+}
+
+/// Milvus answers errors with `200 OK` and a non-zero `code`.
+#[derive(Deserialize)]
+struct MilvusResponse<T> {
+    code: i32,
+    #[serde(default)]
+    message: String,
+    data: Option<T>,
+}
+
+fn parse_data<T>(response: MilvusResponse<T>) -> Result<T, Error> {
+    if response.code != 0 {
+        bail!("milvus error {}: {}", response.code, response.message);
+    }
+    response
+        .data
+        .ok_or_else(|| anyhow!("milvus response without data"))
+}
+
+#[derive(Deserialize)]
+struct HasCollection {
+    has: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CollectionStats {
+    row_count: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IndexInfo {
+    index_state: String,
+    #[serde(default)]
+    pending_rows: u64,
+    #[serde(default)]
+    fail_reason: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoadState {
+    load_state: String,
+}
+
+#[derive(Deserialize)]
+struct Entity {
+    id: Uuid,
+    embedding: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct SearchHit {
+    id: Uuid,
+    distance: f32,
+    #[serde(flatten)]
+    payload: Option<StoredDocumentPayload>,
+}
+
+#[async_trait(?Send)]
+impl PrepareVectorDatabase for Milvus {
+    async fn initialize(
+        &self,
+        parameters: &IngestionParameters,
+        dimensions: usize,
+    ) -> Result<bool, Error> {
+        if parameters.element_type != ElementType::Float
+            || parameters.quantization != Quantization::None
+        {
+            bail!("milvus doesn't support {parameters}");
+        }
+        if self.has_collection().await? {
+            let CollectionStats { row_count } = self
+                .json_request("collections", "get_stats", json!({}))
+                .await?;
+            return Ok(row_count == 0);
+        }
+
+        let label_field = |name: &str| {
+            json!({
+                "fieldName": name,
+                "dataType": "Array",
+                "elementDataType": "VarChar",
+                "elementTypeParams": {
+                    "max_capacity": MAX_LABELS_PER_DOCUMENT,
+                    "max_length": 36,
+                },
+            })
+        };
+        let vector_index = match parameters.index_type {
+            IndexType::Hnsw => json!({
+                "index_type": "HNSW",
+                "M": parameters.m,
+                "efConstruction": parameters.ef_construct,
+            }),
+            IndexType::Flat => json!({ "index_type": "FLAT" }),
+        };
+        let _: Value = self
+            .json_request(
+                "collections",
+                "create",
+                json!({
+                    "schema": {
+                        "autoId": false,
+                        "enableDynamicField": false,
+                        "fields": [
+                            {
+                                "fieldName": "id",
+                                "dataType": "VarChar",
+                                "isPrimary": true,
+                                "elementTypeParams": { "max_length": 36 },
+                            },
+                            {
+                                "fieldName": "embedding",
+                                "dataType": "FloatVector",
+                                "elementTypeParams": { "dim": dimensions },
+                            },
+                            { "fieldName": "publication_date", "dataType": "Int64" },
+                            label_field("authors"),
+                            label_field("tags"),
+                            {
+                                "fieldName": "link",
+                                "dataType": "VarChar",
+                                "elementTypeParams": { "max_length": 256 },
+                            },
+                        ],
+                    },
+                    // creating the indices with the collection also loads it
+                    "indexParams": [
+                        {
+                            "fieldName": "embedding",
+                            "indexName": VECTOR_INDEX,
+                            "metricType": milvus_metric(self.distance),
+                            "params": vector_index,
+                        },
+                        {
+                            "fieldName": "publication_date",
+                            "indexName": "publication_date",
+                            "params": { "index_type": "STL_SORT" },
+                        },
+                        {
+                            "fieldName": "authors",
+                            "indexName": "authors",
+                            "params": { "index_type": "INVERTED" },
+                        },
+                        {
+                            "fieldName": "tags",
+                            "indexName": "tags",
+                            "params": { "index_type": "INVERTED" },
+                        },
+                    ],
+                    // similar to the other providers 3 shards, the 2 replicas are
+                    // configured cluster wide in `./docker/milvus/config/user.yaml`
+                    "params": { "shardsNum": 3 },
+                }),
+            )
+            .await?;
+
+        Ok(true)
+    }
+
+    async fn prepare_mass_ingestion(&self) -> Result<(), Error> {
+        // segments are indexed once they are sealed, there is nothing to disable
+        Ok(())
+    }
+
+    async fn finish_mass_ingestion(&self, target_max_time: Duration) -> Result<(), Error> {
+        let start = Instant::now();
+        // seal all growing segments so that they get indexed
+        let _: Value = self.json_request("collections", "flush", json!({})).await?;
+        let remaining = target_max_time.saturating_sub(start.elapsed());
+        poll_until("finish mass ingestion", remaining, 1, || async {
+            Ok(self.is_index_finished().await? && self.is_loaded().await?)
+        })
+        .await
+    }
+
+    async fn ingest_batch(
+        &self,
+        batch: impl IntoIterator<Item = IngestionInfo<'_>>,
+    ) -> Result<(), Error> {
+        let entities = batch
+            .into_iter()
+            .map(|info| milvus_entity(info.id, info.vector, info.payload))
+            .collect();
+        self.upsert(entities).await
+    }
+
+    async fn update_payloads(
+        &self,
+        batch: impl IntoIterator<Item = PayloadUpdate<'_>>,
+    ) -> Result<(), Error> {
+        // milvus can't update single fields, so the vectors are fetched and upserted again
+        let batch = batch.into_iter().collect::<Vec<_>>();
+        let ids = batch
+            .iter()
+            .map(|update| update.id.to_string())
+            .collect::<Vec<_>>();
+        let entities: Vec<Entity> = self
+            .json_request(
+                "entities",
+                "get",
+                json!({ "id": ids, "outputFields": ["embedding"] }),
+            )
+            .await?;
+        let entities = batch
+            .iter()
+            .map(|update| {
+                let entity = entities
+                    .iter()
+                    .find(|entity| entity.id == update.id)
+                    .ok_or_else(|| anyhow!("document {} doesn't exist", update.id))?;
+                Ok(milvus_entity(update.id, &entity.embedding, update.payload))
+            })
+            .collect::<Result<_, Error>>()?;
+        self.upsert(entities).await
+    }
+
+    async fn delete_batch(&self, ids: &[Uuid]) -> Result<(), Error> {
+        let _: Value = self
+            .json_request(
+                "entities",
+                "delete",
+                json!({ "filter": format!("id in {}", milvus_string_list(ids)) }),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl QueryVectorDatabase for Milvus {
+    async fn query(&self, request: &QueryRequest<'_>) -> Result<QueryResponse, Error> {
+        if request.consistency != Consistency::default() {
+            // milvus consistency levels are about the staleness of the data, not about replicas
+            bail!(
+                "milvus doesn't support consistency {:?}",
+                request.consistency
+            );
+        }
+        let return_payload = request.payload == PayloadSelection::All;
+        let hits: Vec<SearchHit> = self
+            .json_request_with_timeout(
+                "entities",
+                "search",
+                milvus_search(self.distance, request),
+                request.timeout,
+            )
+            .await?;
+
+        let hits = hits
+            .into_iter()
+            .map(|hit| {
+                Ok(QueryHit {
+                    id: hit.id,
+                    score: hit.distance,
+                    payload: hit
+                        .payload
+                        .filter(|_| return_payload)
+                        .map(DocumentPayload::try_from)
+                        .transpose()?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(QueryResponse { hits, took: None })
+    }
+
+    fn score_to_distance(&self, score: f32) -> f32 {
+        match self.distance {
+            // `L2` is the squared euclidean distance
+            Distance::Euclidean => score.max(0.).sqrt(),
+            // `COSINE` and `IP` are the similarity
+            Distance::Cosine => 1. - score,
+            Distance::DotProduct => -score,
+        }
+    }
+}
+
+fn milvus_metric(distance: Distance) -> &'static str {
+    match distance {
+        Distance::Euclidean => "L2",
+        Distance::Cosine => "COSINE",
+        Distance::DotProduct => "IP",
+    }
+}
+
+fn milvus_entity(id: Uuid, vector: &[f32], payload: &DocumentPayload) -> Value {
+    json!({
+        "id": id,
+        "embedding": vector,
+        "publication_date": payload.publication_date.timestamp(),
+        "authors": payload.authors.to_uuid_string_vec(),
+        "tags": payload.tags.to_uuid_string_vec(),
+        "link": &payload.link,
+    })
+}
+
+fn milvus_search(distance: Distance, request: &QueryRequest<'_>) -> Value {
+    let mut search = json!({
+        "data": [request.vector],
+        "annsField": "embedding",
+        "limit": request.k,
+        "searchParams": {
+            "metricType": milvus_metric(distance),
+            "params": { "ef": request.ef },
+        },
+        "outputFields": match request.payload {
+            PayloadSelection::None => &[] as &[&str],
+            PayloadSelection::All => &PAYLOAD_FIELDS,
+        },
+    });
+    if let Some(filter) = request.filter.and_then(milvus_filter) {
+        search["filter"] = json!(filter);
+    }
+    search
+}
+
+fn milvus_filter(
+    QueryPayload {
+        publication_date,
+        authors,
+        tags,
+    }: &QueryPayload,
+) -> Option<String> {
+    let mut conditions = Vec::new();
+    milvus_date_filter("publication_date", publication_date, &mut conditions);
+    milvus_label_filter("authors", authors, &mut conditions);
+    milvus_label_filter("tags", tags, &mut conditions);
+    (!conditions.is_empty()).then(|| conditions.join(" and "))
+}
+
+fn milvus_date_filter(
+    field: &str,
+    DateFilter {
+        lower_bound,
+        upper_bound,
+    }: &DateFilter,
+    conditions: &mut Vec<String>,
+) {
+    if let Some(bound) = lower_bound {
+        conditions.push(format!("{field} >= {}", bound.timestamp()));
+    }
+    if let Some(bound) = upper_bound {
+        conditions.push(format!("{field} <= {}", bound.timestamp()));
+    }
+}
+
+fn milvus_label_filter(
+    field: &str,
+    LabelFilter { include, exclude }: &LabelFilter,
+    conditions: &mut Vec<String>,
+) {
+    let contains_any = |labels: &Labels| {
+        format!(
+            "array_contains_any({field}, {})",
+            milvus_string_list(labels.to_uuid_string_vec())
+        )
+    };
+    if !include.is_empty() {
+        conditions.push(contains_any(include));
+    }
+    if !exclude.is_empty() {
+        conditions.push(format!("not {}", contains_any(exclude)));
+    }
+}
+
+/// Formats a list of uuids/uuid strings as a boolean expression string list.
+fn milvus_string_list<T: Serialize>(values: impl IntoIterator<Item = T>) -> String {
+    // uuids need no escaping, and json strings are valid expression strings
+    json!(values.into_iter().collect::<Vec<_>>()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::databases::test_fixtures::{filter, filtered_request, request};
+
+    use super::*;
+
+    #[test]
+    fn test_search_building() {
+        let filter = filter();
+        let search = milvus_search(Distance::Cosine, &filtered_request(&filter));
+        assert_eq!(
+            search,
+            json!({
+                "data": [[2., 4.]],
+                "annsField": "embedding",
+                "limit": 10,
+                "searchParams": { "metricType": "COSINE", "params": { "ef": 20 } },
+                "outputFields": ["publication_date", "authors", "tags", "link"],
+                "filter": concat!(
+                    "publication_date >= 3661 and publication_date <= 3662",
+                    r#" and array_contains_any(authors, ["00000000-0000-400c-8000-00000000000c"])"#,
+                    r#" and not array_contains_any(tags, ["00000000-0000-4003-8000-000000000003","00000000-0000-4004-8000-000000000004"])"#,
+                ),
+            })
+        );
+
+        let search = milvus_search(Distance::Euclidean, &request());
+        assert_eq!(search["searchParams"]["metricType"], "L2");
+        assert_eq!(search["outputFields"], json!([]));
+        assert!(search.get("filter").is_none());
+    }
+}
@@ -0,0 +1,553 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! PostgreSQL with the pgvector extension.
+//!
+//! The compose setup runs a primary with two streaming replicas, writes go to the primary
+//! and queries are balanced across all nodes.
+
+use std::{
+    pin::pin,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Error};
+use async_trait::async_trait;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
+use pgvector::Vector;
+use tokio::try_join;
+use tokio_postgres::{binary_copy::BinaryCopyInWriter, types::ToSql, Client, NoTls, Row};
+use uuid::Uuid;
+
+use crate::{
+    benchmarks::{
+        Consistency, ElementType, IndexType, IngestionParameters, PayloadSelection, Quantization,
+        QueryHit, QueryRequest, QueryResponse, QueryVectorDatabase,
+    },
+    distribution::{
+        ingestion::{IngestionInfo, PayloadUpdate, PrepareVectorDatabase},
+        DateFilter, DocumentPayload, LabelFilter, QueryPayload, StoredDocumentPayload,
+    },
+    math::Distance,
+};
+
+use super::{
+    balancer::{Balancer, Balancing, NodeRequests},
+    check_node_id, poll_until, DatabaseName,
+};
+
+/// The maximal number of connections to each node.
+const CONNECTIONS_PER_NODE: usize = 32;
+
+type SqlParams = Vec<Box<dyn ToSql + Sync + Send>>;
+
+pub struct Pgvector {
+    /// The first node is the primary.
+    nodes: Balancer<Pool>,
+    replicas: usize,
+    table: String,
+    parameters: IngestionParameters,
+    distance: Distance,
+}
+
+impl Pgvector {
+    pub fn new(
+        node_ids: &[u16],
+        balancing: Balancing,
+        parameters: &IngestionParameters,
+        distance: Distance,
+    ) -> Result<Self, Error> {
+        let nodes = node_ids
+            .iter()
+            .map(|&node_id| {
+                check_node_id(node_id, 1)?;
+                let port = 5440 + node_id;
+                let mut config = tokio_postgres::Config::new();
+                config
+                    .host("localhost")
+                    .port(port)
+                    .user("postgres")
+                    .password("postgres")
+                    .dbname("postgres");
+                let manager = Manager::from_config(
+                    config,
+                    NoTls,
+                    ManagerConfig {
+                        recycling_method: RecyclingMethod::Fast,
+                    },
+                );
+                let pool = Pool::builder(manager)
+                    .max_size(CONNECTIONS_PER_NODE)
+                    .build()?;
+                Ok((format!("localhost:{port}"), pool))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Self {
+            nodes: Balancer::new(nodes, balancing)?,
+            replicas: node_ids.len().saturating_sub(1),
+            table: parameters.index_name(),
+            parameters: *parameters,
+            distance,
+        })
+    }
+
+    fn staging_table(&self) -> String {
+        format!("{}_staging", self.table)
+    }
+
+    fn index_name(&self) -> String {
+        format!("{}_embedding", self.table)
+    }
+
+    /// Waits until all replicas replayed the WAL of the primary up to now.
+    async fn wait_for_replicas(&self, timeout: Duration) -> Result<(), Error> {
+        let node = self.nodes.pick_first();
+        let client = node.get().await?;
+        let lsn: String = client
+            .query_one("SELECT pg_current_wal_lsn()::text", &[])
+            .await?
+            .try_get(0)?;
+        poll_until("wait for the replicas to catch up", timeout, 1, || async {
+            let replayed: i64 = client
+                .query_one(
+                    "SELECT count(*) FROM pg_stat_replication WHERE replay_lsn >= $1::text::pg_lsn",
+                    &[&lsn],
+                )
+                .await?
+                .try_get(0)?;
+            Ok(replayed as usize >= self.replicas)
+        })
+        .await
+    }
+}
+
+impl DatabaseName for Pgvector {
+    fn name(&self) -> &str {
+        "pgvector"
+    }
+
+    fn node_requests(&self) -> NodeRequests {
+        self.nodes.node_requests()
+    }
+}
+
+/// Whether a table or index with given name exists.
+async fn relation_exists(client: &Client, name: &str) -> Result<bool, Error> {
+    Ok(client
+        .query_one("SELECT to_regclass(quote_ident($1)) IS NOT NULL", &[&name])
+        .await?
+        .try_get(0)?)
+}
+
+fn pg_operator(distance: Distance) -> &'static str {
+    match distance {
+        Distance::Euclidean => "<->",
+        // `1 - cosine_similarity`
+        Distance::Cosine => "<=>",
+        // the negated inner product
+        Distance::DotProduct => "<#>",
+    }
+}
+
+fn pg_operator_class(distance: Distance) -> &'static str {
+    match distance {
+        Distance::Euclidean => "vector_l2_ops",
+        Distance::Cosine => "vector_cosine_ops",
+        Distance::DotProduct => "vector_ip_ops",
+    }
+}
+
+#[async_trait(?Send)]
+impl PrepareVectorDatabase for Pgvector {
+    async fn initialize(
+        &self,
+        parameters: &IngestionParameters,
+        dimensions: usize,
+    ) -> Result<bool, Error> {
+        if parameters.element_type != ElementType::Float
+            || parameters.quantization != Quantization::None
+        {
+            bail!("pgvector doesn't support {parameters}");
+        }
+        let node = self.nodes.pick_first();
+        let client = node.get().await?;
+        client
+            .batch_execute("CREATE EXTENSION IF NOT EXISTS vector")
+            .await?;
+        if relation_exists(&client, &self.table).await? {
+            let count: i64 = client
+                .query_one(&format!("SELECT count(*) FROM \"{}\"", self.table), &[])
+                .await?
+                .try_get(0)?;
+            // the index is dropped during the ingestion, without it the ingestion didn't finish
+            let indexed = parameters.index_type == IndexType::Flat
+                || relation_exists(&client, &self.index_name()).await?;
+            return Ok(count == 0 || !indexed);
+        }
+
+        let table = &self.table;
+        client
+            .batch_execute(&format!(
+                r#"
+                BEGIN;
+                CREATE TABLE "{table}" (
+                    id uuid PRIMARY KEY,
+                    embedding vector({dimensions}) NOT NULL,
+                    publication_date bigint NOT NULL,
+                    authors text[] NOT NULL,
+                    tags text[] NOT NULL,
+                    link text NOT NULL
+                );
+                CREATE INDEX "{table}_publication_date" ON "{table}" (publication_date);
+                CREATE INDEX "{table}_authors" ON "{table}" USING gin (authors);
+                CREATE INDEX "{table}_tags" ON "{table}" USING gin (tags);
+                COMMIT;
+                "#
+            ))
+            .await?;
+
+        Ok(true)
+    }
+
+    async fn prepare_mass_ingestion(&self) -> Result<(), Error> {
+        // building the index after the bulk load is much faster
+        let node = self.nodes.pick_first();
+        node.get()
+            .await?
+            .batch_execute(&format!("DROP INDEX IF EXISTS \"{}\"", self.index_name()))
+            .await?;
+        Ok(())
+    }
+
+    async fn finish_mass_ingestion(&self, target_max_time: Duration) -> Result<(), Error> {
+        let start = Instant::now();
+        let IngestionParameters {
+            m,
+            ef_construct,
+            index_type,
+            ..
+        } = self.parameters;
+        // a flat index is a sequential scan
+        let create_index = match index_type {
+            IndexType::Hnsw => format!(
+                "CREATE INDEX IF NOT EXISTS \"{index}\" ON \"{table}\" USING hnsw (embedding {class}) WITH (m = {m}, ef_construction = {ef_construct});",
+                index = self.index_name(),
+                table = self.table,
+                class = pg_operator_class(self.distance),
+            ),
+            IndexType::Flat => String::new(),
+        };
+        let node = self.nodes.pick_first();
+        node.get()
+            .await?
+            .batch_execute(&format!(
+                "BEGIN; SET LOCAL statement_timeout = {timeout}; {create_index} ANALYZE \"{table}\"; COMMIT;",
+                timeout = target_max_time.as_millis(),
+                table = self.table,
+            ))
+            .await?;
+        drop(node);
+
+        self.wait_for_replicas(target_max_time.saturating_sub(start.elapsed()))
+            .await
+    }
+
+    async fn ingest_batch(
+        &self,
+        batch: impl IntoIterator<Item = IngestionInfo<'_>>,
+    ) -> Result<(), Error> {
+        let node = self.nodes.pick_first();
+        let mut client = node.get().await?;
+        let transaction = client.transaction().await?;
+        let table = &self.table;
+        let staging = self.staging_table();
+        // `COPY` can't upsert, so we copy into a staging table first
+        transaction
+            .batch_execute(&format!(
+                "CREATE TEMP TABLE IF NOT EXISTS \"{staging}\" (LIKE \"{table}\") ON COMMIT DELETE ROWS"
+            ))
+            .await?;
+        let types = transaction
+            .prepare(&format!(
+                "INSERT INTO \"{staging}\" (id, embedding, publication_date, authors, tags, link) VALUES ($1, $2, $3, $4, $5, $6)"
+            ))
+            .await?
+            .params()
+            .to_vec();
+        let sink = transaction
+            .copy_in(&format!(
+                "COPY \"{staging}\" (id, embedding, publication_date, authors, tags, link) FROM STDIN (FORMAT binary)"
+            ))
+            .await?;
+        let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+        for IngestionInfo {
+            id,
+            vector,
+            payload,
+        } in batch
+        {
+            writer
+                .as_mut()
+                .write(&[
+                    &id,
+                    &Vector::from(vector.to_vec()),
+                    &payload.publication_date.timestamp(),
+                    &payload.authors.to_uuid_string_vec(),
+                    &payload.tags.to_uuid_string_vec(),
+                    &payload.link,
+                ])
+                .await?;
+        }
+        writer.finish().await?;
+        transaction
+            .batch_execute(&format!(
+                r#"
+                INSERT INTO "{table}" SELECT * FROM "{staging}"
+                ON CONFLICT (id) DO UPDATE SET
+                    embedding = EXCLUDED.embedding,
+                    publication_date = EXCLUDED.publication_date,
+                    authors = EXCLUDED.authors,
+                    tags = EXCLUDED.tags,
+                    link = EXCLUDED.link
+                "#
+            ))
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn update_payloads(
+        &self,
+        batch: impl IntoIterator<Item = PayloadUpdate<'_>>,
+    ) -> Result<(), Error> {
+        let node = self.nodes.pick_first();
+        let mut client = node.get().await?;
+        let transaction = client.transaction().await?;
+        let statement = transaction
+            .prepare_cached(&format!(
+                "UPDATE \"{}\" SET publication_date = $2, authors = $3, tags = $4, link = $5 WHERE id = $1",
+                self.table
+            ))
+            .await?;
+        for PayloadUpdate { id, payload } in batch {
+            transaction
+                .execute(
+                    &statement,
+                    &[
+                        &id,
+                        &payload.publication_date.timestamp(),
+                        &payload.authors.to_uuid_string_vec(),
+                        &payload.tags.to_uuid_string_vec(),
+                        &payload.link,
+                    ],
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_batch(&self, ids: &[Uuid]) -> Result<(), Error> {
+        let node = self.nodes.pick_first();
+        node.get()
+            .await?
+            .execute(
+                &format!("DELETE FROM \"{}\" WHERE id = ANY($1)", self.table),
+                &[&ids],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl QueryVectorDatabase for Pgvector {
+    async fn query(&self, request: &QueryRequest<'_>) -> Result<QueryResponse, Error> {
+        if request.consistency != Consistency::default() {
+            // each query is answered by a single node, how far a replica lags isn't configurable
+            bail!(
+                "pgvector doesn't support consistency {:?}",
+                request.consistency
+            );
+        }
+        let (sql, params) = pg_build_query(&self.table, self.distance, request);
+        let params = params
+            .iter()
+            .map(|param| param.as_ref() as &(dyn ToSql + Sync))
+            .collect::<Vec<_>>();
+        // filtered queries continue the index scan until enough hits match the filter
+        let iterative_scan = if request.filter.is_some() {
+            "strict_order"
+        } else {
+            "off"
+        };
+        // the settings are per session, so they have to be set for each query
+        let settings = format!(
+            "SET hnsw.ef_search = {ef}; SET hnsw.iterative_scan = {iterative_scan}; SET statement_timeout = {timeout}",
+            ef = request.ef,
+            timeout = request.timeout.map_or(0, |timeout| timeout.as_millis()),
+        );
+
+        let node = self.nodes.pick();
+        let client = node.get().await?;
+        let statement = client.prepare_cached(&sql).await?;
+        // both are pipelined over the same connection
+        let ((), rows) = try_join!(
+            client.batch_execute(&settings),
+            client.query(&statement, &params)
+        )?;
+
+        let return_payload = request.payload == PayloadSelection::All;
+        let hits = rows
+            .iter()
+            .map(|row| {
+                Ok(QueryHit {
+                    id: row.try_get("id")?,
+                    score: row.try_get::<_, f64>("distance")? as f32,
+                    payload: return_payload
+                        .then(|| pg_document_payload(row))
+                        .transpose()?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(QueryResponse { hits, took: None })
+    }
+
+    fn score_to_distance(&self, score: f32) -> f32 {
+        // the operators return our distances
+        score
+    }
+}
+
+fn pg_document_payload(row: &Row) -> Result<DocumentPayload, Error> {
+    StoredDocumentPayload {
+        publication_date: row.try_get("publication_date")?,
+        authors: row.try_get("authors")?,
+        tags: row.try_get("tags")?,
+        link: row.try_get("link")?,
+    }
+    .try_into()
+}
+
+fn pg_build_query(
+    table: &str,
+    distance: Distance,
+    request: &QueryRequest<'_>,
+) -> (String, SqlParams) {
+    let mut params: SqlParams = vec![
+        Box::new(Vector::from(request.vector.to_vec())),
+        Box::new(request.k as i64),
+    ];
+    let operator = pg_operator(distance);
+    let payload = match request.payload {
+        PayloadSelection::None => "",
+        PayloadSelection::All => ", publication_date, authors, tags, link",
+    };
+    let mut sql =
+        format!("SELECT id, embedding {operator} $1 AS distance{payload} FROM \"{table}\"");
+    if let Some(filter) = request.filter {
+        pg_append_filter(&mut sql, &mut params, filter);
+    }
+    sql.push_str(&format!(" ORDER BY embedding {operator} $1 LIMIT $2"));
+    (sql, params)
+}
+
+fn pg_append_filter(
+    sql: &mut String,
+    params: &mut SqlParams,
+    QueryPayload {
+        publication_date,
+        authors,
+        tags,
+    }: &QueryPayload,
+) {
+    let mut conditions = Vec::new();
+    pg_date_conditions(
+        "publication_date",
+        publication_date,
+        params,
+        &mut conditions,
+    );
+    pg_label_conditions("authors", authors, params, &mut conditions);
+    pg_label_conditions("tags", tags, params, &mut conditions);
+    if !conditions.is_empty() {
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+}
+
+fn pg_date_conditions(
+    field: &str,
+    DateFilter {
+        lower_bound,
+        upper_bound,
+    }: &DateFilter,
+    params: &mut SqlParams,
+    conditions: &mut Vec<String>,
+) {
+    for (bound, operator) in [(lower_bound, ">="), (upper_bound, "<=")] {
+        if let Some(bound) = bound {
+            params.push(Box::new(bound.timestamp()));
+            conditions.push(format!("{field} {operator} ${}", params.len()));
+        }
+    }
+}
+
+fn pg_label_conditions(
+    field: &str,
+    LabelFilter { include, exclude }: &LabelFilter,
+    params: &mut SqlParams,
+    conditions: &mut Vec<String>,
+) {
+    // `&&` is the array overlap, i.e. the document has any of the labels
+    if !include.is_empty() {
+        params.push(Box::new(include.to_uuid_string_vec()));
+        conditions.push(format!("{field} && ${}", params.len()));
+    }
+    if !exclude.is_empty() {
+        params.push(Box::new(exclude.to_uuid_string_vec()));
+        conditions.push(format!("NOT ({field} && ${})", params.len()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::databases::test_fixtures::{filter, filtered_request, request};
+
+    use super::*;
+
+    #[test]
+    fn test_query_building() {
+        let filter = filter();
+        let (sql, params) = pg_build_query("content", Distance::Cosine, &filtered_request(&filter));
+        assert_eq!(
+            sql,
+            concat!(
+                r#"SELECT id, embedding <=> $1 AS distance, publication_date, authors, tags, link FROM "content""#,
+                " WHERE publication_date >= $3 AND publication_date <= $4 AND authors && $5 AND NOT (tags && $6)",
+                " ORDER BY embedding <=> $1 LIMIT $2",
+            )
+        );
+        assert_eq!(params.len(), 6);
+
+        let (sql, params) = pg_build_query("content", Distance::Euclidean, &request());
+        assert_eq!(
+            sql,
+            r#"SELECT id, embedding <-> $1 AS distance FROM "content" ORDER BY embedding <-> $1 LIMIT $2"#
+        );
+        assert_eq!(params.len(), 2);
+    }
+}
@@ -12,58 +12,82 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::time::{Duration, Instant};
+pub mod rest;
+
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::{anyhow, bail, Error};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use qdrant_client::{
-    prelude::QdrantClient,
+    prelude::{Payload, QdrantClient},
     qdrant::{
-        condition::ConditionOneOf, point_id::PointIdOptions, r#match::MatchValue, read_consistency,
-        value::Kind, vectors::VectorsOptions, vectors_config, with_payload_selector,
-        CollectionStatus, Condition, CreateCollection, Distance, FieldCondition, FieldType, Filter,
-        HnswConfigDiff, ListValue, Match, OptimizersConfigDiff, PointId, PointStruct, Range,
-        ReadConsistency, ReadConsistencyType, RepeatedStrings, SearchParams, SearchPoints, Value,
-        Vector, VectorParams, Vectors, VectorsConfig, WithPayloadSelector,
+        self, condition::ConditionOneOf, point_id::PointIdOptions, points_update_operation,
+        quantization_config, r#match::MatchValue, read_consistency, value::Kind,
+        vectors::VectorsOptions, vectors_config, with_payload_selector, BinaryQuantization,
+        CollectionStatus, CompressionRatio as QdrantCompressionRatio, Condition, CreateCollection,
+        FieldCondition, FieldType, Filter, HnswConfigDiff, ListValue, Match, OptimizersConfigDiff,
+        PointId, PointStruct, PointsUpdateOperation, ProductQuantization, QuantizationConfig,
+        QuantizationSearchParams, QuantizationType, Range, ReadConsistency, ReadConsistencyType,
+        RepeatedStrings, ScalarQuantization, SearchParams, SearchPoints, Value, Vector,
+        VectorParams, Vectors, VectorsConfig, WithPayloadSelector,
     },
 };
-use tokio::time::sleep;
+use tokio::time::timeout;
 use uuid::Uuid;
 
 use crate::{
-    benchmarks::QueryVectorDatabase,
+    benchmarks::{
+        CompressionRatio, Consistency, ElementType, IndexType, IngestionParameters,
+        PayloadSelection, Quantization, QuantizationSearch, QueryHit, QueryRequest, QueryResponse,
+        QueryVectorDatabase,
+    },
     distribution::{
-        ingestion::{IngestionInfo, PrepareVectorDatabase},
-        DateFilter, LabelFilter, Labels, QueryPayload,
+        ingestion::{IngestionInfo, PayloadUpdate, PrepareVectorDatabase},
+        DateFilter, DocumentPayload, LabelFilter, Labels, QueryPayload, StoredDocumentPayload,
     },
+    math::Distance,
 };
 
-use super::DatabaseName;
+use super::{
+    balancer::{Balancer, Balancing, NodeRequests},
+    check_node_id, poll_until, DatabaseName,
+};
 
 pub struct Qdrant {
-    client: QdrantClient,
+    nodes: Balancer<QdrantClient>,
     collection: String,
-    vector_size: u64,
+    distance: Distance,
 }
 
 impl Qdrant {
-    pub fn new(node_id: u16) -> Result<Self, Error> {
-        if node_id == 0 || node_id > 9 {
-            bail!("only support node id [1;9]");
-        }
-        let port = 6400 + node_id * 10 + 4;
+    pub fn new(
+        node_ids: &[u16],
+        balancing: Balancing,
+        collection: impl Into<String>,
+        distance: Distance,
+    ) -> Result<Self, Error> {
+        let nodes = node_ids
+            .iter()
+            .map(|&node_id| {
+                let port = qdrant_port(node_id, 4)?;
+                let url = format!("http://localhost:{port}");
+                let client = QdrantClient::from_url(&url).build()?;
+                Ok((url, client))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
 
         Ok(Qdrant {
-            client: QdrantClient::from_url(&format!("http://localhost:{port}")).build()?,
-            collection: "content".into(),
-            vector_size: 960,
+            nodes: Balancer::new(nodes, balancing)?,
+            collection: collection.into(),
+            distance,
         })
     }
 
     async fn is_fully_ready(&self) -> Result<bool, Error> {
         let status = self
-            .client
+            .nodes
+            .pick()
             .collection_info(&self.collection)
             .await?
             .result
@@ -78,6 +102,16 @@ impl DatabaseName for Qdrant {
     fn name(&self) -> &str {
         "qdrant"
     }
+
+    fn node_requests(&self) -> NodeRequests {
+        self.nodes.node_requests()
+    }
+}
+
+/// The port of the node, `port_suffix` is `3` for REST and `4` for gRPC.
+fn qdrant_port(node_id: u16, port_suffix: u16) -> Result<u16, Error> {
+    check_node_id(node_id, 1)?;
+    Ok(6400 + node_id * 10 + port_suffix)
 }
 
 fn qdrant_labels(labels: &Labels) -> Value {
@@ -99,6 +133,21 @@ fn qdrant_time(time: DateTime<Utc>) -> Value {
     }
 }
 
+fn qdrant_payload(payload: &DocumentPayload) -> Payload {
+    Payload::from(HashMap::from([
+        ("publication_date", qdrant_time(payload.publication_date)),
+        ("authors", qdrant_labels(&payload.authors)),
+        ("tags", qdrant_labels(&payload.tags)),
+        ("link", payload.link.as_str().into()),
+    ]))
+}
+
+fn qdrant_point_id(id: Uuid) -> PointId {
+    PointId {
+        point_id_options: Some(PointIdOptions::Uuid(id.to_string())),
+    }
+}
+
 fn qdrant_vector(data: impl Into<Vec<f32>>) -> Vectors {
     Vectors {
         vectors_options: Some(VectorsOptions::Vector(Vector { data: data.into() })),
@@ -107,19 +156,28 @@ fn qdrant_vector(data: impl Into<Vec<f32>>) -> Vectors {
 
 #[async_trait(?Send)]
 impl PrepareVectorDatabase for Qdrant {
-    async fn initialize(&self) -> Result<bool, Error> {
-        let needs_ingestion = if self.client.has_collection(&self.collection).await? {
-            let info = self.client.collection_info(&self.collection).await?;
+    async fn initialize(
+        &self,
+        parameters: &IngestionParameters,
+        dimensions: usize,
+    ) -> Result<bool, Error> {
+        if parameters.index_type != IndexType::Hnsw || parameters.element_type != ElementType::Float
+        {
+            bail!("qdrant doesn't support {parameters}");
+        }
+        let client = self.nodes.pick();
+        let needs_ingestion = if client.has_collection(&self.collection).await? {
+            let info = client.collection_info(&self.collection).await?;
             info.result
                 .map(|info| info.vectors_count == 0)
                 .unwrap_or(true)
         } else {
-            self.client
+            client
                 .create_collection(&CreateCollection {
                     collection_name: self.collection.clone(),
                     hnsw_config: Some(HnswConfigDiff {
-                        m: Some(16),
-                        ef_construct: Some(100),
+                        m: Some(parameters.m as _),
+                        ef_construct: Some(parameters.ef_construct as _),
                         on_disk: Some(false),
                         ..HnswConfigDiff::default()
                     }),
@@ -134,23 +192,17 @@ impl PrepareVectorDatabase for Qdrant {
                     replication_factor: Some(2),
                     vectors_config: Some(VectorsConfig {
                         config: Some(vectors_config::Config::Params(VectorParams {
-                            //TODO parameterize
-                            size: self.vector_size,
-                            distance: Distance::Euclid as _,
+                            size: dimensions as _,
+                            distance: qdrant_distance(self.distance) as _,
                             ..VectorParams::default()
                         })),
                     }),
-                    /*quantization_config: Some(QuantizationConfig {
-                        quantization: Some(Quantization::Scalar(ScalarQuantization {
-                            r#type: QuantizationType::Int8 as _,
-                            ..ScalarQuantization::default()
-                        })),
-                    }),*/
+                    quantization_config: qdrant_quantization(parameters.quantization),
                     ..CreateCollection::default()
                 })
                 .await?;
 
-            self.client
+            client
                 .create_field_index(
                     &self.collection,
                     "publication_date",
@@ -160,11 +212,11 @@ impl PrepareVectorDatabase for Qdrant {
                 )
                 .await?;
 
-            self.client
+            client
                 .create_field_index(&self.collection, "authors", FieldType::Keyword, None, None)
                 .await?;
 
-            self.client
+            client
                 .create_field_index(&self.collection, "tags", FieldType::Keyword, None, None)
                 .await?;
 
@@ -175,7 +227,8 @@ impl PrepareVectorDatabase for Qdrant {
     }
 
     async fn prepare_mass_ingestion(&self) -> Result<(), Error> {
-        self.client
+        self.nodes
+            .pick()
             .update_collection(
                 &self.collection,
                 &OptimizersConfigDiff {
@@ -189,7 +242,8 @@ impl PrepareVectorDatabase for Qdrant {
     }
 
     async fn finish_mass_ingestion(&self, target_max_time: Duration) -> Result<(), Error> {
-        self.client
+        self.nodes
+            .pick()
             .update_collection(
                 &self.collection,
                 &OptimizersConfigDiff {
@@ -200,49 +254,26 @@ impl PrepareVectorDatabase for Qdrant {
             )
             .await?;
 
-        let start = Instant::now();
-        let mut ready_count = 0;
-        while Instant::now().duration_since(start) < target_max_time {
-            //Hint: It seems to be flacky
-            if self.is_fully_ready().await? {
-                ready_count += 1;
-                if ready_count >= 3 {
-                    return Ok(());
-                }
-            } else {
-                ready_count = 0;
-            }
-            sleep(Duration::from_secs(1)).await;
-        }
-        bail!(
-            "failed to finish mass ingestion even after {:.2}s",
-            Instant::now().duration_since(start).as_secs_f32()
-        )
+        //Hint: It seems to be flacky
+        poll_until("finish mass ingestion", target_max_time, 3, || {
+            self.is_fully_ready()
+        })
+        .await
     }
 
     async fn ingest_batch(
         &self,
         batch: impl IntoIterator<Item = IngestionInfo<'_>>,
     ) -> Result<(), Error> {
-        self.client
+        self.nodes
+            .pick()
             .upsert_points(
                 &self.collection,
                 batch
                     .into_iter()
                     .map(|info| PointStruct {
-                        id: Some(PointId {
-                            point_id_options: Some(PointIdOptions::Uuid(info.id.to_string())),
-                        }),
-                        payload: [
-                            (
-                                "publication_date".to_owned(),
-                                qdrant_time(info.payload.publication_date),
-                            ),
-                            ("authors".to_owned(), qdrant_labels(&info.payload.authors)),
-                            ("tags".to_owned(), qdrant_labels(&info.payload.tags)),
-                            ("link".to_owned(), info.payload.link.as_str().into()),
-                        ]
-                        .into(),
+                        id: Some(qdrant_point_id(info.id)),
+                        payload: qdrant_payload(info.payload).into(),
                         vectors: Some(qdrant_vector(info.vector)),
                     })
                     .collect(),
@@ -252,45 +283,78 @@ impl PrepareVectorDatabase for Qdrant {
 
         Ok(())
     }
-}
 
-#[async_trait]
-impl QueryVectorDatabase for Qdrant {
-    async fn query(
+    async fn update_payloads(
         &self,
-        k: usize,
-        ef: usize,
-        vector: &[f32],
-        payload: &QueryPayload,
-        return_payload: bool,
-        use_filter: bool,
-    ) -> Result<Vec<Uuid>, Error> {
-        let result = self
-            .client
-            .search_points(&SearchPoints {
-                collection_name: self.collection.clone(),
-                vector: vector.into(),
-                filter: use_filter.then(|| qdrant_filter(payload)).flatten(),
-                limit: k as _,
-                params: Some(SearchParams {
-                    hnsw_ef: Some(ef as _),
-                    ..SearchParams::default()
-                }),
-                with_payload: Some(WithPayloadSelector {
-                    selector_options: Some(with_payload_selector::SelectorOptions::Enable(
-                        return_payload,
-                    )),
-                }),
-                read_consistency: Some(ReadConsistency {
-                    value: Some(read_consistency::Value::Type(
-                        ReadConsistencyType::Quorum as _,
-                    )),
-                }),
-                ..SearchPoints::default()
+        batch: impl IntoIterator<Item = PayloadUpdate<'_>>,
+    ) -> Result<(), Error> {
+        // different payloads per point need one operation per point
+        let operations = batch
+            .into_iter()
+            .map(|PayloadUpdate { id, payload }| PointsUpdateOperation {
+                operation: Some(points_update_operation::Operation::OverwritePayload(
+                    points_update_operation::SetPayload {
+                        payload: qdrant_payload(payload).into(),
+                        points_selector: Some(vec![qdrant_point_id(id)].into()),
+                    },
+                )),
             })
+            .collect::<Vec<_>>();
+        self.nodes
+            .pick()
+            .batch_updates(&self.collection, &operations, None)
             .await?;
+        Ok(())
+    }
 
-        result
+    async fn delete_batch(&self, ids: &[Uuid]) -> Result<(), Error> {
+        self.nodes
+            .pick()
+            .delete_points(
+                &self.collection,
+                &ids.iter()
+                    .copied()
+                    .map(qdrant_point_id)
+                    .collect::<Vec<_>>()
+                    .into(),
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl QueryVectorDatabase for Qdrant {
+    async fn query(&self, request: &QueryRequest<'_>) -> Result<QueryResponse, Error> {
+        let return_payload = request.payload == PayloadSelection::All;
+        let search_points = SearchPoints {
+            collection_name: self.collection.clone(),
+            vector: request.vector.into(),
+            filter: request.filter.and_then(qdrant_filter),
+            limit: request.k as _,
+            params: Some(SearchParams {
+                hnsw_ef: Some(request.ef as _),
+                quantization: qdrant_quantization_search(request.quantization),
+                ..SearchParams::default()
+            }),
+            with_payload: Some(WithPayloadSelector {
+                selector_options: Some(with_payload_selector::SelectorOptions::Enable(
+                    return_payload,
+                )),
+            }),
+            read_consistency: Some(qdrant_read_consistency(request.consistency)),
+            ..SearchPoints::default()
+        };
+        let node = self.nodes.pick();
+        let search = node.search_points(&search_points);
+        // the grpc api has no per request timeout
+        let result = match request.timeout {
+            Some(duration) => timeout(duration, search).await??,
+            None => search.await?,
+        };
+
+        let hits = result
             .result
             .into_iter()
             .map(|entry| {
@@ -300,10 +364,140 @@ impl QueryVectorDatabase for Qdrant {
                 else {
                     bail!("document without uuid: {:?}", entry);
                 };
-                Ok(uuid.parse()?)
+                Ok(QueryHit {
+                    id: uuid.parse()?,
+                    score: entry.score,
+                    payload: return_payload
+                        .then(|| qdrant_document_payload(entry.payload))
+                        .transpose()?,
+                })
             })
-            .collect()
+            .collect::<Result<_, Error>>()?;
+
+        Ok(QueryResponse {
+            hits,
+            took: Some(Duration::from_secs_f64(result.time)),
+        })
+    }
+
+    fn score_to_distance(&self, score: f32) -> f32 {
+        qdrant_score_to_distance(self.distance, score)
+    }
+}
+
+fn qdrant_score_to_distance(distance: Distance, score: f32) -> f32 {
+    match distance {
+        // for `Euclid` the score is the distance
+        Distance::Euclidean => score,
+        // for `Cosine` and `Dot` the score is the similarity
+        Distance::Cosine => 1. - score,
+        Distance::DotProduct => -score,
+    }
+}
+
+fn qdrant_distance(distance: Distance) -> qdrant::Distance {
+    match distance {
+        Distance::Euclidean => qdrant::Distance::Euclid,
+        Distance::Cosine => qdrant::Distance::Cosine,
+        Distance::DotProduct => qdrant::Distance::Dot,
+    }
+}
+
+fn qdrant_read_consistency(consistency: Consistency) -> ReadConsistency {
+    let value = match consistency {
+        Consistency::All => read_consistency::Value::Type(ReadConsistencyType::All as _),
+        Consistency::Majority => read_consistency::Value::Type(ReadConsistencyType::Majority as _),
+        Consistency::Quorum => read_consistency::Value::Type(ReadConsistencyType::Quorum as _),
+        // the number of replicas which have to answer
+        Consistency::One => read_consistency::Value::Factor(1),
+    };
+    ReadConsistency { value: Some(value) }
+}
+
+fn qdrant_quantization(quantization: Quantization) -> Option<QuantizationConfig> {
+    let quantization = match quantization {
+        Quantization::None => return None,
+        Quantization::Scalar {
+            quantile,
+            always_ram,
+        } => quantization_config::Quantization::Scalar(ScalarQuantization {
+            r#type: QuantizationType::Int8 as _,
+            quantile,
+            always_ram: Some(always_ram),
+        }),
+        Quantization::Product {
+            compression,
+            always_ram,
+        } => quantization_config::Quantization::Product(ProductQuantization {
+            compression: qdrant_compression_ratio(compression) as _,
+            always_ram: Some(always_ram),
+        }),
+        Quantization::Binary { always_ram } => {
+            quantization_config::Quantization::Binary(BinaryQuantization {
+                always_ram: Some(always_ram),
+            })
+        }
+    };
+    Some(QuantizationConfig {
+        quantization: Some(quantization),
+    })
+}
+
+fn qdrant_compression_ratio(compression: CompressionRatio) -> QdrantCompressionRatio {
+    match compression {
+        CompressionRatio::X4 => QdrantCompressionRatio::X4,
+        CompressionRatio::X8 => QdrantCompressionRatio::X8,
+        CompressionRatio::X16 => QdrantCompressionRatio::X16,
+        CompressionRatio::X32 => QdrantCompressionRatio::X32,
+        CompressionRatio::X64 => QdrantCompressionRatio::X64,
+    }
+}
+
+fn qdrant_quantization_search(
+    quantization: QuantizationSearch,
+) -> Option<QuantizationSearchParams> {
+    (quantization != QuantizationSearch::default()).then_some(QuantizationSearchParams {
+        ignore: Some(quantization.ignore),
+        rescore: quantization.rescore,
+        oversampling: quantization.oversampling,
+    })
+}
+
+fn qdrant_document_payload(mut payload: HashMap<String, Value>) -> Result<DocumentPayload, Error> {
+    let mut field = |name: &str| {
+        payload
+            .remove(name)
+            .and_then(|value| value.kind)
+            .ok_or_else(|| anyhow!("missing payload field: {name}"))
+    };
+    let Kind::IntegerValue(publication_date) = field("publication_date")? else {
+        bail!("malformed publication_date");
+    };
+    let mut labels = |name: &str| {
+        let Kind::ListValue(ListValue { values }) = field(name)? else {
+            bail!("malformed {name}");
+        };
+        values
+            .into_iter()
+            .map(|value| match value.kind {
+                Some(Kind::StringValue(label)) => Ok(label),
+                _ => Err(anyhow!("malformed {name}")),
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let authors = labels("authors")?;
+    let tags = labels("tags")?;
+    let Kind::StringValue(link) = field("link")? else {
+        bail!("malformed link");
+    };
+
+    StoredDocumentPayload {
+        publication_date,
+        authors,
+        tags,
+        link,
     }
+    .try_into()
 }
 
 fn qdrant_filter(
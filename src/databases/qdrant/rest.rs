@@ -0,0 +1,534 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Qdrant over its JSON REST API instead of gRPC.
+//!
+//! Collections, payloads and queries are the same as with [`super::Qdrant`], so the
+//! difference of both measures the cost of the transport.
+
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use async_trait::async_trait;
+use reqwest::{Client, Method, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    benchmarks::{
+        Consistency, ElementType, IndexType, IngestionParameters, PayloadSelection, Quantization,
+        QueryHit, QueryRequest, QueryResponse, QueryVectorDatabase,
+    },
+    databases::{
+        balancer::{Balancer, Balancing, NodeRequests},
+        poll_until, DatabaseName,
+    },
+    distribution::{
+        ingestion::{IngestionInfo, PayloadUpdate, PrepareVectorDatabase},
+        DateFilter, DocumentPayload, LabelFilter, Labels, QueryPayload, StoredDocumentPayload,
+    },
+    math::Distance,
+    utils::{await_and_check_request, body_to_error},
+};
+
+use super::{qdrant_port, qdrant_score_to_distance};
+
+pub struct QdrantRest {
+    client: Client,
+    nodes: Balancer<Url>,
+    collection: String,
+    distance: Distance,
+}
+
+impl QdrantRest {
+    pub fn new(
+        node_ids: &[u16],
+        balancing: Balancing,
+        collection: impl Into<String>,
+        distance: Distance,
+    ) -> Result<Self, Error> {
+        let nodes = node_ids
+            .iter()
+            .map(|&node_id| {
+                let url = format!("http://localhost:{}/", qdrant_port(node_id, 3)?);
+                Ok((url.clone(), url.parse()?))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(QdrantRest {
+            client: Client::new(),
+            nodes: Balancer::new(nodes, balancing)?,
+            collection: collection.into(),
+            distance,
+        })
+    }
+
+    fn make_url(&self, node: &Url, segments: impl IntoIterator<Item = impl AsRef<str>>) -> Url {
+        let mut url = node.clone();
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .extend(["collections", &self.collection])
+            .extend(segments);
+        url
+    }
+
+    async fn json_request(
+        &self,
+        method: Method,
+        segments: impl IntoIterator<Item = impl AsRef<str>>,
+        data: &impl Serialize,
+    ) -> Result<Response, Error> {
+        let node = self.nodes.pick();
+        let fut = self
+            .client
+            .request(method, self.make_url(&node, segments))
+            .json(data)
+            .send();
+
+        await_and_check_request(fut).await
+    }
+
+    /// Returns `None` if the collection doesn't exist.
+    async fn collection_info(&self) -> Result<Option<CollectionInfo>, Error> {
+        let node = self.nodes.pick();
+        let response = self
+            .client
+            .get(self.make_url(&node, [] as [&str; 0]))
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(parse_result(response).await?.0)),
+            _ => Err(body_to_error(response).await),
+        }
+    }
+
+    async fn is_fully_ready(&self) -> Result<bool, Error> {
+        Ok(self
+            .collection_info()
+            .await?
+            .is_some_and(|info| info.status == "green"))
+    }
+
+    async fn set_indexing_threshold(&self, indexing_threshold: usize) -> Result<(), Error> {
+        self.json_request(
+            Method::PATCH,
+            [] as [&str; 0],
+            &json!({
+                "optimizers_config": {
+                    "indexing_threshold": indexing_threshold,
+                },
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+impl DatabaseName for QdrantRest {
+    fn name(&self) -> &str {
+        "qdrant-rest"
+    }
+
+    fn service(&self) -> &str {
+        "qdrant"
+    }
+
+    fn node_requests(&self) -> NodeRequests {
+        self.nodes.node_requests()
+    }
+}
+
+#[derive(Deserialize)]
+struct RestResponse<T> {
+    result: T,
+    /// Seconds
+    time: f64,
+}
+
+/// Returns the result and the time the server took.
+async fn parse_result<T>(response: Response) -> Result<(T, Duration), Error>
+where
+    T: DeserializeOwned,
+{
+    let RestResponse { result, time } = response.json::<RestResponse<T>>().await?;
+    Ok((result, Duration::from_secs_f64(time)))
+}
+
+#[derive(Deserialize)]
+struct CollectionInfo {
+    status: String,
+    #[serde(default)]
+    vectors_count: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ScoredPoint {
+    id: Uuid,
+    score: f32,
+    #[serde(default)]
+    payload: Option<StoredDocumentPayload>,
+}
+
+#[async_trait(?Send)]
+impl PrepareVectorDatabase for QdrantRest {
+    async fn initialize(
+        &self,
+        parameters: &IngestionParameters,
+        dimensions: usize,
+    ) -> Result<bool, Error> {
+        if parameters.index_type != IndexType::Hnsw || parameters.element_type != ElementType::Float
+        {
+            bail!("qdrant doesn't support {parameters}");
+        }
+        if let Some(info) = self.collection_info().await? {
+            return Ok(info.vectors_count.unwrap_or_default() == 0);
+        }
+
+        self.json_request(
+            Method::PUT,
+            [] as [&str; 0],
+            &json!({
+                "vectors": {
+                    "size": dimensions,
+                    "distance": rest_distance(self.distance),
+                },
+                "hnsw_config": {
+                    "m": parameters.m,
+                    "ef_construct": parameters.ef_construct,
+                    "on_disk": false,
+                },
+                "optimizers_config": {
+                    "memmap_threshold": 6_000_000,
+                },
+                // see `Qdrant::initialize`
+                "shard_number": 3,
+                "replication_factor": 2,
+                "quantization_config": rest_quantization(parameters.quantization),
+            }),
+        )
+        .await?;
+
+        for (field, schema) in [
+            ("publication_date", "integer"),
+            ("authors", "keyword"),
+            ("tags", "keyword"),
+        ] {
+            self.json_request(
+                Method::PUT,
+                ["index"],
+                &json!({
+                    "field_name": field,
+                    "field_schema": schema,
+                }),
+            )
+            .await?;
+        }
+
+        Ok(true)
+    }
+
+    async fn prepare_mass_ingestion(&self) -> Result<(), Error> {
+        //disable indexing
+        self.set_indexing_threshold(0).await
+    }
+
+    async fn finish_mass_ingestion(&self, target_max_time: Duration) -> Result<(), Error> {
+        //reset to default value
+        self.set_indexing_threshold(20_000).await?;
+
+        //Hint: It seems to be flacky
+        poll_until("finish mass ingestion", target_max_time, 3, || {
+            self.is_fully_ready()
+        })
+        .await
+    }
+
+    async fn ingest_batch(
+        &self,
+        batch: impl IntoIterator<Item = IngestionInfo<'_>>,
+    ) -> Result<(), Error> {
+        let points = batch
+            .into_iter()
+            .map(|info| {
+                json!({
+                    "id": info.id,
+                    "vector": info.vector,
+                    "payload": rest_payload(info.payload),
+                })
+            })
+            .collect::<Vec<_>>();
+        self.json_request(Method::PUT, ["points"], &json!({ "points": points }))
+            .await?;
+        Ok(())
+    }
+
+    async fn update_payloads(
+        &self,
+        batch: impl IntoIterator<Item = PayloadUpdate<'_>>,
+    ) -> Result<(), Error> {
+        // same as with grpc one operation per point
+        let operations = batch
+            .into_iter()
+            .map(|PayloadUpdate { id, payload }| {
+                json!({
+                    "overwrite_payload": {
+                        "payload": rest_payload(payload),
+                        "points": [id],
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        self.json_request(
+            Method::POST,
+            ["points", "batch"],
+            &json!({ "operations": operations }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_batch(&self, ids: &[Uuid]) -> Result<(), Error> {
+        self.json_request(
+            Method::POST,
+            ["points", "delete"],
+            &json!({ "points": ids }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl QueryVectorDatabase for QdrantRest {
+    async fn query(&self, request: &QueryRequest<'_>) -> Result<QueryResponse, Error> {
+        let return_payload = request.payload == PayloadSelection::All;
+        let node = self.nodes.pick();
+        let mut url = self.make_url(&node, ["points", "search"]);
+        url.query_pairs_mut()
+            .append_pair("consistency", rest_read_consistency(request.consistency));
+        let mut search = self.client.post(url).json(&rest_search(request));
+        if let Some(timeout) = request.timeout {
+            search = search.timeout(timeout);
+        }
+        let (points, took) =
+            parse_result::<Vec<ScoredPoint>>(await_and_check_request(search.send()).await?).await?;
+
+        let hits = points
+            .into_iter()
+            .map(|point| {
+                Ok(QueryHit {
+                    id: point.id,
+                    score: point.score,
+                    payload: point
+                        .payload
+                        .filter(|_| return_payload)
+                        .map(DocumentPayload::try_from)
+                        .transpose()?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(QueryResponse {
+            hits,
+            took: Some(took),
+        })
+    }
+
+    fn score_to_distance(&self, score: f32) -> f32 {
+        qdrant_score_to_distance(self.distance, score)
+    }
+}
+
+fn rest_distance(distance: Distance) -> &'static str {
+    match distance {
+        Distance::Euclidean => "Euclid",
+        Distance::Cosine => "Cosine",
+        Distance::DotProduct => "Dot",
+    }
+}
+
+fn rest_read_consistency(consistency: Consistency) -> &'static str {
+    match consistency {
+        Consistency::All => "all",
+        Consistency::Majority => "majority",
+        Consistency::Quorum => "quorum",
+        Consistency::One => "1",
+    }
+}
+
+fn rest_quantization(quantization: Quantization) -> Value {
+    match quantization {
+        Quantization::None => Value::Null,
+        Quantization::Scalar {
+            quantile,
+            always_ram,
+        } => json!({
+            "scalar": {
+                "type": "int8",
+                "quantile": quantile,
+                "always_ram": always_ram,
+            },
+        }),
+        Quantization::Product {
+            compression,
+            always_ram,
+        } => json!({
+            "product": {
+                "compression": compression,
+                "always_ram": always_ram,
+            },
+        }),
+        Quantization::Binary { always_ram } => json!({
+            "binary": {
+                "always_ram": always_ram,
+            },
+        }),
+    }
+}
+
+fn rest_payload(payload: &DocumentPayload) -> Value {
+    json!({
+        "publication_date": payload.publication_date.timestamp(),
+        "authors": payload.authors.to_uuid_string_vec(),
+        "tags": payload.tags.to_uuid_string_vec(),
+        "link": &payload.link,
+    })
+}
+
+fn rest_search(request: &QueryRequest<'_>) -> Value {
+    let mut params = json!({ "hnsw_ef": request.ef });
+    if request.quantization != Default::default() {
+        params["quantization"] = json!(request.quantization);
+    }
+    let mut search = json!({
+        "vector": request.vector,
+        "limit": request.k,
+        "params": params,
+        "with_payload": request.payload == PayloadSelection::All,
+    });
+    if let Some(filter) = request.filter.and_then(rest_filter) {
+        search["filter"] = filter;
+    }
+    search
+}
+
+fn rest_filter(
+    QueryPayload {
+        publication_date,
+        authors,
+        tags,
+    }: &QueryPayload,
+) -> Option<Value> {
+    let mut must = Vec::new();
+    let mut must_not = Vec::new();
+    rest_date_filter("publication_date", publication_date, &mut must);
+    rest_label_filter("authors", authors, &mut must, &mut must_not);
+    rest_label_filter("tags", tags, &mut must, &mut must_not);
+    (!must.is_empty() || !must_not.is_empty()).then(|| {
+        json!({
+            "must": must,
+            "must_not": must_not,
+        })
+    })
+}
+
+fn rest_date_filter(
+    field: &str,
+    DateFilter {
+        lower_bound,
+        upper_bound,
+    }: &DateFilter,
+    must: &mut Vec<Value>,
+) {
+    if lower_bound.is_some() || upper_bound.is_some() {
+        must.push(json!({
+            "key": field,
+            "range": {
+                "gte": lower_bound.map(|bound| bound.timestamp()),
+                "lte": upper_bound.map(|bound| bound.timestamp()),
+            },
+        }));
+    }
+}
+
+fn rest_label_filter(
+    field: &str,
+    LabelFilter { include, exclude }: &LabelFilter,
+    must: &mut Vec<Value>,
+    must_not: &mut Vec<Value>,
+) {
+    let match_any = |labels: &Labels| {
+        json!({
+            "key": field,
+            "match": { "any": labels.to_uuid_string_vec() },
+        })
+    };
+    if !include.is_empty() {
+        must.push(match_any(include));
+    }
+    if !exclude.is_empty() {
+        must_not.push(match_any(exclude));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        benchmarks::QuantizationSearch,
+        databases::test_fixtures::{filter, filtered_request, request},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_search_building() {
+        let filter = filter();
+        let search = rest_search(&QueryRequest {
+            quantization: QuantizationSearch {
+                rescore: Some(true),
+                ..QuantizationSearch::default()
+            },
+            ..filtered_request(&filter)
+        });
+        assert_eq!(
+            search,
+            json!({
+                "vector": [2., 4.],
+                "limit": 10,
+                "params": {
+                    "hnsw_ef": 20,
+                    "quantization": { "ignore": false, "rescore": true },
+                },
+                "with_payload": true,
+                "filter": {
+                    "must": [
+                        { "key": "publication_date", "range": { "gte": 3661, "lte": 3662 } },
+                        { "key": "authors", "match": { "any": ["00000000-0000-400c-8000-00000000000c"] } },
+                    ],
+                    "must_not": [
+                        { "key": "tags", "match": { "any": ["00000000-0000-4003-8000-000000000003", "00000000-0000-4004-8000-000000000004"] } },
+                    ],
+                },
+            })
+        );
+
+        let search = rest_search(&request());
+        assert_eq!(search["params"], json!({ "hnsw_ef": 20 }));
+        assert!(search.get("filter").is_none());
+    }
+}
@@ -12,70 +12,139 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::{cmp::min, collections::HashMap, fmt::Write, future::Future, time::Duration};
+use std::{
+    cmp::min,
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{anyhow, bail, Error};
 use async_trait::async_trait;
 use reqwest::{Client, Method, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use tokio::task::JoinSet;
 use url::Url;
 use uuid::Uuid;
 
 use crate::{
-    benchmarks::QueryVectorDatabase,
+    benchmarks::{
+        Consistency, ElementType, IndexType, IngestionParameters, PayloadSelection, Quantization,
+        QueryHit, QueryRequest, QueryResponse, QueryVectorDatabase,
+    },
     distribution::{
-        ingestion::{IngestionInfo, PrepareVectorDatabase},
-        DateFilter, LabelFilter, Labels, QueryPayload,
+        ingestion::{IngestionInfo, PayloadUpdate, PrepareVectorDatabase, WriteStatistics},
+        DateFilter, LabelFilter, Labels, StoredDocumentPayload,
     },
+    math::Distance,
     utils::await_and_check_request,
 };
 
-use super::DatabaseName;
+use super::{
+    balancer::{Balancer, Balancing, NodeRequests},
+    http_nodes, DatabaseName,
+};
+
+mod application;
+mod config_server;
+mod feeder;
+
+use self::{
+    application::{ApplicationPackage, Redundancy},
+    config_server::ConfigServer,
+    feeder::{Feeder, Operation},
+};
 
 pub struct Vespa {
     client: Client,
-    base_url: Url,
+    nodes: Arc<Balancer<Url>>,
+    /// The number of nodes of the cluster, i.e. `node-1` to `node-{cluster_nodes}`.
+    cluster_nodes: usize,
+    config_server: ConfigServer,
+    feeder: Feeder,
     namespace: String,
     document_type: String,
+    distance: Distance,
 }
 
 impl Vespa {
-    pub fn new(node_id: u16) -> Result<Self, Error> {
-        if node_id > 9 {
-            bail!("port pattern only supports nodes [0;9]");
-        }
-        let port = 8080 + node_id;
+    /// Queries and documents are balanced across the nodes, the application is deployed
+    /// through the config server of the first node.
+    pub fn new(
+        node_ids: &[u16],
+        balancing: Balancing,
+        document_type: impl Into<String>,
+        distance: Distance,
+    ) -> Result<Self, Error> {
+        let nodes = Arc::new(Balancer::new(http_nodes(node_ids, 0, 8080)?, balancing)?);
+        let config_server_port = 19071 + node_ids.first().copied().unwrap_or_default();
         let namespace = "default".into();
-        let document_type = "content".into();
+        let document_type = document_type.into();
+        let client = Client::builder().http2_prior_knowledge().build()?;
         Ok(Vespa {
-            client: Client::builder().http2_prior_knowledge().build()?,
-            base_url: format!("http://localhost:{port}/").parse()?,
+            feeder: Feeder::new(client.clone(), nodes.clone()),
+            client,
+            nodes,
+            cluster_nodes: node_ids.len(),
+            config_server: ConfigServer::new(config_server_port)?,
             namespace,
             document_type,
+            distance,
         })
     }
 
-    fn make_url(&self, segments: impl IntoIterator<Item = impl AsRef<str>>) -> Url {
-        let mut url = self.base_url.clone();
-        url.path_segments_mut().unwrap().extend(segments);
-        url
+    fn document_path(&self, id: Uuid) -> [String; 6] {
+        [
+            "document".into(),
+            "v1".into(),
+            self.namespace.clone(),
+            self.document_type.clone(),
+            "docid".into(),
+            id.to_string(),
+        ]
+    }
+
+    async fn deploy(
+        &self,
+        schemas: BTreeMap<String, String>,
+        dispatch_policy: &'static str,
+    ) -> Result<(), Error> {
+        let package = ApplicationPackage {
+            schemas,
+            nodes: self.cluster_nodes,
+            redundancy: Redundancy::from_env(self.cluster_nodes)?,
+            dispatch_policy,
+        }
+        .zip()?;
+        self.config_server.deploy(package).await
+    }
+
+    async fn document_count(&self) -> Result<usize, Error> {
+        let query = json!({
+            "yql": format!("select id from {} where true", self.document_type),
+            "hits": 0,
+        });
+        let SearchResult { root, .. } = self
+            .json_request(Method::POST, ["search", ""], &query)
+            .await?
+            .json::<SearchResult>()
+            .await?;
+        Ok(root.fields.total_count)
     }
 
-    fn json_request(
+    async fn json_request(
         &self,
         method: Method,
         segments: impl IntoIterator<Item = impl AsRef<str>>,
         data: &impl Serialize,
-    ) -> impl Future<Output = Result<Response, Error>> + Send + 'static {
-        let fut = self
-            .client
-            .request(method, self.make_url(segments))
-            .json(data)
-            .send();
-
-        async move { await_and_check_request(fut).await }
+    ) -> Result<Response, Error> {
+        let node = self.nodes.pick();
+        let mut url = Url::clone(&node);
+        url.path_segments_mut().unwrap().extend(segments);
+        let fut = self.client.request(method, url).json(data).send();
+
+        await_and_check_request(fut).await
     }
 }
 
@@ -83,52 +152,105 @@ impl DatabaseName for Vespa {
     fn name(&self) -> &str {
         "vespa"
     }
+
+    fn node_requests(&self) -> NodeRequests {
+        self.nodes.node_requests()
+    }
 }
 
 #[async_trait]
 impl QueryVectorDatabase for Vespa {
-    async fn query(
-        &self,
-        k: usize,
-        ef: usize,
-        vector: &[f32],
-        payload: &QueryPayload,
-        return_payload: bool,
-        use_filter: bool,
-    ) -> Result<Vec<Uuid>, Error> {
-        let query = yql_build_query(k, ef, vector, payload, return_payload, use_filter)?;
-        let root = self
+    async fn query(&self, request: &QueryRequest<'_>) -> Result<QueryResponse, Error> {
+        let query = yql_build_query(&self.document_type, request)?;
+        let SearchResult { root, timing } = self
             //Hint: The trailing "" is important the path has to be /search/ not /search
             .json_request(Method::POST, ["search", ""], &query)
             .await?
             .json::<SearchResult>()
-            .await?
-            .root;
+            .await?;
 
         let total_count = root.fields.total_count;
         let got_count = root.children.len();
-        if min(total_count, k) != got_count {
+        if min(total_count, request.k) != got_count {
             bail!("malformed result({total_count} != {got_count}): {root:?}");
         }
 
-        root.children
+        let return_payload = request.payload == PayloadSelection::All;
+        let hits = root
+            .children
             .into_iter()
             .map(|child| {
-                child
+                let id = child
                     .fields
                     .get("id")
                     .and_then(|o| o.as_str())
                     .ok_or_else(|| anyhow!("malformed or missing id field: {:?}", child))?
-                    .parse()
-                    .map_err(Error::from)
+                    .parse()?;
+                let payload = return_payload
+                    .then(|| {
+                        serde_json::from_value::<StoredDocumentPayload>(Value::Object(
+                            child.fields.into_iter().collect(),
+                        ))?
+                        .try_into()
+                    })
+                    .transpose()?;
+                Ok(QueryHit {
+                    id,
+                    score: child.relevance,
+                    payload,
+                })
             })
-            .collect()
+            .collect::<Result<_, Error>>()?;
+
+        Ok(QueryResponse {
+            hits,
+            took: timing.map(|timing| Duration::from_secs_f64(timing.search_time)),
+        })
+    }
+
+    async fn prepare_queries(&self, request: &QueryRequest<'_>) -> Result<(), Error> {
+        let dispatch_policy = application::dispatch_policy(request.consistency)?;
+        let services = self.config_server.deployed_services().await?;
+        if services
+            .as_deref()
+            .and_then(application::deployed_dispatch_policy)
+            == Some(dispatch_policy)
+        {
+            return Ok(());
+        }
+        eprintln!("deploy vespa application with dispatch policy {dispatch_policy}");
+        self.deploy(
+            self.config_server.deployed_schemas().await?,
+            dispatch_policy,
+        )
+        .await?;
+        self.config_server.wait_for_convergence().await
+    }
+
+    fn score_to_distance(&self, score: f32) -> f32 {
+        match self.distance {
+            // `closeness(field, embedding)` is `1 / (1 + distance)`
+            Distance::Euclidean => (1. / score - 1.).max(0.),
+            // with the `angular` metric the distance is the angle
+            Distance::Cosine => 1. - (1. / score - 1.).max(0.).cos(),
+            // with the `dotproduct` metric the closeness is the dot product
+            Distance::DotProduct => -score,
+        }
     }
 }
 
 #[derive(Deserialize, Debug)]
 struct SearchResult {
     root: Root,
+    /// Only returned with `presentation.timing`.
+    timing: Option<Timing>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Timing {
+    /// Seconds
+    #[serde(rename = "searchtime")]
+    search_time: f64,
 }
 
 #[derive(Deserialize, Debug)]
@@ -148,23 +270,31 @@ struct RootFields {
 struct Child {
     // WARNING: we can't use this id it's not useful if the whole result is fetched from memory
     // id: String,
+    relevance: f32,
     fields: HashMap<String, Value>,
 }
 
-fn yql_build_query(
-    k: usize,
-    ef: usize,
-    vector: &[f32],
-    payload: &QueryPayload,
-    return_payload: bool,
-    use_filter: bool,
-) -> Result<Value, Error> {
-    let selector = if return_payload { " * " } else { " id " };
+fn yql_build_query(document_type: &str, request: &QueryRequest<'_>) -> Result<Value, Error> {
+    let QueryRequest {
+        vector,
+        k,
+        ef,
+        filter,
+        payload,
+        timeout,
+        // applied through the dispatch policy, see `prepare_queries`
+        consistency: _,
+        quantization: _,
+    } = *request;
+    let selector = match payload {
+        PayloadSelection::None => " id ",
+        PayloadSelection::All => " * ",
+    };
     let explore_additional_hits = ef - k;
     let mut query =
-        format!("select{selector}from content where {{hnsw.exploreAdditionalHits:{explore_additional_hits}, targetHits:{k}}}nearestNeighbor(embedding, query_embedding)");
+        format!("select{selector}from {document_type} where {{hnsw.exploreAdditionalHits:{explore_additional_hits}, targetHits:{k}}}nearestNeighbor(embedding, query_embedding)");
 
-    if use_filter {
+    if let Some(payload) = filter {
         yql_append_date_range(&mut query, "publication_date", &payload.publication_date)?;
         yql_append_label_filter(&mut query, "authors", &payload.authors)?;
         yql_append_label_filter(&mut query, "tags", &payload.tags)?;
@@ -179,7 +309,8 @@ fn yql_build_query(
         // early one, but we error and stop the benchmark once we hit a single error and with 100q being no
         // stop thrown at it in parallel (but over the same http2 client) and given resource limits some timeouts
         // are doomed to happen sooner or later. So we set them to the max value of 60s.
-        "timeout": "60s",
+        "timeout": timeout.map_or_else(|| "60s".into(), |timeout| format!("{}ms", timeout.as_millis())),
+        "presentation.timing": true,
     }))
 }
 
@@ -242,9 +373,41 @@ fn yql_append_or_joined_labels(
 
 #[async_trait(?Send)]
 impl PrepareVectorDatabase for Vespa {
-    async fn initialize(&self) -> Result<bool, Error> {
-        //TODO check if empty, in the future maybe deploy tenant here instead of with docker helper
-        Ok(true)
+    async fn initialize(
+        &self,
+        parameters: &IngestionParameters,
+        dimensions: usize,
+    ) -> Result<bool, Error> {
+        if parameters.index_type != IndexType::Hnsw
+            || parameters.element_type != ElementType::Float
+            || parameters.quantization != Quantization::None
+        {
+            bail!("vespa doesn't support {parameters}");
+        }
+        self.config_server.wait_until_ready().await?;
+
+        // Deploying replaces the whole application, so we keep the schemas of other
+        // ingestion parameters to not loose their documents.
+        let schema =
+            application::schema(&self.document_type, parameters, self.distance, dimensions);
+        let mut schemas = self.config_server.deployed_schemas().await?;
+        match schemas.get(&self.document_type) {
+            Some(deployed) if *deployed == schema => {}
+            Some(_) => bail!(
+                "{} is already deployed with a different schema (distance={:?}, dimensions={dimensions}), recreate the volumes to start from scratch",
+                self.document_type,
+                self.distance,
+            ),
+            None => {
+                eprintln!("deploy vespa application with {}", self.document_type);
+                schemas.insert(self.document_type.clone(), schema);
+                let dispatch_policy = application::dispatch_policy(Consistency::default())?;
+                self.deploy(schemas, dispatch_policy).await?;
+            }
+        }
+        self.config_server.wait_for_convergence().await?;
+
+        Ok(self.document_count().await? == 0)
     }
 
     async fn prepare_mass_ingestion(&self) -> Result<(), Error> {
@@ -261,25 +424,15 @@ impl PrepareVectorDatabase for Vespa {
         &self,
         batch: impl IntoIterator<Item = IngestionInfo<'_>>,
     ) -> Result<(), Error> {
-        let mut tasks = JoinSet::new();
-
-        for IngestionInfo {
-            id,
-            vector,
-            payload,
-        } in batch
-        {
-            tasks.spawn(self.json_request(
-                Method::POST,
-                [
-                    "document",
-                    "v1",
-                    &self.namespace,
-                    &self.document_type,
-                    "docid",
-                    &id.to_string(),
-                ],
-                &json!({
+        let operations = batch.into_iter().map(
+            |IngestionInfo {
+                 id,
+                 vector,
+                 payload,
+             }| Operation {
+                method: Method::POST,
+                path: self.document_path(id),
+                body: Some(json!({
                     "fields": {
                         "id": id,
                         "embedding": vector,
@@ -288,15 +441,44 @@ impl PrepareVectorDatabase for Vespa {
                         "tags": payload.tags.to_uuid_string_vec(),
                         "link": &payload.link,
                     }
-                }),
-            ));
-        }
+                })),
+            },
+        );
+        self.feeder.feed(operations).await
+    }
 
-        while let Some(result) = tasks.join_next().await {
-            result??;
-        }
+    async fn update_payloads(
+        &self,
+        batch: impl IntoIterator<Item = PayloadUpdate<'_>>,
+    ) -> Result<(), Error> {
+        let operations = batch
+            .into_iter()
+            .map(|PayloadUpdate { id, payload }| Operation {
+                method: Method::PUT,
+                path: self.document_path(id),
+                body: Some(json!({
+                    "fields": {
+                        "publication_date": { "assign": payload.publication_date.timestamp() },
+                        "authors": { "assign": payload.authors.to_uuid_string_vec() },
+                        "tags": { "assign": payload.tags.to_uuid_string_vec() },
+                        "link": { "assign": &payload.link },
+                    }
+                })),
+            });
+        self.feeder.feed(operations).await
+    }
 
-        Ok(())
+    async fn delete_batch(&self, ids: &[Uuid]) -> Result<(), Error> {
+        let operations = ids.iter().map(|&id| Operation {
+            method: Method::DELETE,
+            path: self.document_path(id),
+            body: None,
+        });
+        self.feeder.feed(operations).await
+    }
+
+    fn write_statistics(&self) -> Option<WriteStatistics> {
+        Some(self.feeder.statistics())
     }
 }
 
@@ -304,32 +486,32 @@ impl PrepareVectorDatabase for Vespa {
 mod tests {
     use chrono::{TimeZone, Utc};
 
-    use crate::distribution::Label;
+    use crate::distribution::{Label, QueryPayload};
 
     use super::*;
 
     #[test]
     fn test_query_building() {
         let res = yql_build_query(
-            10,
-            22,
-            &[2., 4., 6.],
-            &QueryPayload {
-                publication_date: DateFilter {
-                    lower_bound: Some(Utc.with_ymd_and_hms(1970, 1, 1, 1, 1, 1).unwrap()),
-                    upper_bound: Some(Utc.with_ymd_and_hms(2005, 2, 2, 2, 2, 2).unwrap()),
-                },
-                authors: LabelFilter {
-                    include: Labels(vec![Label(12), Label(5)]),
-                    exclude: Labels(vec![Label(3)]),
-                },
-                tags: LabelFilter {
-                    include: Labels(vec![Label(7)]),
-                    exclude: Labels(vec![Label(1), Label(321)]),
-                },
+            "content",
+            &QueryRequest {
+                filter: Some(&QueryPayload {
+                    publication_date: DateFilter {
+                        lower_bound: Some(Utc.with_ymd_and_hms(1970, 1, 1, 1, 1, 1).unwrap()),
+                        upper_bound: Some(Utc.with_ymd_and_hms(2005, 2, 2, 2, 2, 2).unwrap()),
+                    },
+                    authors: LabelFilter {
+                        include: Labels(vec![Label(12), Label(5)]),
+                        exclude: Labels(vec![Label(3)]),
+                    },
+                    tags: LabelFilter {
+                        include: Labels(vec![Label(7)]),
+                        exclude: Labels(vec![Label(1), Label(321)]),
+                    },
+                }),
+                payload: PayloadSelection::All,
+                ..QueryRequest::new(&[2., 4., 6.], 10, 22)
             },
-            true,
-            true,
         )
         .unwrap();
 
@@ -349,29 +531,30 @@ mod tests {
                 "ranking.profile": "ann",
                 "hits": 10,
                 "timeout": "60s",
+                "presentation.timing": true,
             })
         );
 
         let res = yql_build_query(
-            10,
-            25,
-            &[2., 4., 6.],
-            &QueryPayload {
-                publication_date: DateFilter {
-                    lower_bound: Some(Utc.with_ymd_and_hms(1970, 1, 1, 1, 1, 1).unwrap()),
-                    upper_bound: None,
-                },
-                authors: LabelFilter {
-                    include: Labels::default(),
-                    exclude: Labels(vec![Label(3)]),
-                },
-                tags: LabelFilter {
-                    include: Labels(vec![Label(7)]),
-                    exclude: Labels::default(),
-                },
+            "content",
+            &QueryRequest {
+                filter: Some(&QueryPayload {
+                    publication_date: DateFilter {
+                        lower_bound: Some(Utc.with_ymd_and_hms(1970, 1, 1, 1, 1, 1).unwrap()),
+                        upper_bound: None,
+                    },
+                    authors: LabelFilter {
+                        include: Labels::default(),
+                        exclude: Labels(vec![Label(3)]),
+                    },
+                    tags: LabelFilter {
+                        include: Labels(vec![Label(7)]),
+                        exclude: Labels::default(),
+                    },
+                }),
+                payload: PayloadSelection::All,
+                ..QueryRequest::new(&[2., 4., 6.], 10, 25)
             },
-            true,
-            true,
         )
         .unwrap();
 
@@ -389,20 +572,21 @@ mod tests {
                 "ranking.profile": "ann",
                 "hits": 10,
                 "timeout": "60s",
+                "presentation.timing": true,
             })
         );
 
         let res = yql_build_query(
-            15,
-            20,
-            &[2., 4., 6.],
-            &QueryPayload {
-                publication_date: DateFilter::default(),
-                authors: LabelFilter::default(),
-                tags: LabelFilter::default(),
+            "content",
+            &QueryRequest {
+                filter: Some(&QueryPayload {
+                    publication_date: DateFilter::default(),
+                    authors: LabelFilter::default(),
+                    tags: LabelFilter::default(),
+                }),
+                payload: PayloadSelection::All,
+                ..QueryRequest::new(&[2., 4., 6.], 15, 20)
             },
-            true,
-            true,
         )
         .unwrap();
 
@@ -417,29 +601,29 @@ mod tests {
                 "ranking.profile": "ann",
                 "hits": 15,
                 "timeout": "60s",
+                "presentation.timing": true,
             })
         );
 
         let res = yql_build_query(
-            10,
-            20,
-            &[2., 4., 6.],
-            &QueryPayload {
-                publication_date: DateFilter {
-                    lower_bound: None,
-                    upper_bound: Some(Utc.with_ymd_and_hms(1970, 1, 1, 1, 1, 1).unwrap()),
-                },
-                authors: LabelFilter {
-                    include: Labels::default(),
-                    exclude: Labels::default(),
-                },
-                tags: LabelFilter {
-                    include: Labels::default(),
-                    exclude: Labels::default(),
-                },
+            "content",
+            &QueryRequest {
+                filter: Some(&QueryPayload {
+                    publication_date: DateFilter {
+                        lower_bound: None,
+                        upper_bound: Some(Utc.with_ymd_and_hms(1970, 1, 1, 1, 1, 1).unwrap()),
+                    },
+                    authors: LabelFilter {
+                        include: Labels::default(),
+                        exclude: Labels::default(),
+                    },
+                    tags: LabelFilter {
+                        include: Labels::default(),
+                        exclude: Labels::default(),
+                    },
+                }),
+                ..QueryRequest::new(&[2., 4., 6.], 10, 20)
             },
-            false,
-            true,
         )
         .unwrap();
 
@@ -455,31 +639,11 @@ mod tests {
                 "ranking.profile": "ann",
                 "hits": 10,
                 "timeout": "60s",
+                "presentation.timing": true,
             })
         );
 
-        let res = yql_build_query(
-            10,
-            20,
-            &[2., 4., 6.],
-            &QueryPayload {
-                publication_date: DateFilter {
-                    lower_bound: None,
-                    upper_bound: Some(Utc.with_ymd_and_hms(1970, 1, 1, 1, 1, 1).unwrap()),
-                },
-                authors: LabelFilter {
-                    include: Labels::default(),
-                    exclude: Labels::default(),
-                },
-                tags: LabelFilter {
-                    include: Labels::default(),
-                    exclude: Labels::default(),
-                },
-            },
-            false,
-            false,
-        )
-        .unwrap();
+        let res = yql_build_query("content", &QueryRequest::new(&[2., 4., 6.], 10, 20)).unwrap();
 
         assert_eq!(
             res,
@@ -492,6 +656,7 @@ mod tests {
                 "ranking.profile": "ann",
                 "hits": 10,
                 "timeout": "60s",
+                "presentation.timing": true,
             })
         );
     }
@@ -0,0 +1,374 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Generation of the vespa application package.
+//!
+//! The package is rendered from the run parameters, zipped in memory and deployed through
+//! the config server, see [`super::config_server`].
+
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Write},
+};
+
+use anyhow::{bail, Error};
+use zip::{write::FileOptions, ZipWriter};
+
+use crate::{
+    benchmarks::{Consistency, IngestionParameters},
+    consts::{VESPA_MIN_REDUNDANCY, VESPA_SEARCHABLE_COPIES},
+    math::Distance,
+};
+
+/// How many copies of each document are stored and indexed.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Redundancy {
+    pub(super) min_redundancy: usize,
+    pub(super) searchable_copies: usize,
+}
+
+impl Redundancy {
+    /// Reads the redundancy configured for a cluster of given number of nodes.
+    pub(super) fn from_env(nodes: usize) -> Result<Self, Error> {
+        let redundancy = Self {
+            min_redundancy: *VESPA_MIN_REDUNDANCY,
+            searchable_copies: *VESPA_SEARCHABLE_COPIES,
+        };
+        if !(1..=nodes).contains(&redundancy.min_redundancy)
+            || !(1..=nodes).contains(&redundancy.searchable_copies)
+        {
+            bail!("vespa redundancy {redundancy:?} must be within [1;{nodes}]");
+        }
+        Ok(redundancy)
+    }
+}
+
+/// Maps the consistency to the dispatch policy of the content cluster.
+///
+/// Vespa has no read consistency, the dispatch policy selects the copies a query is sent to.
+pub(super) fn dispatch_policy(consistency: Consistency) -> Result<&'static str, Error> {
+    match consistency {
+        // prefers the nodes with the fewest outstanding queries
+        Consistency::Quorum => Ok("adaptive"),
+        Consistency::One => Ok("round-robin"),
+        Consistency::All | Consistency::Majority => {
+            bail!("vespa doesn't support consistency {consistency:?}")
+        }
+    }
+}
+
+/// Extracts the dispatch policy from a rendered `services.xml`.
+pub(super) fn deployed_dispatch_policy(services: &str) -> Option<&str> {
+    let (_, policy) = services.split_once("<dispatch-policy>")?;
+    let (policy, _) = policy.split_once("</dispatch-policy>")?;
+    Some(policy)
+}
+
+pub(super) struct ApplicationPackage {
+    /// The schemas by document type, schema and document have the same name.
+    pub(super) schemas: BTreeMap<String, String>,
+    /// The number of vespa nodes, i.e. `node-1` to `node-{nodes}`.
+    pub(super) nodes: usize,
+    pub(super) redundancy: Redundancy,
+    /// See [`dispatch_policy`].
+    pub(super) dispatch_policy: &'static str,
+}
+
+impl ApplicationPackage {
+    /// Zips the package in memory.
+    pub(super) fn zip(&self) -> Result<Vec<u8>, Error> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let mut add_file = |name: &str, content: &str| -> Result<(), Error> {
+            zip.start_file(name, FileOptions::default())?;
+            zip.write_all(content.as_bytes())?;
+            Ok(())
+        };
+        add_file("services.xml", &self.services())?;
+        add_file("hosts.xml", &self.hosts())?;
+        for (document_type, schema) in &self.schemas {
+            add_file(&format!("schemas/{document_type}.sd"), schema)?;
+        }
+        Ok(zip.finish()?.into_inner())
+    }
+
+    fn hosts(&self) -> String {
+        let hosts = (1..=self.nodes)
+            .map(|node| {
+                format!("    <host name=\"node-{node}\">\n        <alias>node{node}</alias>\n    </host>\n")
+            })
+            .collect::<String>();
+        format!("<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<hosts>\n{hosts}</hosts>\n")
+    }
+
+    fn services(&self) -> String {
+        let node_list = |indent: &str, distribution_key: bool| {
+            (1..=self.nodes)
+                .map(|node| {
+                    let key = if distribution_key {
+                        format!(r#" distribution-key="{}""#, node - 1)
+                    } else {
+                        String::new()
+                    };
+                    format!("{indent}<node hostalias=\"node{node}\"{key} />\n")
+                })
+                .collect::<String>()
+        };
+        let admin_nodes = |tag: &str| {
+            (1..=self.nodes)
+                .map(|node| format!("            <{tag} hostalias=\"node{node}\" />\n"))
+                .collect::<String>()
+        };
+        let documents = self
+            .schemas
+            .keys()
+            .map(|document_type| {
+                format!("            <document type=\"{document_type}\" mode=\"index\" />\n")
+            })
+            .collect::<String>();
+        let configservers = admin_nodes("configserver");
+        let cluster_controllers = admin_nodes("cluster-controller");
+        let container_nodes = node_list("            ", false);
+        let content_nodes = node_list("            ", true);
+        let Redundancy {
+            min_redundancy,
+            searchable_copies,
+        } = self.redundancy;
+        let dispatch_policy = self.dispatch_policy;
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8" ?>
+<!-- based on https://github.com/vespa-engine/sample-apps/blob/master/examples/operations/multinode/services.xml -->
+<services version="1.0" xmlns:deploy="vespa" xmlns:preprocess="properties">
+    <admin version='2.0'>
+        <adminserver hostalias="node1" />
+        <configservers>
+{configservers}        </configservers>
+        <cluster-controllers>
+{cluster_controllers}        </cluster-controllers>
+    </admin>
+
+    <container id="default" version="1.0">
+        <document-api/>
+        <search/>
+        <nodes>
+{container_nodes}        </nodes>
+    </container>
+
+    <content id="content" version="1.0">
+        <min-redundancy>{min_redundancy}</min-redundancy>
+        <engine>
+            <proton>
+                <searchable-copies>{searchable_copies}</searchable-copies>
+            </proton>
+        </engine>
+        <tuning>
+            <dispatch>
+                <dispatch-policy>{dispatch_policy}</dispatch-policy>
+            </dispatch>
+        </tuning>
+        <documents>
+{documents}        </documents>
+        <nodes>
+{content_nodes}        </nodes>
+    </content>
+
+</services>
+"#
+        )
+    }
+}
+
+fn vespa_distance_metric(distance: Distance) -> &'static str {
+    match distance {
+        Distance::Euclidean => "euclidean",
+        Distance::Cosine => "angular",
+        Distance::DotProduct => "dotproduct",
+    }
+}
+
+/// Generates the schema of `document_type` for given HNSW parameters and vectors.
+///
+/// Schema and document have the same name.
+pub(super) fn schema(
+    document_type: &str,
+    parameters: &IngestionParameters,
+    distance: Distance,
+    dimensions: usize,
+) -> String {
+    let IngestionParameters {
+        m, ef_construct, ..
+    } = parameters;
+    let distance_metric = vespa_distance_metric(distance);
+    format!(
+        r#"# based on: https://github.com/vespa-engine/sample-apps/blob/master/simple-semantic-search/schemas/doc.sd
+
+# schemas mainly bundle _one_ document with a number of rank-profile and other things like struct definitions
+# it's convenient to give it the same name as the document so that you don't have to think about which places
+# want the schema name and which the document name (most)
+schema {document_type} {{
+
+    document {document_type} {{
+
+        # See: https://docs.vespa.ai/en/document-v1-api-guide.html#troubleshooting 3rd bullet point
+        # WARNING: can not be called id
+        field id type string {{
+            indexing: summary
+        }}
+
+        field embedding type tensor<float>(x[{dimensions}]) {{
+            # summary == allow returning the vector
+            # index == allows using it as main search/ranking feature
+            # attribute == allows filtering by it
+            # hnsw search needs index+attribute
+            indexing: summary | attribute | index
+            attribute {{
+                # or e.g. dotproduct and many other
+                # be aware that angular is the angle use cos(closeness(.....)) to get a cosin metric
+                distance-metric: {distance_metric}
+            }}
+            index {{
+                hnsw {{
+                    # == M
+                    max-links-per-node: {m}
+                    # == ef_construction
+                    neighbors-to-explore-at-insert: {ef_construct}
+                    # the ef parameter can be set as attribute of nearestNeighbor:
+                    # e.g. `{{hnsw.exploreAdditionalHits:180, targetHits:20}}nearestNeighbor(embedding, query_embedding)`
+                    # would be equivalent to ef=200,k=20 in term more familiar with HNSW in papers
+                }}
+            }}
+        }}
+
+        field tags type array<string> {{
+            # like ES each field of an array is indexed separately
+            indexing: summary | attribute
+            # needed so that string comparison using `contains` is a exact match
+            # instead of checking tokenized/stemed word overlap
+            match: exact
+            # hints to vespa that it's only used for filtering
+            rank: filter
+
+        }}
+
+        field authors type array<string> {{
+            indexing: summary | attribute
+            match: exact
+            rank: filter
+        }}
+
+        # time in unix epoch UTC
+        field publication_date type long {{
+            # there is no date time, so we use UTC+0 unix epoch timestamps in seconds
+            indexing: summary | attribute
+            rank: filter
+        }}
+
+        field link type string {{
+            # note that there is a URL type which handles the trickiness of searching/filtering
+            # for urls better, but we don't need that here
+            indexing: summary
+        }}
+    }}
+
+    rank-profile ann inherits default {{
+        inputs {{
+            # nearestNeighbor in queries needs one query input and that inputs tensor type needs to be defined here
+            query(query_embedding) tensor<float>(x[{dimensions}])
+        }}
+        first-phase {{
+            # `field` here is a _constant string_ it basically says:
+            #  "from that single nearestNeighbor function in the search query take the vector and
+            #   compare it to the embedding field of this schemas document"
+            #  see https://docs.vespa.ai/en/reference/rank-features.html#closeness(dimension,name)
+            expression: closeness(field, embedding)
+        }}
+    }}
+
+}}
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use zip::ZipArchive;
+
+    use crate::benchmarks::{ElementType, IndexType, Quantization};
+
+    use super::*;
+
+    #[test]
+    fn test_package_contains_all_schemas() {
+        let parameters = [(16, 100), (32, 200)].map(|(m, ef_construct)| IngestionParameters {
+            m,
+            ef_construct,
+            index_type: IndexType::Hnsw,
+            element_type: ElementType::Float,
+            quantization: Quantization::None,
+        });
+        let package = ApplicationPackage {
+            schemas: parameters
+                .iter()
+                .map(|parameters| {
+                    let document_type = parameters.index_name();
+                    let schema = schema(&document_type, parameters, Distance::Cosine, 3);
+                    (document_type, schema)
+                })
+                .collect(),
+            nodes: 3,
+            redundancy: Redundancy {
+                min_redundancy: 1,
+                searchable_copies: 3,
+            },
+            dispatch_policy: dispatch_policy(Consistency::One).unwrap(),
+        };
+
+        let mut zip = ZipArchive::new(Cursor::new(package.zip().unwrap())).unwrap();
+        let mut names = zip.file_names().collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                "hosts.xml",
+                "schemas/content_m16_ef100.sd",
+                "schemas/content_m32_ef200.sd",
+                "services.xml",
+            ]
+        );
+
+        let mut services = String::new();
+        zip.by_name("services.xml")
+            .unwrap()
+            .read_to_string(&mut services)
+            .unwrap();
+        assert!(services.contains(concat!(
+            "        <documents>\n",
+            "            <document type=\"content_m16_ef100\" mode=\"index\" />\n",
+            "            <document type=\"content_m32_ef200\" mode=\"index\" />\n",
+            "        </documents>\n",
+        )));
+        assert!(services.contains(r#"<node hostalias="node3" distribution-key="2" />"#));
+        assert_eq!(deployed_dispatch_policy(&services), Some("round-robin"));
+
+        let mut schema = String::new();
+        zip.by_name("schemas/content_m32_ef200.sd")
+            .unwrap()
+            .read_to_string(&mut schema)
+            .unwrap();
+        assert!(schema.contains("field embedding type tensor<float>(x[3])"));
+        assert!(schema.contains("distance-metric: angular"));
+        assert!(schema.contains("max-links-per-node: 32"));
+    }
+}
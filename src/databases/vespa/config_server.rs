@@ -0,0 +1,213 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Deployment of the application package through the config server API.
+//!
+//! See <https://docs.vespa.ai/en/reference/deploy-rest-api-v2.html>.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Error};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use tokio::time::sleep;
+use url::Url;
+
+use crate::utils::{await_and_check_request, body_to_error};
+
+/// How long we wait for the config server to be ready and the application to converge.
+const TIMEOUT: Duration = Duration::from_secs(300);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Path of the (only) application of the self-hosted default tenant.
+const APPLICATION_PATH: [&str; 12] = [
+    "application",
+    "v2",
+    "tenant",
+    "default",
+    "application",
+    "default",
+    "environment",
+    "prod",
+    "region",
+    "default",
+    "instance",
+    "default",
+];
+
+pub(super) struct ConfigServer {
+    client: Client,
+    base_url: Url,
+}
+
+impl ConfigServer {
+    pub(super) fn new(port: u16) -> Result<Self, Error> {
+        Ok(Self {
+            client: Client::new(),
+            base_url: format!("http://localhost:{port}/").parse()?,
+        })
+    }
+
+    fn make_url<'a>(&self, segments: impl IntoIterator<Item = &'a str>) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    fn application_url<'a>(&self, segments: impl IntoIterator<Item = &'a str>) -> Url {
+        self.make_url(APPLICATION_PATH.into_iter().chain(segments))
+    }
+
+    /// Waits until the config server accepts requests.
+    pub(super) async fn wait_until_ready(&self) -> Result<(), Error> {
+        let url = self.make_url(["ApplicationStatus"]);
+        poll(|| async {
+            let ready = self
+                .client
+                .head(url.clone())
+                .send()
+                .await
+                .is_ok_and(|response| response.status().is_success());
+            Ok(ready)
+        })
+        .await
+        .map_err(|error| error.context("config server isn't ready"))
+    }
+
+    /// Returns the schemas of the active application by document type.
+    ///
+    /// Returns no schemas if no application has been deployed yet.
+    pub(super) async fn deployed_schemas(&self) -> Result<BTreeMap<String, String>, Error> {
+        let response = self
+            .client
+            .get(self.application_url(["content", "schemas", ""]))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(BTreeMap::new());
+        }
+        if !response.status().is_success() {
+            return Err(body_to_error(response).await);
+        }
+
+        let mut schemas = BTreeMap::new();
+        for file_url in response.json::<Vec<String>>().await? {
+            // the returned urls use the internal host name of the config server
+            let file_name = file_url.rsplit('/').next().unwrap_or_default();
+            let Some(document_type) = file_name.strip_suffix(".sd") else {
+                continue;
+            };
+            let schema = await_and_check_request(
+                self.client
+                    .get(self.application_url(["content", "schemas", file_name]))
+                    .send(),
+            )
+            .await?
+            .text()
+            .await?;
+            schemas.insert(document_type.to_owned(), schema);
+        }
+        Ok(schemas)
+    }
+
+    /// Returns the `services.xml` of the active application, if any.
+    pub(super) async fn deployed_services(&self) -> Result<Option<String>, Error> {
+        let response = self
+            .client
+            .get(self.application_url(["content", "services.xml"]))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(body_to_error(response).await);
+        }
+        Ok(Some(response.text().await?))
+    }
+
+    /// Prepares and activates the zipped application package.
+    pub(super) async fn deploy(&self, package: Vec<u8>) -> Result<(), Error> {
+        let url = self.make_url([
+            "application",
+            "v2",
+            "tenant",
+            "default",
+            "prepareandactivate",
+        ]);
+        let response = await_and_check_request(
+            self.client
+                .post(url)
+                .header("Content-Type", "application/zip")
+                .body(package)
+                .send(),
+        )
+        .await?
+        .json::<DeployResponse>()
+        .await?;
+        eprintln!("vespa: {}", response.message);
+        Ok(())
+    }
+
+    /// Waits until all services run with the config of the active application.
+    pub(super) async fn wait_for_convergence(&self) -> Result<(), Error> {
+        let url = self.application_url(["serviceconverge"]);
+        poll(|| async {
+            // the endpoint fails while the new config is still being distributed
+            let converged = match await_and_check_request(self.client.get(url.clone()).send()).await
+            {
+                Ok(response) => response.json::<ConvergeResponse>().await?.converged,
+                Err(_) => false,
+            };
+            Ok(converged)
+        })
+        .await
+        .map_err(|error| error.context("vespa application didn't converge"))
+    }
+}
+
+/// Polls `check` until it returns `true` or [`TIMEOUT`] is reached.
+async fn poll<F, Fut>(mut check: F) -> Result<(), Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool, Error>>,
+{
+    let start = Instant::now();
+    loop {
+        if check().await? {
+            return Ok(());
+        }
+        if start.elapsed() > TIMEOUT {
+            bail!("timed out after {}s", TIMEOUT.as_secs());
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[derive(Deserialize)]
+struct DeployResponse {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct ConvergeResponse {
+    converged: bool,
+}
@@ -0,0 +1,271 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Flow-controlled feeding of `/document/v1` operations.
+//!
+//! Vespa signals overload with `429 Too Many Requests` and `503 Service Unavailable`, the
+//! feeder retries such operations with an exponential backoff and adapts the number of
+//! in-flight requests similar to TCP congestion control (additive increase, multiplicative
+//! decrease at most once per window of in-flight requests), like the official
+//! `vespa-feed-client` does.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Error};
+use reqwest::{Client, Method, StatusCode};
+use serde_json::Value;
+use tokio::{task::JoinSet, time::sleep};
+use url::Url;
+
+use crate::{databases::balancer::Balancer, distribution::ingestion::WriteStatistics};
+
+const INITIAL_CONCURRENCY: f64 = 32.;
+const MIN_CONCURRENCY: f64 = 1.;
+const MAX_CONCURRENCY: f64 = 256.;
+const MAX_RETRIES: usize = 10;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+pub(super) struct Operation {
+    pub(super) method: Method,
+    /// The path segments of the document, the node is selected per attempt.
+    pub(super) path: [String; 6],
+    pub(super) body: Option<Value>,
+}
+
+pub(super) struct Feeder {
+    client: Client,
+    nodes: Arc<Balancer<Url>>,
+    state: Mutex<FeedState>,
+}
+
+struct FeedState {
+    /// The current limit of in-flight requests.
+    concurrency: f64,
+    /// Operations which were already in-flight when the concurrency was last decreased,
+    /// their responses don't reflect the decrease yet.
+    in_flight_at_decrease: usize,
+    statistics: WriteStatistics,
+}
+
+impl Feeder {
+    pub(super) fn new(client: Client, nodes: Arc<Balancer<Url>>) -> Self {
+        Self {
+            client,
+            nodes,
+            state: Mutex::new(FeedState {
+                concurrency: INITIAL_CONCURRENCY,
+                in_flight_at_decrease: 0,
+                statistics: WriteStatistics::default(),
+            }),
+        }
+    }
+
+    fn concurrency(&self) -> usize {
+        self.state.lock().unwrap().concurrency as usize
+    }
+
+    fn record(&self, outcome: &Outcome) {
+        let mut state = self.state.lock().unwrap();
+        let statistics = &mut state.statistics;
+        statistics.documents += 1;
+        statistics.retries += outcome.retries;
+        statistics.throttled += outcome.throttled;
+        if let Some((kind, _)) = &outcome.error {
+            statistics.failed += 1;
+            *statistics.errors.entry(kind.clone()).or_default() += 1;
+        }
+
+        state.in_flight_at_decrease = state.in_flight_at_decrease.saturating_sub(1);
+        if outcome.throttled == 0 {
+            state.concurrency += 1. / state.concurrency;
+        } else if state.in_flight_at_decrease == 0 {
+            state.in_flight_at_decrease = state.concurrency as usize;
+            state.concurrency /= 2.;
+        }
+        state.concurrency = state.concurrency.clamp(MIN_CONCURRENCY, MAX_CONCURRENCY);
+    }
+
+    /// Sends all operations, fails if any operation still failed after retrying.
+    pub(super) async fn feed(
+        &self,
+        operations: impl IntoIterator<Item = Operation>,
+    ) -> Result<(), Error> {
+        let mut tasks = JoinSet::new();
+        let mut report = FeedReport::default();
+
+        for operation in operations {
+            while tasks.len() >= self.concurrency() {
+                if let Some(outcome) = tasks.join_next().await {
+                    let outcome = outcome?;
+                    self.record(&outcome);
+                    report.add(outcome);
+                }
+            }
+            tasks.spawn(send(self.client.clone(), self.nodes.clone(), operation));
+        }
+        while let Some(outcome) = tasks.join_next().await {
+            let outcome = outcome?;
+            self.record(&outcome);
+            report.add(outcome);
+        }
+
+        report.check()
+    }
+
+    /// The statistics of all operations fed so far.
+    pub(super) fn statistics(&self) -> WriteStatistics {
+        self.state.lock().unwrap().statistics.clone()
+    }
+}
+
+#[derive(Default)]
+struct Outcome {
+    retries: usize,
+    throttled: usize,
+    /// The kind of error and the error message if the operation failed.
+    error: Option<(String, String)>,
+}
+
+/// Per-document errors of a single [`Feeder::feed`] call.
+#[derive(Default)]
+struct FeedReport {
+    documents: usize,
+    failed: usize,
+    /// Number of failed documents by kind of error.
+    errors: BTreeMap<String, usize>,
+    first_error: Option<String>,
+}
+
+impl FeedReport {
+    fn add(&mut self, outcome: Outcome) {
+        self.documents += 1;
+        if let Some((kind, message)) = outcome.error {
+            self.failed += 1;
+            *self.errors.entry(kind).or_default() += 1;
+            self.first_error.get_or_insert(message);
+        }
+    }
+
+    fn check(self) -> Result<(), Error> {
+        if self.failed > 0 {
+            bail!(
+                "{} of {} documents failed {:?}, first error: {}",
+                self.failed,
+                self.documents,
+                self.errors,
+                self.first_error.unwrap_or_default(),
+            );
+        }
+        Ok(())
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    )
+}
+
+async fn send(client: Client, nodes: Arc<Balancer<Url>>, operation: Operation) -> Outcome {
+    let mut outcome = Outcome::default();
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let node = nodes.pick();
+        let mut url = Url::clone(&node);
+        url.path_segments_mut().unwrap().extend(&operation.path);
+        let mut request = client.request(operation.method.clone(), url);
+        if let Some(body) = &operation.body {
+            request = request.json(body);
+        }
+        let error = match request.send().await {
+            Ok(response) if response.status().is_success() => return outcome,
+            Ok(response) => {
+                let status = response.status();
+                let message = response.text().await.unwrap_or_default();
+                let error = (status.to_string(), format!("{status}: {message}"));
+                if !is_transient(status) {
+                    outcome.error = Some(error);
+                    return outcome;
+                }
+                outcome.throttled += 1;
+                error
+            }
+            Err(error) => {
+                let transient = error.is_timeout() || error.is_connect();
+                let error = ("request error".into(), error.to_string());
+                if !transient {
+                    outcome.error = Some(error);
+                    return outcome;
+                }
+                error
+            }
+        };
+        if outcome.retries >= MAX_RETRIES {
+            outcome.error = Some(error);
+            return outcome;
+        }
+        outcome.retries += 1;
+        drop(node);
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::databases::balancer::Balancing;
+
+    use super::*;
+
+    #[test]
+    fn test_concurrency_adapts_to_throttling() {
+        let nodes = Balancer::new(
+            [(
+                "localhost:8080".into(),
+                "http://localhost:8080/".parse().unwrap(),
+            )],
+            Balancing::Single,
+        )
+        .unwrap();
+        let feeder = Feeder::new(Client::new(), Arc::new(nodes));
+        let throttled = Outcome {
+            throttled: 1,
+            ..Outcome::default()
+        };
+        // the in-flight requests of the window are throttled too, but only decrease once
+        for _ in 0..32 {
+            feeder.record(&throttled);
+        }
+        assert_eq!(feeder.concurrency(), 16);
+        feeder.record(&throttled);
+        assert_eq!(feeder.concurrency(), 8);
+        for _ in 0..9 {
+            feeder.record(&Outcome::default());
+        }
+        assert_eq!(feeder.concurrency(), 9);
+        for _ in 0..100 {
+            feeder.record(&throttled);
+        }
+        assert_eq!(feeder.concurrency(), 1);
+        let statistics = feeder.statistics();
+        assert_eq!(statistics.documents, 142);
+        assert_eq!(statistics.throttled, 133);
+    }
+}
@@ -0,0 +1,719 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Weaviate, the schema and ingestion use the REST API and queries GraphQL.
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Error};
+use async_trait::async_trait;
+use reqwest::{Client, Method, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    benchmarks::{
+        Consistency, ElementType, IndexType, IngestionParameters, PayloadSelection, Quantization,
+        QueryHit, QueryRequest, QueryResponse, QueryVectorDatabase,
+    },
+    databases::{
+        balancer::{Balancer, Balancing, NodeRequests},
+        http_nodes, poll_until, DatabaseName,
+    },
+    distribution::{
+        ingestion::{IngestionInfo, PayloadUpdate, PrepareVectorDatabase},
+        DateFilter, DocumentPayload, LabelFilter, Labels, QueryPayload, StoredDocumentPayload,
+    },
+    math::Distance,
+    utils::{await_and_check_request, body_to_error},
+};
+
+/// Marks that the `ef` of the class wasn't set yet.
+const UNSET_EF: usize = usize::MAX;
+
+/// How long we wait for all nodes to use an updated `ef`.
+const EF_UPDATE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Weaviate {
+    client: Client,
+    nodes: Balancer<Url>,
+    class: String,
+    distance: Distance,
+    /// The `ef` configured for the class by [`QueryVectorDatabase::prepare_queries`].
+    ef: AtomicUsize,
+}
+
+impl Weaviate {
+    pub fn new(
+        node_ids: &[u16],
+        balancing: Balancing,
+        index: &str,
+        distance: Distance,
+    ) -> Result<Self, Error> {
+        let nodes = http_nodes(node_ids, 1, 8180)?;
+
+        Ok(Weaviate {
+            client: Client::new(),
+            nodes: Balancer::new(nodes, balancing)?,
+            class: weaviate_class_name(index),
+            distance,
+            ef: AtomicUsize::new(UNSET_EF),
+        })
+    }
+
+    fn make_url(&self, node: &Url, segments: &[&str]) -> Url {
+        let mut url = node.clone();
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .push("v1")
+            .extend(segments);
+        url
+    }
+
+    async fn json_request(
+        &self,
+        method: Method,
+        segments: &[&str],
+        data: &impl Serialize,
+    ) -> Result<Response, Error> {
+        let node = self.nodes.pick();
+        let fut = self
+            .client
+            .request(method, self.make_url(&node, segments))
+            .json(data)
+            .send();
+
+        await_and_check_request(fut).await
+    }
+
+    /// Returns `None` if the class doesn't exist.
+    async fn class_schema(&self) -> Result<Option<Value>, Error> {
+        let node = self.nodes.pick();
+        self.class_schema_of(&node).await
+    }
+
+    /// Like [`Weaviate::class_schema`] but as known to the given node.
+    async fn class_schema_of(&self, node: &Url) -> Result<Option<Value>, Error> {
+        let response = self
+            .client
+            .get(self.make_url(node, &["schema", &self.class]))
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.json().await?)),
+            _ => Err(body_to_error(response).await),
+        }
+    }
+
+    async fn graphql<T>(&self, query: String, timeout: Option<Duration>) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let node = self.nodes.pick();
+        let mut request = self
+            .client
+            .post(self.make_url(&node, &["graphql"]))
+            .json(&json!({ "query": query }));
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let GraphQlResponse { data, errors } = await_and_check_request(request.send())
+            .await?
+            .json::<GraphQlResponse<T>>()
+            .await?;
+        // graphql answers errors with `200 OK`
+        if let Some(error) = errors.first() {
+            bail!("weaviate graphql error: {}", error.message);
+        }
+        data.ok_or_else(|| anyhow!("weaviate graphql response without data"))
+    }
+
+    async fn count(&self) -> Result<u64, Error> {
+        let data: Value = self
+            .graphql(
+                format!(
+                    "{{ Aggregate {{ {} {{ meta {{ count }} }} }} }}",
+                    self.class
+                ),
+                None,
+            )
+            .await?;
+        data["Aggregate"][&self.class][0]["meta"]["count"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("unexpected aggregate response: {data}"))
+    }
+
+    /// The `ef` of weaviate is a setting of the class and not of the query.
+    ///
+    /// Only returns once every node uses the new `ef`.
+    async fn set_ef(&self, ef: usize) -> Result<(), Error> {
+        let mut schema = self
+            .class_schema()
+            .await?
+            .ok_or_else(|| anyhow!("class {} doesn't exist", self.class))?;
+        if schema["vectorIndexConfig"]["ef"] != ef {
+            schema["vectorIndexConfig"]["ef"] = json!(ef);
+            self.json_request(Method::PUT, &["schema", &self.class], &schema)
+                .await?;
+        }
+        poll_until(
+            "update the ef on all nodes",
+            EF_UPDATE_TIMEOUT,
+            1,
+            || async {
+                for node in self.nodes.endpoints() {
+                    let schema = self.class_schema_of(node).await?;
+                    if schema.is_none_or(|schema| schema["vectorIndexConfig"]["ef"] != ef) {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            },
+        )
+        .await?;
+        self.ef.store(ef, Ordering::Release);
+        Ok(())
+    }
+
+    /// The number of elements of the vectors of the class, `None` if it has no objects.
+    async fn vector_dimensions(&self) -> Result<Option<usize>, Error> {
+        let node = self.nodes.pick();
+        let mut url = self.make_url(&node, &["objects"]);
+        url.query_pairs_mut()
+            .append_pair("class", &self.class)
+            .append_pair("limit", "1")
+            .append_pair("include", "vector");
+        let Objects { objects } = await_and_check_request(self.client.get(url).send())
+            .await?
+            .json()
+            .await?;
+        Ok(objects.first().map(|object| object.vector.len()))
+    }
+
+    /// Checks if all shards of the class are indexed.
+    async fn is_fully_indexed(&self) -> Result<bool, Error> {
+        let node = self.nodes.pick();
+        let mut url = self.make_url(&node, &["nodes", &self.class]);
+        url.query_pairs_mut().append_pair("output", "verbose");
+        let NodesStatus { nodes } = await_and_check_request(self.client.get(url).send())
+            .await?
+            .json()
+            .await?;
+        Ok(nodes
+            .iter()
+            .flat_map(|node| &node.shards)
+            .all(|shard| shard.vector_queue_length == 0 && shard.vector_indexing_status == "READY"))
+    }
+}
+
+impl DatabaseName for Weaviate {
+    fn name(&self) -> &str {
+        "weaviate"
+    }
+
+    fn node_requests(&self) -> NodeRequests {
+        self.nodes.node_requests()
+    }
+}
+
+/// Class names have to start with an uppercase letter and can't contain `.`/`-`.
+fn weaviate_class_name(index: &str) -> String {
+    let mut chars = index.chars();
+    chars
+        .next()
+        .map(|first| first.to_ascii_uppercase())
+        .into_iter()
+        .chain(chars)
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct GraphQlResponse<T> {
+    data: Option<T>,
+    #[serde(default)]
+    errors: Vec<GraphQlError>,
+}
+
+#[derive(Deserialize)]
+struct GraphQlError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct NodesStatus {
+    nodes: Vec<NodeStatus>,
+}
+
+#[derive(Deserialize)]
+struct NodeStatus {
+    #[serde(default)]
+    shards: Vec<ShardStatus>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ShardStatus {
+    vector_queue_length: u64,
+    vector_indexing_status: String,
+}
+
+#[derive(Deserialize)]
+struct Objects {
+    #[serde(default)]
+    objects: Vec<Object>,
+}
+
+#[derive(Deserialize)]
+struct Object {
+    #[serde(default)]
+    vector: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct BatchResult {
+    #[serde(default)]
+    result: Option<BatchResultErrors>,
+}
+
+#[derive(Deserialize)]
+struct BatchResultErrors {
+    #[serde(default)]
+    errors: Option<BatchErrors>,
+}
+
+#[derive(Deserialize)]
+struct BatchErrors {
+    error: Vec<GraphQlError>,
+}
+
+#[derive(Deserialize)]
+struct Hit {
+    publication_date: Option<i64>,
+    // empty arrays are returned as `null`
+    authors: Option<Vec<String>>,
+    tags: Option<Vec<String>>,
+    link: Option<String>,
+    #[serde(rename = "_additional")]
+    additional: HitAdditional,
+}
+
+#[derive(Deserialize)]
+struct HitAdditional {
+    id: Uuid,
+    distance: f32,
+}
+
+#[async_trait(?Send)]
+impl PrepareVectorDatabase for Weaviate {
+    async fn initialize(
+        &self,
+        parameters: &IngestionParameters,
+        dimensions: usize,
+    ) -> Result<bool, Error> {
+        if parameters.element_type != ElementType::Float
+            || parameters.quantization != Quantization::None
+        {
+            bail!("weaviate doesn't support {parameters}");
+        }
+        if self.class_schema().await?.is_some() {
+            // weaviate takes the dimensions from the first imported object
+            if let Some(existing) = self.vector_dimensions().await? {
+                if existing != dimensions {
+                    bail!(
+                        "{} already exists with {existing} instead of {dimensions} dimensions, recreate the volumes to start from scratch",
+                        self.class,
+                    );
+                }
+            }
+            return Ok(self.count().await? == 0);
+        }
+
+        let (index_type, index_config) = match parameters.index_type {
+            IndexType::Hnsw => (
+                "hnsw",
+                json!({
+                    "distance": weaviate_distance(self.distance),
+                    "maxConnections": parameters.m,
+                    "efConstruction": parameters.ef_construct,
+                }),
+            ),
+            IndexType::Flat => (
+                "flat",
+                json!({ "distance": weaviate_distance(self.distance) }),
+            ),
+        };
+        let label_property = |name: &str| {
+            json!({
+                "name": name,
+                "dataType": ["text[]"],
+                // match the uuids as a whole
+                "tokenization": "field",
+                "indexFilterable": true,
+                "indexSearchable": false,
+            })
+        };
+        self.json_request(
+            Method::POST,
+            &["schema"],
+            &json!({
+                "class": self.class,
+                // the vectors are provided
+                "vectorizer": "none",
+                "vectorIndexType": index_type,
+                "vectorIndexConfig": index_config,
+                "properties": [
+                    {
+                        "name": "publication_date",
+                        "dataType": ["int"],
+                        "indexFilterable": true,
+                        "indexRangeFilters": true,
+                    },
+                    label_property("authors"),
+                    label_property("tags"),
+                    {
+                        "name": "link",
+                        "dataType": ["text"],
+                        "indexFilterable": false,
+                        "indexSearchable": false,
+                    },
+                ],
+                // similar to the other providers 3 shards with 2 replicas
+                "shardingConfig": { "desiredCount": 3 },
+                "replicationConfig": { "factor": 2 },
+            }),
+        )
+        .await?;
+
+        Ok(true)
+    }
+
+    async fn prepare_mass_ingestion(&self) -> Result<(), Error> {
+        // objects are indexed on import, there is nothing to disable
+        Ok(())
+    }
+
+    async fn finish_mass_ingestion(&self, target_max_time: Duration) -> Result<(), Error> {
+        poll_until("finish mass ingestion", target_max_time, 1, || {
+            self.is_fully_indexed()
+        })
+        .await
+    }
+
+    async fn ingest_batch(
+        &self,
+        batch: impl IntoIterator<Item = IngestionInfo<'_>>,
+    ) -> Result<(), Error> {
+        let objects = batch
+            .into_iter()
+            .map(|info| {
+                json!({
+                    "class": self.class,
+                    "id": info.id,
+                    "vector": info.vector,
+                    "properties": weaviate_properties(info.payload),
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = self
+            .json_request(
+                Method::POST,
+                &["batch", "objects"],
+                &json!({ "objects": objects }),
+            )
+            .await?
+            .json::<Vec<BatchResult>>()
+            .await?;
+        // failed objects don't fail the request
+        let error = results
+            .into_iter()
+            .filter_map(|result| result.result?.errors)
+            .flat_map(|errors| errors.error)
+            .next();
+        if let Some(error) = error {
+            bail!("weaviate batch import failed: {}", error.message);
+        }
+        Ok(())
+    }
+
+    async fn update_payloads(
+        &self,
+        batch: impl IntoIterator<Item = PayloadUpdate<'_>>,
+    ) -> Result<(), Error> {
+        // there is no batch update, merges only replace the given properties
+        for PayloadUpdate { id, payload } in batch {
+            self.json_request(
+                Method::PATCH,
+                &["objects", &self.class, &id.to_string()],
+                &json!({
+                    "class": self.class,
+                    "properties": weaviate_properties(payload),
+                }),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_batch(&self, ids: &[Uuid]) -> Result<(), Error> {
+        self.json_request(
+            Method::DELETE,
+            &["batch", "objects"],
+            &json!({
+                "match": {
+                    "class": self.class,
+                    "where": {
+                        "path": ["id"],
+                        "operator": "ContainsAny",
+                        "valueTextArray": ids,
+                    },
+                },
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl QueryVectorDatabase for Weaviate {
+    async fn query(&self, request: &QueryRequest<'_>) -> Result<QueryResponse, Error> {
+        match self.ef.load(Ordering::Acquire) {
+            ef if ef == request.ef => {}
+            UNSET_EF => bail!(
+                "the ef of {} isn't set, prepare the queries first",
+                self.class
+            ),
+            ef => bail!(
+                "{} is prepared for ef {ef}, got a query with ef {}",
+                self.class,
+                request.ef
+            ),
+        }
+        let return_payload = request.payload == PayloadSelection::All;
+        let data: Value = self
+            .graphql(weaviate_query(&self.class, request)?, request.timeout)
+            .await?;
+        let hits = serde_json::from_value::<Vec<Hit>>(data["Get"][&self.class].clone())?;
+
+        let hits = hits
+            .into_iter()
+            .map(|hit| {
+                let payload = return_payload
+                    .then(|| {
+                        StoredDocumentPayload {
+                            publication_date: hit
+                                .publication_date
+                                .ok_or_else(|| anyhow!("hit without publication_date"))?,
+                            authors: hit.authors.unwrap_or_default(),
+                            tags: hit.tags.unwrap_or_default(),
+                            link: hit.link.unwrap_or_default(),
+                        }
+                        .try_into()
+                    })
+                    .transpose()?;
+                Ok(QueryHit {
+                    id: hit.additional.id,
+                    score: hit.additional.distance,
+                    payload,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(QueryResponse { hits, took: None })
+    }
+
+    async fn prepare_queries(&self, request: &QueryRequest<'_>) -> Result<(), Error> {
+        self.set_ef(request.ef).await
+    }
+
+    fn score_to_distance(&self, score: f32) -> f32 {
+        match self.distance {
+            // `l2-squared` is the squared euclidean distance
+            Distance::Euclidean => score.max(0.).sqrt(),
+            // `cosine` is `1 - similarity` and `dot` the negated dot product
+            Distance::Cosine | Distance::DotProduct => score,
+        }
+    }
+}
+
+fn weaviate_distance(distance: Distance) -> &'static str {
+    match distance {
+        Distance::Euclidean => "l2-squared",
+        Distance::Cosine => "cosine",
+        Distance::DotProduct => "dot",
+    }
+}
+
+fn weaviate_consistency(consistency: Consistency) -> Result<&'static str, Error> {
+    Ok(match consistency {
+        Consistency::All => "ALL",
+        Consistency::Quorum => "QUORUM",
+        Consistency::One => "ONE",
+        // weaviate has no way to require the answers of the replicas to agree
+        Consistency::Majority => bail!("weaviate doesn't support consistency {consistency:?}"),
+    })
+}
+
+fn weaviate_properties(payload: &DocumentPayload) -> Value {
+    json!({
+        "publication_date": payload.publication_date.timestamp(),
+        "authors": payload.authors.to_uuid_string_vec(),
+        "tags": payload.tags.to_uuid_string_vec(),
+        "link": &payload.link,
+    })
+}
+
+fn weaviate_query(class: &str, request: &QueryRequest<'_>) -> Result<String, Error> {
+    let mut arguments = vec![
+        format!("nearVector: {{ vector: {} }}", json!(request.vector)),
+        format!("limit: {}", request.k),
+        format!(
+            "consistencyLevel: {}",
+            weaviate_consistency(request.consistency)?
+        ),
+    ];
+    if let Some(filter) = request.filter.and_then(weaviate_where) {
+        arguments.push(format!("where: {filter}"));
+    }
+    let payload = match request.payload {
+        PayloadSelection::None => "",
+        PayloadSelection::All => "publication_date authors tags link ",
+    };
+    Ok(format!(
+        "{{ Get {{ {class}({arguments}) {{ {payload}_additional {{ id distance }} }} }} }}",
+        arguments = arguments.join(", "),
+    ))
+}
+
+fn weaviate_where(
+    QueryPayload {
+        publication_date,
+        authors,
+        tags,
+    }: &QueryPayload,
+) -> Option<String> {
+    let mut operands = Vec::new();
+    weaviate_date_filter("publication_date", publication_date, &mut operands);
+    weaviate_label_filter("authors", authors, &mut operands);
+    weaviate_label_filter("tags", tags, &mut operands);
+    match operands.len() {
+        0 => None,
+        1 => operands.pop(),
+        _ => Some(format!(
+            "{{ operator: And, operands: [{}] }}",
+            operands.join(", ")
+        )),
+    }
+}
+
+fn weaviate_date_filter(
+    field: &str,
+    DateFilter {
+        lower_bound,
+        upper_bound,
+    }: &DateFilter,
+    operands: &mut Vec<String>,
+) {
+    for (bound, operator) in [
+        (lower_bound, "GreaterThanEqual"),
+        (upper_bound, "LessThanEqual"),
+    ] {
+        if let Some(bound) = bound {
+            operands.push(format!(
+                "{{ path: [\"{field}\"], operator: {operator}, valueInt: {} }}",
+                bound.timestamp()
+            ));
+        }
+    }
+}
+
+fn weaviate_label_filter(
+    field: &str,
+    LabelFilter { include, exclude }: &LabelFilter,
+    operands: &mut Vec<String>,
+) {
+    let contains_any = |labels: &Labels| {
+        format!(
+            "{{ path: [\"{field}\"], operator: ContainsAny, valueText: {} }}",
+            json!(labels.to_uuid_string_vec())
+        )
+    };
+    if !include.is_empty() {
+        operands.push(contains_any(include));
+    }
+    if !exclude.is_empty() {
+        operands.push(format!(
+            "{{ operator: Not, operands: [{}] }}",
+            contains_any(exclude)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::databases::test_fixtures::{filter, filtered_request, request};
+
+    use super::*;
+
+    #[test]
+    fn test_query_building() {
+        assert_eq!(
+            weaviate_class_name("content_m16_ef100_flat"),
+            "Content_m16_ef100_flat"
+        );
+
+        let filter = filter();
+        let query = weaviate_query("Content", &filtered_request(&filter)).unwrap();
+        assert_eq!(
+            query,
+            concat!(
+                "{ Get { Content(nearVector: { vector: [2.0,4.0] }, limit: 10, consistencyLevel: QUORUM, where: { operator: And, operands: [",
+                r#"{ path: ["publication_date"], operator: GreaterThanEqual, valueInt: 3661 }, "#,
+                r#"{ path: ["publication_date"], operator: LessThanEqual, valueInt: 3662 }, "#,
+                r#"{ path: ["authors"], operator: ContainsAny, valueText: ["00000000-0000-400c-8000-00000000000c"] }, "#,
+                r#"{ operator: Not, operands: [{ path: ["tags"], operator: ContainsAny, valueText: ["00000000-0000-4003-8000-000000000003","00000000-0000-4004-8000-000000000004"] }] }"#,
+                "] }) { publication_date authors tags link _additional { id distance } } } }",
+            )
+        );
+
+        let query = weaviate_query(
+            "Content",
+            &QueryRequest {
+                consistency: Consistency::One,
+                ..request()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            "{ Get { Content(nearVector: { vector: [2.0,4.0] }, limit: 10, consistencyLevel: ONE) { _additional { id distance } } } }"
+        );
+        assert!(weaviate_query(
+            "Content",
+            &QueryRequest {
+                consistency: Consistency::Majority,
+                ..request()
+            },
+        )
+        .is_err());
+    }
+}
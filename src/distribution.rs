@@ -16,6 +16,7 @@ use std::{fs, path::Path};
 
 mod choice;
 mod date;
+pub mod ground_truth;
 pub mod ids;
 pub mod ingestion;
 mod label;
@@ -26,12 +27,14 @@ pub use self::{
     date::Filter as DateFilter,
     label::{Filter as LabelFilter, Label, Labels},
 };
-use anyhow::Error;
-use chrono::{DateTime, Utc};
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, TimeZone, Utc};
 use rand::{distributions::DistString, rngs::StdRng, Rng};
 use rand_distr::{Alphanumeric, Distribution};
 use serde::{Deserialize, Serialize};
 
+use crate::resources::{dump_bincode, ResolvedPaths};
+
 #[derive(Deserialize)]
 pub struct Distributions {
     rng: rng::RngBuilder,
@@ -67,19 +70,96 @@ impl Distributions {
             tags: self.tags.sample(rng),
         }
     }
+
+    /// Generates the document and query payload files for the vectors file.
+    pub fn generate_payload_files(&self, paths: &ResolvedPaths) -> Result<(), Error> {
+        paths.check_only_vectors_file_exists()?;
+
+        let file = hdf5::File::open(&paths.vectors_file)?;
+        let nr_train = file.dataset("train")?.shape()[0];
+        let nr_test = file.dataset("test")?.shape()[0];
+        file.close()?;
+
+        let rng = &mut self.create_rng();
+
+        generate_payloads(nr_train, &paths.document_payload_file, "document", || {
+            self.sample_document_payload(rng)
+        })?;
+        generate_payloads(nr_test, &paths.query_payload_file, "query", || {
+            self.sample_query_payload(rng)
+        })?;
+
+        Ok(())
+    }
+}
+
+fn generate_payloads<S>(
+    number: usize,
+    file: &Path,
+    hint: &str,
+    mut genfn: impl FnMut() -> S,
+) -> Result<(), Error>
+where
+    S: Serialize,
+{
+    eprintln!("Starting {hint} payload generation (x{number})");
+    let payloads: Vec<_> = (0..number).map(|_| genfn()).collect();
+
+    eprintln!("Writing {hint} payload file");
+    dump_bincode(file, &payloads)?;
+    Ok(())
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DocumentPayload {
+    /// For simplicity the databases store a unix timestamp instead of a proper date.
     pub publication_date: DateTime<Utc>,
     pub authors: Labels,
     pub tags: Labels,
     pub link: String,
 }
 
+/// A document payload as stored in the databases.
+///
+/// Labels are stored as uuid strings and the publication date as timestamp.
+#[derive(Debug, Deserialize)]
+pub struct StoredDocumentPayload {
+    pub publication_date: i64,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub link: String,
+}
+
+impl TryFrom<StoredDocumentPayload> for DocumentPayload {
+    type Error = Error;
+
+    fn try_from(stored: StoredDocumentPayload) -> Result<Self, Self::Error> {
+        Ok(Self {
+            publication_date: Utc
+                .timestamp_opt(stored.publication_date, 0)
+                .single()
+                .ok_or_else(|| anyhow!("invalid timestamp: {}", stored.publication_date))?,
+            authors: Labels::from_uuid_strings(stored.authors)?,
+            tags: Labels::from_uuid_strings(stored.tags)?,
+            link: stored.link,
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct QueryPayload {
     pub publication_date: DateFilter,
     pub authors: LabelFilter,
     pub tags: LabelFilter,
 }
+
+impl QueryPayload {
+    /// Checks if the document passes all filters.
+    pub fn matches(&self, document: &DocumentPayload) -> bool {
+        self.publication_date.matches(document.publication_date)
+            && self.authors.matches(&document.authors)
+            && self.tags.matches(&document.tags)
+    }
+}
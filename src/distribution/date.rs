@@ -30,6 +30,14 @@ pub struct Filter {
     pub upper_bound: Option<DateTime<Utc>>,
 }
 
+impl Filter {
+    /// Checks if the date is in the (inclusive) bounds.
+    pub fn matches(&self, date: DateTime<Utc>) -> bool {
+        self.lower_bound.is_none_or(|bound| bound <= date)
+            && self.upper_bound.is_none_or(|bound| date <= bound)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(try_from = "DatePopulationSerdeProxy")]
 pub(super) struct Population {
@@ -140,7 +148,7 @@ impl From<RangeDistribution> for DateRangeDistribution {
 impl Distribution<DateTime<Utc>> for DateRangeDistribution {
     fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> DateTime<Utc> {
         let timestamp = self.0.sample(rng);
-        // assumption: RangeDistribution is [0;...), if the assumption brakes we clamp
-        DateTime::from_timestamp(timestamp.max(0) as _, 0).unwrap()
+        // RangeDistribution samples u64 so the timestamp is always >= 0
+        DateTime::from_timestamp(timestamp as _, 0).unwrap()
     }
 }
@@ -0,0 +1,210 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{cmp::Ordering, collections::BinaryHeap, num::NonZeroUsize, thread};
+
+use anyhow::{anyhow, Error};
+
+use crate::{
+    math::Distance,
+    resources::{dump_bincode, load_bincode, load_vectors, ResolvedPaths},
+};
+
+use super::{DocumentPayload, QueryPayload};
+
+/// How many neighbors are stored per query, same as in the ann-benchmarks datasets.
+pub const GROUND_TRUTH_NEIGHBORS: usize = 100;
+
+/// Brute forces the exact nearest neighbors of each test query among the train vectors
+/// matching the query payload and stores them in `paths.filtered_neighbors_file`.
+///
+/// Queries with less than [`GROUND_TRUTH_NEIGHBORS`] matching documents have less neighbors.
+pub fn generate_filtered_ground_truth(
+    paths: &ResolvedPaths,
+    distance: Distance,
+) -> Result<(), Error> {
+    paths.check_files_exists()?;
+
+    eprintln!("load data");
+    let documents: Vec<DocumentPayload> = load_bincode(&paths.document_payload_file)?;
+    let queries: Vec<QueryPayload> = load_bincode(&paths.query_payload_file)?;
+    let train = load_vectors(&paths.vectors_file, "train")?;
+    let test = load_vectors(&paths.vectors_file, "test")?;
+    assert!(documents.len() == train.len());
+    assert!(queries.len() == test.len());
+
+    eprintln!("brute force filtered neighbors (x{})", test.len());
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let chunk_size = test.len().div_ceil(threads).max(1);
+    let (train, documents) = (&train, &documents);
+    let neighbors = thread::scope(|scope| {
+        let handles = test
+            .chunks(chunk_size)
+            .zip(queries.chunks(chunk_size))
+            .map(|(vectors, payloads)| {
+                scope.spawn(move || {
+                    vectors
+                        .iter()
+                        .zip(payloads)
+                        .map(|(vector, payload)| {
+                            filtered_neighbors(
+                                distance,
+                                vector,
+                                payload,
+                                train,
+                                documents,
+                                GROUND_TRUTH_NEIGHBORS,
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|_| anyhow!("ground truth thread panicked"))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    })?
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    eprintln!("writing filtered neighbors file");
+    dump_bincode(&paths.filtered_neighbors_file, &neighbors)?;
+    Ok(())
+}
+
+pub fn load_filtered_neighbors(paths: &ResolvedPaths) -> Result<Vec<Vec<usize>>, Error> {
+    load_bincode(&paths.filtered_neighbors_file)
+}
+
+/// Returns the indices of the `k` nearest documents matching the payload, nearest first.
+fn filtered_neighbors(
+    distance: Distance,
+    query: &[f32],
+    payload: &QueryPayload,
+    vectors: &[Vec<f32>],
+    documents: &[DocumentPayload],
+    k: usize,
+) -> Vec<usize> {
+    // max-heap, so the farthest of the current top-k is on top
+    let mut heap = BinaryHeap::with_capacity(k + 1);
+    for (idx, (vector, document)) in vectors.iter().zip(documents).enumerate() {
+        if !payload.matches(document) {
+            continue;
+        }
+        heap.push(Candidate {
+            distance: distance.distance(query, vector),
+            idx,
+        });
+        if heap.len() > k {
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|candidate| candidate.idx)
+        .collect()
+}
+
+struct Candidate {
+    distance: f32,
+    idx: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.idx.cmp(&other.idx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::distribution::{DateFilter, Label, LabelFilter, Labels};
+
+    use super::*;
+
+    fn document(year: i32, authors: &[u64]) -> DocumentPayload {
+        DocumentPayload {
+            publication_date: Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).unwrap(),
+            authors: Labels(authors.iter().copied().map(Label).collect()),
+            tags: Labels::default(),
+            link: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_filtered_neighbors_only_contains_matching_documents() {
+        let vectors = [[0.], [1.], [2.], [3.], [4.]].map(|v| v.to_vec());
+        let documents = [
+            document(2000, &[1]),
+            document(2010, &[1, 2]),
+            document(2010, &[3]),
+            document(2020, &[1]),
+            document(2020, &[2]),
+        ];
+        let payload = QueryPayload {
+            publication_date: DateFilter {
+                lower_bound: Some(Utc.with_ymd_and_hms(2005, 1, 1, 0, 0, 0).unwrap()),
+                upper_bound: None,
+            },
+            authors: LabelFilter {
+                include: Labels(vec![Label(1), Label(2)]),
+                exclude: Labels(vec![Label(3)]),
+            },
+            tags: LabelFilter::default(),
+        };
+
+        let neighbors = filtered_neighbors(
+            Distance::Euclidean,
+            &[3.2],
+            &payload,
+            &vectors,
+            &documents,
+            2,
+        );
+        assert_eq!(neighbors, [3, 4]);
+        let neighbors = filtered_neighbors(
+            Distance::Euclidean,
+            &[0.],
+            &payload,
+            &vectors,
+            &documents,
+            10,
+        );
+        assert_eq!(neighbors, [1, 3, 4]);
+    }
+}
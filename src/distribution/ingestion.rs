@@ -12,21 +12,26 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::time::{Duration, Instant};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
 
 use anyhow::Error;
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::runtime::Handle;
 use uuid::Uuid;
 
 use crate::{
+    benchmarks::IngestionParameters,
     consts::{DOCKER_LIMIT_CPUS, DOCKER_LIMIT_MEMORY},
     databases::DatabaseName,
     distribution::{ids::index_to_fake_uuid, DocumentPayload},
     docker::DockerStatScanner,
     math::WelfordOnlineAlgorithm,
-    resources::{load_bincode, load_vectors, ResolvedPaths, ResourceWriter},
+    resources::{load_bincode, load_dimensions, load_vectors, ResolvedPaths, ResourceWriter},
 };
 
 pub struct IngestionInfo<'a> {
@@ -35,15 +40,49 @@ pub struct IngestionInfo<'a> {
     pub payload: &'a DocumentPayload,
 }
 
+pub struct PayloadUpdate<'a> {
+    pub id: Uuid,
+    pub payload: &'a DocumentPayload,
+}
+
+/// Statistics of the writes of databases which send and retry each document separately.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WriteStatistics {
+    pub documents: usize,
+    pub retries: usize,
+    /// Responses asking to slow down.
+    pub throttled: usize,
+    pub failed: usize,
+    /// Number of failed documents by kind of error.
+    pub errors: BTreeMap<String, usize>,
+}
+
 #[async_trait(?Send)]
 pub trait PrepareVectorDatabase: DatabaseName {
-    async fn initialize(&self) -> Result<bool, Error>;
+    /// Creates the collection/index for given parameters and vectors with `dimensions` elements,
+    /// returns `true` if it still needs ingestion.
+    async fn initialize(
+        &self,
+        parameters: &IngestionParameters,
+        dimensions: usize,
+    ) -> Result<bool, Error>;
     async fn prepare_mass_ingestion(&self) -> Result<(), Error>;
     async fn finish_mass_ingestion(&self, target_max_time: Duration) -> Result<(), Error>;
+    /// Inserts the documents, existing documents with the same id are replaced.
     async fn ingest_batch(
         &self,
         batch: impl IntoIterator<Item = IngestionInfo<'_>>,
     ) -> Result<(), Error>;
+    /// Replaces the payload of existing documents, their vectors are kept.
+    async fn update_payloads(
+        &self,
+        batch: impl IntoIterator<Item = PayloadUpdate<'_>>,
+    ) -> Result<(), Error>;
+    async fn delete_batch(&self, ids: &[Uuid]) -> Result<(), Error>;
+    /// The statistics of all writes so far, if the database tracks them.
+    fn write_statistics(&self) -> Option<WriteStatistics> {
+        None
+    }
 }
 
 pub fn load_ingestion_data(
@@ -65,12 +104,16 @@ pub async fn ingest_database(
     writer: &ResourceWriter,
     paths: &ResolvedPaths,
     database: &impl PrepareVectorDatabase,
+    parameters: &IngestionParameters,
 ) -> Result<(), Error> {
-    let writer = writer.sub_writer("ingestion")?;
+    let writer = writer
+        .sub_writer("ingestion")?
+        .sub_writer(parameters.to_string())?;
     writer.write_file("paths.json", paths)?;
 
     eprintln!("initialize database");
-    let needs_ingestion = database.initialize().await?;
+    let dimensions = load_dimensions(&paths.vectors_file, "train")?;
+    let needs_ingestion = database.initialize(parameters, dimensions).await?;
     if !needs_ingestion {
         return Ok(());
     }
@@ -82,15 +125,22 @@ pub async fn ingest_database(
         "source.json",
         &json!({
             "dataset": paths.dataset_name()?,
+            "index": parameters.index_name(),
+            "m": parameters.m,
+            "ef_construct": parameters.ef_construct,
+            "index_type": parameters.index_type,
+            "element_type": parameters.element_type,
+            "quantization": parameters.quantization,
             "documents": nr_documents,
-            "vector_size":  vectors.get(0).map_or(0, Vec::len),
+            "vector_size": dimensions,
             "ingestion_batch_size": BATCH_SIZE,
             "limit_cpus": *DOCKER_LIMIT_CPUS,
             "limit_memory": *DOCKER_LIMIT_MEMORY,
         }),
     )?;
 
-    let docker_stats = DockerStatScanner::start(&Handle::current(), database.name())?;
+    let docker_stats = DockerStatScanner::start(&Handle::current(), database.service())?;
+    let node_requests = database.node_requests();
     let mut times = WelfordOnlineAlgorithm::new();
 
     let start = Instant::now();
@@ -99,37 +149,47 @@ pub async fn ingest_database(
         eprintln!("time spend ingesting: {duration:.2}s");
     });
 
-    eprintln!("prepare ingestion");
-    database.prepare_mass_ingestion().await?;
-    let mut vectors = vectors.iter().zip(payloads.iter()).enumerate().peekable();
-    eprintln!("ingestion started");
-    let mut nr_ingested_entries = 0;
-    while vectors.peek().is_some() {
-        let batch_start = Instant::now();
-        database
-            .ingest_batch(
-                vectors
-                    .by_ref()
-                    .take(BATCH_SIZE)
-                    .map(|(idx, (vector, payload))| IngestionInfo {
-                        id: index_to_fake_uuid(idx as _),
-                        vector,
-                        payload,
-                    }),
-            )
-            .await?;
-        nr_ingested_entries += BATCH_SIZE;
-        let duration = Instant::now().duration_since(batch_start).as_secs_f64();
-        times.update(duration);
+    let ingested =
+        async {
+            eprintln!("prepare ingestion");
+            database.prepare_mass_ingestion().await?;
+            let mut vectors = vectors.iter().zip(payloads.iter()).enumerate().peekable();
+            eprintln!("ingestion started");
+            let mut nr_ingested_entries = 0;
+            while vectors.peek().is_some() {
+                let batch_start = Instant::now();
+                database
+                    .ingest_batch(vectors.by_ref().take(BATCH_SIZE).map(
+                        |(idx, (vector, payload))| IngestionInfo {
+                            id: index_to_fake_uuid(idx as _),
+                            vector,
+                            payload,
+                        },
+                    ))
+                    .await?;
+                nr_ingested_entries += BATCH_SIZE;
+                let duration = Instant::now().duration_since(batch_start).as_secs_f64();
+                times.update(duration);
+                eprintln!(
+                    "progress: {:.2}%",
+                    nr_ingested_entries as f32 / nr_documents as f32 * 100.
+                );
+            }
+            eprintln!("finish uploading, waiting for index to be ready");
+            database
+                .finish_mass_ingestion(Duration::from_secs(900))
+                .await
+        }
+        .await;
+    // the statistics are also written if the ingestion failed to help finding out why
+    if let Some(statistics) = database.write_statistics() {
         eprintln!(
-            "progress: {:.2}%",
-            nr_ingested_entries as f32 / nr_documents as f32 * 100.
+            "{} documents, {} retries, {} throttled, {} failed",
+            statistics.documents, statistics.retries, statistics.throttled, statistics.failed,
         );
+        writer.write_file("write_statistics.json", &statistics)?;
     }
-    eprintln!("finish uploading, waiting for index to be ready");
-    database
-        .finish_mass_ingestion(Duration::from_secs(900))
-        .await?;
+    ingested?;
     let duration = Instant::now().duration_since(start).as_secs_f32();
     eprintln!("full ingestion duration: {:.4}s", duration);
 
@@ -143,6 +203,10 @@ pub async fn ingest_database(
 
     let stats = docker_stats.stop().await?;
     writer.write_file("docker_stats.json", &stats)?;
+    writer.write_file(
+        "node_requests.json",
+        &database.node_requests().since(&node_requests),
+    )?;
     Ok(())
 }
 
@@ -12,7 +12,10 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::fmt::{Debug, Display};
+use std::{
+    fmt::{Debug, Display},
+    str::FromStr,
+};
 
 use anyhow::{bail, Error};
 use derive_more::Deref;
@@ -20,7 +23,12 @@ use rand::{prelude::Distribution, Rng};
 use rand_distr::WeightedAliasIndex;
 use serde::{Deserialize, Serialize};
 
-use super::{choice::IdxChoiceDistribution, ids::index_to_fake_uuid};
+use crate::consts::MAX_LABELS_PER_DOCUMENT;
+
+use super::{
+    choice::IdxChoiceDistribution,
+    ids::{fake_uuid_to_index, index_to_fake_uuid},
+};
 
 #[derive(PartialEq, Serialize, Deserialize)]
 #[repr(transparent)]
@@ -32,6 +40,14 @@ impl Display for Label {
     }
 }
 
+impl FromStr for Label {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Label(fake_uuid_to_index(s.parse()?)))
+    }
+}
+
 impl Debug for Label {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self.to_string())
@@ -46,6 +62,16 @@ impl Labels {
     pub fn to_uuid_string_vec(&self) -> Vec<String> {
         self.iter().map(Label::to_string).collect()
     }
+
+    pub fn from_uuid_strings(
+        strings: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Self, Error> {
+        strings
+            .into_iter()
+            .map(|string| string.as_ref().parse())
+            .collect::<Result<_, _>>()
+            .map(Labels)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -101,6 +127,16 @@ pub struct Filter {
     pub exclude: Labels,
 }
 
+impl Filter {
+    /// Checks if any included label and none of the excluded labels are present.
+    ///
+    /// An empty `include` doesn't filter anything.
+    pub fn matches(&self, labels: &Labels) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|label| labels.contains(label)))
+            && !self.exclude.iter().any(|label| labels.contains(label))
+    }
+}
+
 impl Distribution<Filter> for Population {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Filter {
         let nr_include = self.filters.include_count_distribution.sample(rng);
@@ -147,6 +183,11 @@ impl TryFrom<LabelPopulationSerdeProxy> for Population {
         if max_samples > max_allowed_samples {
             bail!("Cannot sample {max_samples} labels (max allowed: {max_allowed_samples})");
         }
+        // the choices are the label counts starting at zero
+        let max_labels = max_samples.saturating_sub(1);
+        if max_labels > MAX_LABELS_PER_DOCUMENT {
+            bail!("Cannot sample {max_labels} labels per document (max allowed: {MAX_LABELS_PER_DOCUMENT})");
+        }
         let total_max_filters = value.filters.exclude_count_distribution.nr_choices()
             + value.filters.include_count_distribution.nr_choices();
         if total_max_filters > value.population / 2 {
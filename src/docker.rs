@@ -12,6 +12,8 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod compose;
+
 use std::process::Stdio;
 
 use anyhow::{anyhow, bail, Context, Error};
@@ -0,0 +1,303 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Error};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::{process::Command, time::sleep};
+
+use crate::{consts::CLUSTER_NODES, databases::Provider};
+
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Manages the docker compose project in `./docker/<provider>`.
+pub struct ComposeProject {
+    provider: Provider,
+    dir: PathBuf,
+}
+
+impl ComposeProject {
+    /// Alternative adapters of a database share the project of the database.
+    pub fn new(provider: Provider) -> Self {
+        let provider = provider.service();
+        Self {
+            provider,
+            dir: PathBuf::from("./docker").join(provider.name()),
+        }
+    }
+
+    /// Stops every other provider, (re-)creates volumes and starts this provider.
+    ///
+    /// Only returns once all nodes report to be healthy.
+    pub async fn start(&self, recreate_volumes: bool) -> Result<(), Error> {
+        for &other in Provider::ALL {
+            if other == other.service() && other != self.provider {
+                ComposeProject::new(other).down().await?;
+            }
+        }
+        self.check_host_settings()?;
+        // make sure we do not start with leftover state
+        self.down().await?;
+        if recreate_volumes {
+            self.delete_volumes().await?;
+        }
+        self.create_volumes().await?;
+
+        eprintln!("starting {}", self.provider.name());
+        let started = async {
+            // --compatibility converts deploy keys to v2 equivalent, should be needed anymore but does not hurt
+            self.compose(&["--compatibility", "up", "--detach", "--wait"])
+                .await?;
+            self.wait_until_healthy(HEALTH_TIMEOUT).await
+        }
+        .await;
+        if let Err(error) = started {
+            // don't leave half started containers holding the ports of the next run
+            if let Err(down_error) = self.down().await {
+                eprintln!("stopping {} failed: {down_error:#}", self.provider.name());
+            }
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    pub async fn down(&self) -> Result<(), Error> {
+        self.compose(&["down", "--volumes", "--remove-orphans"])
+            .await
+    }
+
+    async fn compose(&self, args: &[&str]) -> Result<(), Error> {
+        let status = Command::new("docker")
+            .arg("compose")
+            .args(args)
+            .current_dir(&self.dir)
+            .status()
+            .await
+            .context("running docker compose")?;
+        if !status.success() {
+            bail!(
+                "docker compose {} failed for {}: {status}",
+                args.join(" "),
+                self.provider.name()
+            );
+        }
+        Ok(())
+    }
+
+    fn volumes(&self) -> Vec<String> {
+        let name = self.provider.name();
+        (1..=CLUSTER_NODES)
+            .flat_map(|node| {
+                let storage = format!("{name}-storage-{node}");
+                let log = match self.provider {
+                    Provider::Qdrant
+                    | Provider::QdrantRest
+                    | Provider::Pgvector
+                    | Provider::Milvus
+                    | Provider::Weaviate => None,
+                    Provider::Vespa | Provider::Elasticsearch => Some(format!("{name}-log-{node}")),
+                };
+                [Some(storage), log].into_iter().flatten()
+            })
+            .chain(self.shared_volumes())
+            .collect()
+    }
+
+    /// Volumes of services the nodes depend on.
+    fn shared_volumes(&self) -> Vec<String> {
+        let name = self.provider.name();
+        match self.provider {
+            Provider::Milvus => ["etcd", "minio", "pulsar"]
+                .iter()
+                .map(|service| format!("{name}-{service}"))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    async fn existing_volumes(&self) -> Result<Vec<String>, Error> {
+        let out = Command::new("docker")
+            .args(["volume", "ls", "--quiet"])
+            .output()
+            .await?;
+        if !out.status.success() {
+            bail!("docker volume ls failed: {}", out.status);
+        }
+        Ok(String::from_utf8(out.stdout)?
+            .lines()
+            .map(|line| line.trim().to_owned())
+            .collect())
+    }
+
+    async fn create_volumes(&self) -> Result<(), Error> {
+        let existing = self.existing_volumes().await?;
+        for volume in self.volumes() {
+            if !existing.contains(&volume) {
+                docker(&["volume", "create", &volume]).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_volumes(&self) -> Result<(), Error> {
+        let existing = self.existing_volumes().await?;
+        for volume in self.volumes() {
+            if existing.contains(&volume) {
+                docker(&["volume", "rm", &volume]).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_host_settings(&self) -> Result<(), Error> {
+        if self.provider == Provider::Elasticsearch {
+            let max_map_count: u64 = fs::read_to_string("/proc/sys/vm/max_map_count")?
+                .trim()
+                .parse()?;
+            if max_map_count < 262144 {
+                bail!("Elastic search needs vm.max_map_count >= 262144, use: sudo sysctl -w vm.max_map_count=262144");
+            }
+        }
+        Ok(())
+    }
+
+    async fn wait_until_healthy(&self, timeout: Duration) -> Result<(), Error> {
+        let client = Client::new();
+        let start = Instant::now();
+        while Instant::now().duration_since(start) < timeout {
+            let mut healthy = true;
+            for url in self.health_urls() {
+                if !self.is_healthy(&client, &url).await {
+                    healthy = false;
+                    break;
+                }
+            }
+            if healthy {
+                eprintln!("{} is healthy", self.provider.name());
+                return Ok(());
+            }
+            sleep(Duration::from_secs(5)).await;
+        }
+        bail!(
+            "{} did not become healthy within {}s",
+            self.provider.name(),
+            timeout.as_secs()
+        )
+    }
+
+    fn health_urls(&self) -> Vec<String> {
+        (1..=CLUSTER_NODES)
+            .filter_map(|node| match self.provider {
+                Provider::Qdrant | Provider::QdrantRest => {
+                    Some(format!("http://localhost:64{node}3/healthz"))
+                }
+                Provider::Vespa => {
+                    Some(format!("http://localhost:{}/state/v1/health", 8079 + node))
+                }
+                Provider::Elasticsearch => Some(format!(
+                    "http://localhost:{}/_cluster/health?wait_for_status=green&timeout=1s",
+                    9199 + node
+                )),
+                Provider::Milvus => Some(format!("http://localhost:909{node}/healthz")),
+                Provider::Weaviate => Some(format!(
+                    "http://localhost:{}/v1/.well-known/ready",
+                    8180 + node
+                )),
+                // postgres doesn't speak http, the compose healthchecks cover it
+                Provider::Pgvector => None,
+            })
+            .collect()
+    }
+
+    async fn is_healthy(&self, client: &Client, url: &str) -> bool {
+        let Ok(response) = client.get(url).send().await else {
+            return false;
+        };
+        if !response.status().is_success() {
+            return false;
+        }
+        match self.provider {
+            Provider::Qdrant
+            | Provider::QdrantRest
+            | Provider::Elasticsearch
+            | Provider::Pgvector
+            | Provider::Milvus
+            | Provider::Weaviate => true,
+            // vespa answers with 200 while still initializing
+            Provider::Vespa => response
+                .json::<VespaHealth>()
+                .await
+                .is_ok_and(|health| health.status.code == "up"),
+        }
+    }
+}
+
+async fn docker(args: &[&str]) -> Result<(), Error> {
+    let status = Command::new("docker").args(args).status().await?;
+    if !status.success() {
+        bail!("docker {} failed: {status}", args.join(" "));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct VespaHealth {
+    status: VespaHealthStatus,
+}
+
+#[derive(Deserialize)]
+struct VespaHealthStatus {
+    code: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_volumes_match_compose_files() {
+        assert_eq!(
+            ComposeProject::new(Provider::Qdrant).volumes(),
+            ["qdrant-storage-1", "qdrant-storage-2", "qdrant-storage-3"]
+        );
+        assert_eq!(
+            ComposeProject::new(Provider::Vespa).volumes(),
+            [
+                "vespa-storage-1",
+                "vespa-log-1",
+                "vespa-storage-2",
+                "vespa-log-2",
+                "vespa-storage-3",
+                "vespa-log-3"
+            ]
+        );
+        assert_eq!(
+            ComposeProject::new(Provider::Milvus).volumes(),
+            [
+                "milvus-storage-1",
+                "milvus-storage-2",
+                "milvus-storage-3",
+                "milvus-etcd",
+                "milvus-minio",
+                "milvus-pulsar"
+            ]
+        );
+    }
+}
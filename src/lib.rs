@@ -5,4 +5,5 @@ pub mod distribution;
 pub mod docker;
 pub mod math;
 pub mod resources;
+pub mod stats;
 pub mod utils;
@@ -12,6 +12,11 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{bail, Error};
+use clap::ValueEnum;
+use hdrhistogram::Histogram;
 use serde::{Deserialize, Serialize};
 
 /// https://en.wikipedia.org/wiki/Algorithms_for_calculating_variance#Welford's_online_algorithm
@@ -48,10 +53,162 @@ impl WelfordOnlineAlgorithm {
     }
 }
 
+/// The distance metric of a dataset, smaller distances are closer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Distance {
+    Euclidean,
+    /// `1 - cosine_similarity`, called angular by ann-benchmarks.
+    Cosine,
+    /// The negated dot product.
+    DotProduct,
+}
+
+impl Distance {
+    pub fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+        match self {
+            Self::Euclidean => a
+                .iter()
+                .zip(b)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                .sqrt(),
+            Self::Cosine => 1. - dot(a, b) / (dot(a, a).sqrt() * dot(b, b).sqrt()),
+            Self::DotProduct => -dot(a, b),
+        }
+    }
+}
+
+impl FromStr for Distance {
+    type Err = Error;
+
+    /// Parses the `distance` attribute of the ann-benchmarks datasets.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "euclidean" | "l2" => Self::Euclidean,
+            "angular" | "cosine" => Self::Cosine,
+            "dot" | "dot_product" | "ip" => Self::DotProduct,
+            _ => bail!("unsupported distance: {s}"),
+        })
+    }
+}
+
+/// The highest latency we can track, larger values are clamped. Queries time out before this.
+const MAX_LATENCY_US: u64 = 120_000_000;
+
+/// A mergeable histogram of latencies with microsecond resolution.
+///
+/// Serializes as percentile summary and the recorded buckets, only the buckets are
+/// used when deserializing.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(
+    try_from = "LatencyHistogramSerdeProxy",
+    into = "LatencyHistogramSerdeProxy"
+)]
+pub struct LatencyHistogram {
+    histogram: Histogram<u64>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            // 3 significant digits, i.e. at most 0.1% error
+            histogram: Histogram::new_with_bounds(1, MAX_LATENCY_US, 3).unwrap(),
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.histogram.saturating_record(us);
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) -> Result<(), Error> {
+        self.histogram.add(&other.histogram)?;
+        Ok(())
+    }
+
+    pub fn count(&self) -> u64 {
+        self.histogram.len()
+    }
+
+    /// Returns the latency at given quantile in `[0;1]`, e.g. `0.99` for p99.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        Duration::from_micros(self.histogram.value_at_quantile(quantile))
+    }
+
+    pub fn mean(&self) -> Duration {
+        Duration::from_secs_f64(self.histogram.mean() / 1e6)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.histogram.max())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LatencyHistogramSerdeProxy {
+    count: u64,
+    mean_us: f64,
+    p50_us: u64,
+    p90_us: u64,
+    p99_us: u64,
+    p99_9_us: u64,
+    max_us: u64,
+    /// `(highest equivalent value in us, count)` of all non empty buckets
+    buckets: Vec<(u64, u64)>,
+}
+
+impl From<LatencyHistogram> for LatencyHistogramSerdeProxy {
+    fn from(source: LatencyHistogram) -> Self {
+        let histogram = &source.histogram;
+        Self {
+            count: histogram.len(),
+            mean_us: histogram.mean(),
+            p50_us: histogram.value_at_quantile(0.5),
+            p90_us: histogram.value_at_quantile(0.9),
+            p99_us: histogram.value_at_quantile(0.99),
+            p99_9_us: histogram.value_at_quantile(0.999),
+            max_us: histogram.max(),
+            buckets: histogram
+                .iter_recorded()
+                .map(|bucket| (bucket.value_iterated_to(), bucket.count_at_value()))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<LatencyHistogramSerdeProxy> for LatencyHistogram {
+    type Error = Error;
+
+    fn try_from(source: LatencyHistogramSerdeProxy) -> Result<Self, Self::Error> {
+        let mut this = Self::new();
+        for (value, count) in source.buckets {
+            this.histogram.record_n(value, count)?;
+        }
+        Ok(this)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_distances() {
+        let (a, b) = ([1., 0.], [3., 4.]);
+        assert_eq!(Distance::Euclidean.distance(&a, &b), 20f32.sqrt());
+        assert!((Distance::Cosine.distance(&a, &b) - 0.4).abs() < 1e-6);
+        assert_eq!(Distance::DotProduct.distance(&a, &b), -3.);
+        assert_eq!("angular".parse::<Distance>().unwrap(), Distance::Cosine);
+    }
+
     #[test]
     fn welford_works_for_simple_cases() {
         let mut wf = WelfordOnlineAlgorithm::default();
@@ -62,4 +219,27 @@ mod tests {
         assert_eq!(wf.variance().sqrt(), 4.830688931773144);
         assert_eq!(wf.sample_variance().sqrt(), 5.916361494477272);
     }
+
+    #[test]
+    fn latency_histogram_merges_and_roundtrips() {
+        let mut first = LatencyHistogram::new();
+        let mut second = LatencyHistogram::new();
+        for ms in 1..=50 {
+            first.record(Duration::from_millis(ms));
+        }
+        for ms in 51..=100 {
+            second.record(Duration::from_millis(ms));
+        }
+        first.merge(&second).unwrap();
+        assert_eq!(first.count(), 100);
+        assert_eq!(first.quantile(0.5).as_millis(), 50);
+        assert_eq!(first.quantile(0.99).as_millis(), 99);
+        assert_eq!(first.max().as_millis(), 100);
+
+        let json = serde_json::to_string(&first).unwrap();
+        let restored: LatencyHistogram = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.count(), 100);
+        assert_eq!(restored.quantile(0.9), first.quantile(0.9));
+        assert_eq!(restored.max(), first.max());
+    }
 }
@@ -14,6 +14,7 @@
 
 use std::{
     cmp,
+    collections::HashMap,
     fs::{self, File},
     io::{BufReader, BufWriter, Write},
     path::{Path, PathBuf},
@@ -23,15 +24,18 @@ use std::{
 
 use anyhow::{anyhow, bail, Error};
 use bincode::Options;
-use chrono::Utc;
+use chrono::{Local, Utc};
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::json;
 
+use crate::math::Distance;
+
 #[derive(Serialize)]
 pub struct ResolvedPaths {
     pub vectors_file: PathBuf,
     pub document_payload_file: PathBuf,
     pub query_payload_file: PathBuf,
+    pub filtered_neighbors_file: PathBuf,
 }
 
 impl ResolvedPaths {
@@ -40,10 +44,13 @@ impl ResolvedPaths {
         let document_payload_file =
             with_different_file_ending(&vectors_file, "document.payload.bincode");
         let query_payload_file = with_different_file_ending(&vectors_file, "query.payload.bincode");
+        let filtered_neighbors_file =
+            with_different_file_ending(&vectors_file, "filtered.neighbors.bincode");
         Self {
             vectors_file,
             document_payload_file,
             query_payload_file,
+            filtered_neighbors_file,
         }
     }
 
@@ -118,12 +125,21 @@ where
 }
 
 pub fn load_vectors(path: &Path, dataset: &str) -> Result<Vec<Vec<f32>>, Error> {
+    load_first_vectors(path, dataset, usize::MAX)
+}
+
+/// Loads at most `limit` vectors from the start of the dataset.
+pub fn load_first_vectors(
+    path: &Path,
+    dataset: &str,
+    limit: usize,
+) -> Result<Vec<Vec<f32>>, Error> {
     let file = hdf5::File::open(path)?;
     let dataset = file.dataset(dataset)?;
     // Warning: This can easily load 4+GiB of data
     //          To avoid having a single continuous 4GiB allocation and for convenience we
     //          read into a Vec<Vec<f32>> instead of an Array2<f32>.
-    let vectors = (0..dataset.shape()[0])
+    let vectors = (0..dataset.shape()[0].min(limit))
         .map(|idx| match dataset.read_slice_1d(ndarray::s![idx, ..]) {
             Ok(array) => Ok(array.into_raw_vec()),
             Err(err) => Err(anyhow!("malformed vector dataset: {err}")),
@@ -133,6 +149,62 @@ pub fn load_vectors(path: &Path, dataset: &str) -> Result<Vec<Vec<f32>>, Error>
     Ok(vectors)
 }
 
+/// Reads the number of elements per vector from the dataset shape.
+pub fn load_dimensions(path: &Path, dataset: &str) -> Result<usize, Error> {
+    let file = hdf5::File::open(path)?;
+    let shape = file.dataset(dataset)?.shape();
+    file.close()?;
+    match shape[..] {
+        [_, dimensions] => Ok(dimensions),
+        _ => bail!("malformed vector dataset shape: {shape:?}"),
+    }
+}
+
+/// Loads the vectors with given indices, indices outside of the dataset are skipped.
+pub fn load_vectors_by_index(
+    path: &Path,
+    dataset: &str,
+    indices: impl IntoIterator<Item = usize>,
+) -> Result<HashMap<usize, Vec<f32>>, Error> {
+    let file = hdf5::File::open(path)?;
+    let dataset = file.dataset(dataset)?;
+    let len = dataset.shape()[0];
+    let vectors = indices
+        .into_iter()
+        .filter(|&idx| idx < len)
+        .map(|idx| match dataset.read_slice_1d(ndarray::s![idx, ..]) {
+            Ok(array) => Ok((idx, array.into_raw_vec())),
+            Err(err) => Err(anyhow!("malformed vector dataset: {err}")),
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    file.close()?;
+    Ok(vectors)
+}
+
+/// Reads the distance metric from the `distance` attribute of an ann-benchmarks dataset.
+pub fn load_distance(path: &Path) -> Result<Distance, Error> {
+    let file = hdf5::File::open(path)?;
+    let distance = file
+        .attr("distance")?
+        .read_scalar::<hdf5::types::VarLenUnicode>()?;
+    file.close()?;
+    distance.as_str().parse()
+}
+
+/// Loads the exact nearest neighbors of the test vectors.
+pub fn load_neighbors(path: &Path) -> Result<Vec<Vec<usize>>, Error> {
+    let file = hdf5::File::open(path)?;
+    let dataset = file.dataset("neighbors")?;
+    let neighbors = (0..dataset.shape()[0])
+        .map(|idx| match dataset.read_slice_1d(ndarray::s![idx, ..]) {
+            Ok(array) => Ok(array.iter().copied().collect()),
+            Err(err) => Err(anyhow!("malformed vector dataset: {err}")),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    file.close()?;
+    Ok(neighbors)
+}
+
 #[derive(Clone)]
 pub struct ResourceWriter {
     out_dir: PathBuf,
@@ -184,7 +256,18 @@ impl ResourceWriter {
             .open(self.out_dir.join(name.as_ref()))?;
         let mut out = BufWriter::new(out);
         serde_json::to_writer(&mut out, data)?;
-        out.write_all(&[b'\n'])?;
+        out.write_all(b"\n")?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Writes non-json data, e.g. a plot.
+    pub fn write_raw_file(&self, name: impl AsRef<str>, data: &[u8]) -> Result<(), Error> {
+        let mut out = File::options()
+            .write(true)
+            .create_new(true)
+            .open(self.out_dir.join(name.as_ref()))?;
+        out.write_all(data)?;
         out.flush()?;
         Ok(())
     }
@@ -201,12 +284,32 @@ impl ResourceWriter {
             .open(self.out_dir.join(name.as_ref()))?;
         let mut out = BufWriter::new(out);
         serde_json::to_writer(&mut out, data)?;
-        out.write_all(&[b'\n'])?;
+        out.write_all(b"\n")?;
         out.flush()?;
         Ok(())
     }
 }
 
+/// Moves the reports in `out_dir` to `<committed_dir>/<YYYY-MM-DD_HH:MM:SS>.<GITSHORTHASH>`.
+pub fn archive_reports(out_dir: &Path, committed_dir: &Path) -> Result<PathBuf, Error> {
+    if !out_dir.exists() {
+        bail!("no reports exist: {}", out_dir.display());
+    }
+    let hash = get_git_hash()?;
+    let name = format!(
+        "{}.{}",
+        Local::now().format("%Y-%m-%d_%H:%M:%S"),
+        &hash[..cmp::min(7, hash.len())]
+    );
+    let target = committed_dir.join(name);
+    if target.exists() {
+        bail!("{} already exists", target.display());
+    }
+    fs::create_dir_all(committed_dir)?;
+    fs::rename(out_dir, &target)?;
+    Ok(target)
+}
+
 fn get_git_hash() -> Result<String, Error> {
     let out = Command::new("git").args(["rev-parse", "HEAD"]).output()?;
     if !out.status.success() {
@@ -0,0 +1,406 @@
+// Copyright 2023 Xayn AG
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context, Error};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    benchmarks::{
+        filter_compliance::FilterComplianceReport,
+        mixed::{MixedReport, WriteLatencies},
+        open_loop::OpenLoopReport,
+        saturation::SaturationReport,
+        sweep::SweepReport,
+        validation::ValidationReport,
+    },
+    databases::balancer::NodeRequests,
+    distribution::ground_truth::load_filtered_neighbors,
+    docker::DockerStats,
+    math::{LatencyHistogram, WelfordOnlineAlgorithm},
+    resources::{load_neighbors, ResolvedPaths},
+};
+
+/// Prints the stats of all benchmarks in given report dir.
+///
+/// The report dir is either `./reports` or `./committed_reports/<reports_name>/`,
+/// recall is calculated using the neighbors in given vectors file respectively
+/// the filtered ground truth next to it.
+pub fn print_stats(report_dir: &Path, paths: &ResolvedPaths) -> Result<(), Error> {
+    let neighbors = Neighbors {
+        unfiltered: load_neighbors(&paths.vectors_file)?,
+        filtered: paths
+            .filtered_neighbors_file
+            .exists()
+            .then(|| load_filtered_neighbors(paths))
+            .transpose()?,
+    };
+
+    let data_dir = report_dir.join("additional_data");
+    if !data_dir.exists() {
+        bail!(
+            "report dir should contain the ./additional_data sub-dir: {}",
+            data_dir.display()
+        );
+    }
+
+    visit_sub_dirs(data_dir, |provider, provider_path| {
+        visit_sub_dirs(provider_path, |run, run_path| {
+            visit_sub_dirs(run_path, |bench_group, bench_group_path| {
+                if bench_group == "ingestion" {
+                    return Ok(());
+                }
+                visit_sub_dirs(bench_group_path, |bench_id, bench_path| {
+                    println!("{run} {provider}/{bench_group}/{bench_id}");
+                    print_bench_stats(run, &neighbors, bench_path)?;
+                    println!("{run} --");
+                    Ok(())
+                })
+            })
+        })
+    })?;
+
+    Ok(())
+}
+
+fn print_bench_stats(run: &str, neighbors: &Neighbors, bench_path: &Path) -> Result<(), Error> {
+    if let Some(recall) = retrieve_recall(neighbors, bench_path).context("retrieve_recall")? {
+        let cutoff = recall.cutoff;
+        println!("{run} recall@{cutoff}    {:.4}", recall.recall.mean());
+        println!("{run} precision@{cutoff} {:.4}", recall.precision.mean())
+    }
+    if let Some(latencies) = retrieve_json::<LatencyHistogram>(bench_path, "latency.json")
+        .context("retrieve_latencies")?
+    {
+        let ms = |quantile| latencies.quantile(quantile).as_secs_f64() * 1e3;
+        println!(
+            "{run} latency   {p50: >7.2} / {p90: >7.2} / {p99: >7.2} / {p99_9: >7.2} ms",
+            p50 = ms(0.5),
+            p90 = ms(0.9),
+            p99 = ms(0.99),
+            p99_9 = ms(0.999),
+        );
+    }
+    if let Some(report) = retrieve_json::<OpenLoopReport>(bench_path, "open_loop.json")
+        .context("retrieve_open_loop")?
+    {
+        println!(
+            "{run} qps       {achieved: >7.1} / {target: >7} (errors {errors}, dropped {dropped}, late {late})",
+            achieved = report.achieved_qps,
+            target = report.target_qps,
+            errors = report.errors,
+            dropped = report.dropped,
+            late = report.late,
+        );
+    }
+    if let Some(report) =
+        retrieve_json::<MixedReport>(bench_path, "mixed.json").context("retrieve_mixed")?
+    {
+        println!(
+            "{run} mixed     {queries: >7.1} queries/s / {writes: >7.1} docs/s written",
+            queries = report.query_qps,
+            writes = report.write_dps,
+        );
+    }
+    if let Some(latencies) = retrieve_json::<WriteLatencies>(bench_path, "write_latency.json")
+        .context("retrieve_write_latencies")?
+    {
+        for (operation, latencies) in [
+            ("upsert", &latencies.upsert),
+            ("update", &latencies.update),
+            ("delete", &latencies.delete),
+        ] {
+            let ms = |quantile| latencies.quantile(quantile).as_secs_f64() * 1e3;
+            println!(
+                "{run} {operation}    {p50: >7.2} / {p99: >7.2} ms ({count} batches)",
+                p50 = ms(0.5),
+                p99 = ms(0.99),
+                count = latencies.count(),
+            );
+        }
+    }
+    if let Some(report) = retrieve_json::<SaturationReport>(bench_path, "saturation.json")
+        .context("retrieve_saturation")?
+    {
+        for step in &report.steps {
+            println!(
+                "{run} step      {target: >7} qps p99 {p99: >7.2} ms {result}",
+                target = step.target_qps,
+                p99 = step.p99_ms,
+                result = if step.passed { "pass" } else { "fail" },
+            );
+        }
+        match report.max_sustainable_qps {
+            Some(qps) => println!(
+                "{run} max qps   {qps: >7} (p99 < {:.0} ms)",
+                report.p99_sla_ms
+            ),
+            None => println!("{run} max qps   none passed"),
+        }
+    }
+    if let Some(report) =
+        retrieve_json::<SweepReport>(bench_path, "sweep.json").context("retrieve_sweep")?
+    {
+        for point in &report.points {
+            let pareto = if report.pareto.contains(point) {
+                "*"
+            } else {
+                " "
+            };
+            println!(
+                "{run} ef {ef: >6}{pareto} recall@{k} {recall:.4} {qps: >9.1} qps p99 {p99: >7.2} ms",
+                ef = point.ef,
+                k = report.k,
+                recall = point.recall,
+                qps = point.qps,
+                p99 = point.p99_ms,
+            );
+        }
+    }
+    if let Some(report) = retrieve_json::<ValidationReport>(bench_path, "validation.json")
+        .context("retrieve_validation")?
+    {
+        println!(
+            "{run} validated {hits} hits of {queries} queries: {misordered} misordered, {non_monotonic} non-monotonic scores, {mismatches} score mismatches, {unknown} unknown ids",
+            hits = report.hits,
+            queries = report.queries,
+            misordered = report.misordered,
+            non_monotonic = report.non_monotonic_scores,
+            mismatches = report.score_mismatches,
+            unknown = report.unknown_ids,
+        );
+    }
+    if let Some(report) =
+        retrieve_json::<FilterComplianceReport>(bench_path, "filter_compliance.json")
+            .context("retrieve_filter_compliance")?
+    {
+        println!(
+            "{run} filter    {violations} violations in {hits} hits of {queries} queries ({violating} violating queries, {unknown} unknown ids)",
+            violations = report.violations,
+            hits = report.checked_hits,
+            queries = report.checked_queries,
+            violating = report.violating_queries,
+            unknown = report.unknown_ids,
+        );
+    }
+    if let Some(docker_stats) =
+        retrieve_docker_stats(bench_path).context("retrieve_docker_stats")?
+    {
+        println!(
+            "{run} cpu       {mean: >5.0} / {max: >5.0} / {std: >5.0}",
+            mean = docker_stats.cpu.mean(),
+            max = docker_stats.cpu.max(),
+            std = docker_stats.cpu.sample_std(),
+        );
+        println!(
+            "{run} memory    {mean: >5.2} / {max: >5.2} / {std: >5.2}",
+            mean = docker_stats.memory.mean(),
+            max = docker_stats.memory.max(),
+            std = docker_stats.memory.sample_std(),
+        );
+    }
+    if let Some(node_requests) = retrieve_json::<NodeRequests>(bench_path, "node_requests.json")
+        .context("retrieve_node_requests")?
+    {
+        let nodes = node_requests
+            .iter()
+            .map(|(node, requests)| format!("{node} {requests}"))
+            .collect::<Vec<_>>();
+        println!("{run} requests  {}", nodes.join(" / "));
+    }
+    Ok(())
+}
+
+fn retrieve_docker_stats(bench_path: &Path) -> Result<Option<DockerStats>, Error> {
+    let docker_file = bench_path.join("docker_stats.json");
+    docker_file
+        .exists()
+        .then(|| {
+            let reader = BufReader::new(File::open(docker_file)?);
+            Ok(serde_json::from_reader(reader)?)
+        })
+        .transpose()
+}
+
+fn retrieve_json<T>(bench_path: &Path, name: &str) -> Result<Option<T>, Error>
+where
+    T: DeserializeOwned,
+{
+    let file = bench_path.join(name);
+    file.exists()
+        .then(|| {
+            let reader = BufReader::new(File::open(file)?);
+            Ok(serde_json::from_reader(reader)?)
+        })
+        .transpose()
+}
+
+struct Neighbors {
+    unfiltered: Vec<Vec<usize>>,
+    filtered: Option<Vec<Vec<usize>>>,
+}
+
+fn retrieve_recall(
+    neighbors: &Neighbors,
+    bench_path: &Path,
+) -> Result<Option<RecallAndPrecision>, Error> {
+    let recall_data_file = bench_path.join("recall_data.jsonl");
+    if !recall_data_file.exists() {
+        return Ok(None);
+    }
+
+    let recall_file = bench_path.join("recall.json");
+    let stats;
+    if recall_file.exists() {
+        let reader = BufReader::new(File::open(recall_file)?);
+        stats = serde_json::from_reader(reader)?;
+    } else {
+        let file = BufReader::new(File::open(recall_data_file)?);
+        let Some(calculated) = calculate_stats(file, neighbors)? else {
+            return Ok(None);
+        };
+        stats = calculated;
+        let mut out = BufWriter::new(
+            File::options()
+                .create_new(true)
+                .write(true)
+                .open(&recall_file)?,
+        );
+        serde_json::to_writer(&mut out, &stats)?;
+        out.flush()?;
+    }
+
+    Ok(Some(stats))
+}
+
+fn calculate_stats(
+    source: impl BufRead,
+    neighbors: &Neighbors,
+) -> Result<Option<RecallAndPrecision>, Error> {
+    let mut lines = source.lines();
+    let Header {
+        expected_hits,
+        filtered,
+    } = serde_json::from_str(
+        lines
+            .next()
+            .ok_or_else(|| anyhow!("empty recall_data.json"))??
+            .trim(),
+    )?;
+    let neighbors = if filtered {
+        let Some(neighbors) = &neighbors.filtered else {
+            println!("missing filtered ground truth, run `vdb-bench ground-truth`");
+            return Ok(None);
+        };
+        neighbors
+    } else {
+        &neighbors.unfiltered
+    };
+
+    let mut precision = WelfordOnlineAlgorithm::new();
+    let mut recall = WelfordOnlineAlgorithm::new();
+    let cutoff = expected_hits.min(10);
+
+    for line in lines {
+        let data: Vec<(usize, Vec<usize>)> = serde_json::from_str(line?.trim())?;
+        for (idx, got_neighbors) in data {
+            let neighbors = &neighbors[idx];
+            // with filters less than 10 (or even no) documents can match
+            if neighbors.is_empty() {
+                continue;
+            }
+            let expected_neighbors = &neighbors[..neighbors.len().min(cutoff)];
+            let true_positive = got_neighbors
+                .iter()
+                .take(cutoff)
+                .filter(|got| expected_neighbors.contains(got))
+                .count() as f64;
+            precision.update(if true_positive == 0. {
+                0.
+            } else {
+                // hits beyond the cutoff aren't judged, so they can't count as false positives
+                true_positive / got_neighbors.len().min(cutoff) as f64
+            });
+            recall.update(true_positive / expected_neighbors.len() as f64);
+        }
+    }
+
+    Ok(Some(RecallAndPrecision {
+        cutoff,
+        recall,
+        precision,
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecallAndPrecision {
+    cutoff: usize,
+    recall: WelfordOnlineAlgorithm,
+    precision: WelfordOnlineAlgorithm,
+}
+
+fn visit_sub_dirs(
+    path: impl AsRef<Path>,
+    mut visit: impl FnMut(&str, &Path) -> Result<(), Error>,
+) -> Result<(), Error> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let sub_path = entry.path();
+            let name = entry.file_name();
+            let name = name
+                .to_str()
+                .ok_or_else(|| anyhow!("non utf-8 path: {}", sub_path.display()))?;
+            visit(name, &sub_path)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Header {
+    expected_hits: usize,
+    #[serde(default)]
+    filtered: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recall_uses_the_same_cutoff_for_hits_and_neighbors() {
+        let neighbors = Neighbors {
+            unfiltered: vec![(0..20).collect(), vec![7, 8], vec![]],
+            filtered: None,
+        };
+        let source = concat!(
+            r#"{"expected_hits": 20}"#,
+            "\n",
+            // the 11th hit is a neighbor but beyond the cutoff of 10
+            r#"[[0, [0, 1, 2, 3, 4, 5, 6, 7, 8, 30, 9]], [1, [8, 9]], [2, [1]]]"#,
+        );
+        let stats = calculate_stats(source.as_bytes(), &neighbors)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.cutoff, 10);
+        assert_eq!(stats.recall.mean(), (0.9 + 0.5) / 2.);
+        assert_eq!(stats.precision.mean(), (0.9 + 0.5) / 2.);
+    }
+}